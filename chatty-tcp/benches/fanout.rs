@@ -0,0 +1,76 @@
+//! Fan-out of one broadcast to every member of a room, as the writer tasks of their connections
+//! put it in their write buffers: encoded for each member, as before shared frames, or encoded
+//! once and shared.
+
+use bytes::BytesMut;
+use chatty_tcp::listen::response::SharedResponse;
+use chatty_types::codec::{Framing, ServerCodec};
+use chatty_types::response::{ChatMemo, ChatResponse, Stamp};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio_util::codec::Encoder;
+use uuid::Uuid;
+
+const SUBSCRIBERS: [usize; 3] = [10, 1_000, 10_000];
+
+fn broadcast() -> ChatResponse {
+    ChatResponse::Broadcast(ChatMemo {
+        room: "lobby".to_string(),
+        username: "carl".to_string(),
+        content: "Has anyone tried the new tokio release? The scheduler changes look great."
+            .to_string(),
+        stamp: Some(Stamp {
+            id: Uuid::now_v7(),
+            timestamp_ms: 1_700_000_000_000,
+            seq: 42,
+        }),
+    })
+}
+
+/// Codec and write buffer of each member's connection.
+fn connections(subscribers: usize) -> Vec<(ServerCodec, BytesMut)> {
+    (0..subscribers)
+        .map(|_| (ServerCodec::new(Framing::Lines), BytesMut::new()))
+        .collect()
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout");
+    for subscribers in SUBSCRIBERS {
+        group.throughput(Throughput::Elements(subscribers as u64));
+
+        let mut members = connections(subscribers);
+        group.bench_with_input(
+            BenchmarkId::new("encode_per_subscriber", subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    let response = broadcast();
+                    for (codec, buf) in members.iter_mut() {
+                        buf.clear();
+                        codec.encode(&response, buf).unwrap();
+                    }
+                })
+            },
+        );
+
+        let mut members = connections(subscribers);
+        group.bench_with_input(
+            BenchmarkId::new("encode_once_shared", subscribers),
+            &subscribers,
+            |b, _| {
+                b.iter(|| {
+                    let shared = SharedResponse::new(broadcast());
+                    for (codec, buf) in members.iter_mut() {
+                        buf.clear();
+                        let frame = shared.frame(codec).unwrap();
+                        codec.encode(frame, buf).unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
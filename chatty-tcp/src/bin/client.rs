@@ -1,20 +1,33 @@
 use anyhow::Result;
-use chatty_tcp::config::server_address;
-use chatty_tcp::connect::prompt::run;
-use chatty_tcp::handler::ChatHandler;
+use chatty_tcp::config::{
+    client_certificate, client_tls, encoding, framing, reconnect, server_address, size_limits,
+    tls_server_name, token_cache_file,
+};
+use chatty_tcp::connect::command::{Prompt, SignIn};
+use chatty_tcp::connect::prompt::{run, stdin_lines};
+use chatty_tcp::connect::reconnect::Connector;
+use chatty_tcp::connect::token::TokenCache;
+use chatty_tcp::tls;
 use chatty_types::config::{setup_tracing, Component::Client};
 use clap::Parser;
 use std::io::stdout;
 use std::io::Write;
+use std::process;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::{stdin, BufReader};
-use tokio::net::TcpStream;
-use tracing::{debug, debug_span, info, Instrument};
+use tracing::{debug, debug_span, Instrument};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long)]
     username: Option<String>,
+    /// Log in to the account of the username, with the password of CHAT_PASSWORD or asked for,
+    /// unless a session token of an earlier login is cached.
+    #[arg(short, long, conflicts_with = "register")]
+    login: bool,
+    /// Register an account for the username, with the password of CHAT_PASSWORD or asked for.
+    #[arg(short, long)]
+    register: bool,
 }
 
 #[tokio::main]
@@ -29,20 +42,67 @@ pub async fn main() -> Result<()> {
         ask_username().await?
     };
 
+    // checked before connecting, the server would refuse it anyway
+    let size_limits = size_limits();
+    size_limits.check_username(&username)?;
+    // a session token cached by an earlier login saves asking for the password
+    let token_cache = token_cache_file().map(TokenCache::new);
+    let cached_token = token_cache
+        .as_ref()
+        .filter(|_| !args.register)
+        .and_then(|token_cache| token_cache.load(&username));
+    let sign_in = if let Some(token) = cached_token {
+        SignIn::Token(token)
+    } else if args.login {
+        SignIn::Login(password().await?)
+    } else if args.register {
+        SignIn::Register(password().await?)
+    } else {
+        SignIn::Guest
+    };
+
     let span = debug_span!("chatty_tcp_client_main");
     span.in_scope(|| debug!("Client connection is being set up for user: {}", username));
 
     let addr = server_address();
-
-    let stream = TcpStream::connect(&addr).await?;
-    span.in_scope(|| info!("Connected to server at {}", addr));
-
-    let handler = ChatHandler::new(stream);
-    run(handler, username).instrument(span.clone()).await?;
+    let tls = match client_tls() {
+        Some(verification) => Some((
+            tls::connector(&verification, client_certificate().as_ref())?,
+            tls_server_name(),
+        )),
+        None => None,
+    };
+    let connector = Connector {
+        addr,
+        framing: framing(),
+        tls,
+    };
+    let prompt = Prompt::new(username, sign_in, encoding(), size_limits, token_cache);
+    let result = run(connector, prompt, reconnect(), stdin_lines())
+        .instrument(span.clone())
+        .await;
+    if let Err(e) = result {
+        println!("{:#}", e);
+        process::exit(1);
+    }
 
     Ok(())
 }
 
+async fn password() -> Result<String> {
+    if let Ok(password) = std::env::var("CHAT_PASSWORD") {
+        return Ok(password);
+    }
+    print!("Password: ");
+    stdout().flush()?;
+    let mut reader = BufReader::new(stdin()).lines();
+    if let Ok(Some(password)) = reader.next_line().await {
+        Ok(password)
+    } else {
+        Err(anyhow::anyhow!("Failed to read password"))
+    }
+}
+
 async fn ask_username() -> Result<String> {
     print!("Username: ");
     stdout().flush()?;
@@ -1,18 +1,24 @@
 use anyhow::Result;
-use chatty_tcp::config::server_address;
+use chatty_tcp::config::{
+    accounts_file, broadcast_capacity, framing, guest_names, heartbeat, history_limit,
+    lag_backfill, malformed_strikes, message_log_config, outbound_queue, queue_report_interval,
+    rate_limits, resume_grace, server_address, server_tls, size_limits, token_denylist_file,
+    token_secret, token_ttl,
+};
 use chatty_tcp::handler::ChatHandler;
+use chatty_tcp::listen::account::FileAccountStore;
 use chatty_tcp::listen::command::RoomError;
+use chatty_tcp::listen::persist::FileMessageLog;
+use chatty_tcp::listen::registry::RoomRegistry;
 use chatty_tcp::listen::room::serve;
-use chatty_tcp::listen::state::RoomState;
+use chatty_tcp::listen::token::{Denylist, TokenSigner};
+use chatty_tcp::tls;
 use chatty_types::config::{setup_tracing, Component::Server};
-use chatty_types::response::ChatResponse;
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::broadcast;
-use tokio::sync::Mutex;
 use tokio::{select, signal};
-use tracing::{debug, debug_span, info, Instrument};
+use tracing::{debug, debug_span, info, warn, Instrument};
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
@@ -26,13 +32,47 @@ pub async fn main() -> Result<()> {
     let listening_on = listener.local_addr()?;
     span.in_scope(|| info!("listening on {}", listening_on));
 
-    // Set up room state for use
-    // bounded channel
-    let (tx, _rx) = broadcast::channel::<ChatResponse>(100);
-    // task handles
-    let task_handles = Mutex::new(HashMap::new());
-
-    let room_state = Arc::new(RoomState { tx, task_handles });
+    // Set up room registry for use
+    // bounded channel and history for each room
+    let mut registry = RoomRegistry::new(broadcast_capacity(), history_limit())
+        .with_lag_backfill(lag_backfill())
+        .with_outbound_queue(outbound_queue())
+        .with_heartbeat(heartbeat())
+        .with_resume_grace(resume_grace())
+        .with_rate_limits(rate_limits())
+        .with_size_limits(size_limits())
+        .with_malformed_strikes(malformed_strikes())
+        .with_guest_names(guest_names());
+    if let Some(log_config) = message_log_config() {
+        span.in_scope(|| info!("Persisting messages to {}", log_config.dir.display()));
+        registry = registry.with_message_log(Arc::new(FileMessageLog::open(log_config)?))?;
+    }
+    if let Some(path) = accounts_file() {
+        span.in_scope(|| info!("Keeping accounts in {}", path.display()));
+        registry = registry.with_accounts(Arc::new(FileAccountStore::open(path)?));
+    }
+    if let Some(secret) = token_secret()? {
+        let mut signer = TokenSigner::new(&secret, token_ttl());
+        if let Some(path) = token_denylist_file() {
+            span.in_scope(|| info!("Keeping revoked tokens in {}", path.display()));
+            signer = signer.with_denylist(Denylist::open(path)?);
+        }
+        registry = registry.with_tokens(Arc::new(signer));
+    }
+    let acceptor = match server_tls() {
+        Some(tls_config) => {
+            span.in_scope(|| info!("Serving TLS with {}", tls_config.cert_path.display()));
+            let acceptor = tls::acceptor(&tls_config)?;
+            registry = registry.with_certified_usernames(acceptor.usernames());
+            Some(acceptor)
+        }
+        None => None,
+    };
+    let registry = Arc::new(registry);
+    let framing = framing();
+    if let Some(interval) = queue_report_interval() {
+        tokio::spawn(report_queues(registry.clone(), interval));
+    }
 
     let mut connection_handles = Vec::new();
 
@@ -41,11 +81,15 @@ pub async fn main() -> Result<()> {
             accept_result = listener.accept() => {
                 let (stream, addr) = accept_result?;
                 span.in_scope(|| info!("accepted connection from {}", addr));
-                let state = room_state.clone();
+                let state = registry.clone();
+                let acceptor = acceptor.clone();
 
                 let handle = tokio::spawn(
                     async move {
-                        let handler = ChatHandler::new(stream);
+                        let handler = match acceptor {
+                            Some(acceptor) => tls::accept(&acceptor, stream, framing).await?,
+                            None => ChatHandler::with_framing(stream, framing)?,
+                        };
                         serve(handler, state).await?;
                         Ok::<_, RoomError>(())
                     }
@@ -59,15 +103,31 @@ pub async fn main() -> Result<()> {
                 for  handle in connection_handles.iter() {
                     handle.abort();
                 }
-                let mut handles = room_state.task_handles.lock().await;
-                for (username, handle) in handles.iter() {
-                    info!("Aborting background send task for user: {}", username);
-                    handle.abort();
-                }
-                handles.clear();
+                registry.abort_all().await;
                 info!("All background send tasks aborted");
                 return Ok(());
             }
         }
     }
 }
+
+/// Logs the users whose outbound queue is backed up or has dropped broadcasts, slowest first.
+async fn report_queues(registry: Arc<RoomRegistry>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        for (username, stats) in registry.queue_stats().await {
+            if stats.depth > 0 || stats.dropped > 0 {
+                warn!(
+                    "Outbound queue of {}: {} queued, at most {}, {} dropped",
+                    username, stats.depth, stats.high_water, stats.dropped
+                );
+            } else {
+                debug!(
+                    "Outbound queue of {}: empty, at most {}",
+                    username, stats.high_water
+                );
+            }
+        }
+    }
+}
@@ -1,3 +1,17 @@
+use crate::connect::reconnect::ReconnectConfig;
+use crate::listen::account::GuestNames;
+use crate::listen::connection::HeartbeatConfig;
+use crate::listen::limit::{Rate, RateLimits};
+use crate::listen::outbound::{OutboundConfig, DEFAULT_QUEUE_CAPACITY};
+use crate::listen::persist::FileLogConfig;
+use crate::listen::registry::{DEFAULT_MALFORMED_STRIKES, DEFAULT_RESUME_GRACE};
+use crate::listen::token::MIN_SECRET_BYTES;
+use crate::tls::{ClientAuthConfig, ClientCertificate, ServerTlsConfig, ServerVerification};
+use chatty_types::codec::{Encoding, Framing};
+use chatty_types::limits::SizeLimits;
+use std::path::PathBuf;
+use std::time::Duration;
+
 pub fn server_address() -> String {
     format!(
         "{}:{}",
@@ -5,3 +19,293 @@ pub fn server_address() -> String {
         std::env::var("TCP_SERVER_PORT").unwrap_or_else(|_| "8081".to_string())
     )
 }
+
+fn enabled(name: &str, default: bool) -> bool {
+    std::env::var(name)
+        .ok()
+        .and_then(|enabled| enabled.parse().ok())
+        .unwrap_or(default)
+}
+
+/// TLS of the server when both its certificate chain and private key are configured,
+/// asking for client certificates when the authorities issuing them are configured.
+pub fn server_tls() -> Option<ServerTlsConfig> {
+    let client_auth = std::env::var("CHAT_TLS_CLIENT_CA")
+        .ok()
+        .map(|ca_path| ClientAuthConfig {
+            ca_path: PathBuf::from(ca_path),
+            mandatory: enabled("CHAT_TLS_CLIENT_CERT_MANDATORY", true),
+            usernames_path: std::env::var("CHAT_TLS_USERNAMES").ok().map(PathBuf::from),
+        });
+    Some(ServerTlsConfig {
+        cert_path: PathBuf::from(std::env::var("CHAT_TLS_CERT").ok()?),
+        key_path: PathBuf::from(std::env::var("CHAT_TLS_KEY").ok()?),
+        client_auth,
+    })
+}
+
+/// Certificate the client presents when connecting with TLS, when both its chain and key are configured.
+pub fn client_certificate() -> Option<ClientCertificate> {
+    Some(ClientCertificate {
+        cert_path: PathBuf::from(std::env::var("CHAT_TLS_CLIENT_CERT").ok()?),
+        key_path: PathBuf::from(std::env::var("CHAT_TLS_CLIENT_KEY").ok()?),
+    })
+}
+
+/// How the client verifies the server when it connects with TLS, plain TCP when None.
+pub fn client_tls() -> Option<ServerVerification> {
+    if !enabled("CHAT_TLS", false) {
+        return None;
+    }
+    if enabled("CHAT_TLS_INSECURE", false) {
+        return Some(ServerVerification::Insecure);
+    }
+    Some(match std::env::var("CHAT_TLS_CA") {
+        Ok(ca_path) => ServerVerification::CustomCa(PathBuf::from(ca_path)),
+        Err(_) => ServerVerification::PublicRoots,
+    })
+}
+
+/// Name the server's certificate has to be for, the host of the server address by default.
+pub fn tls_server_name() -> String {
+    std::env::var("CHAT_TLS_SERVER_NAME")
+        .or_else(|_| std::env::var("TCP_SERVER_ADDRESS"))
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Framing of the connection, which has to be the same for server and client.
+pub fn framing() -> Framing {
+    std::env::var("CHAT_FRAMING")
+        .ok()
+        .and_then(|framing| framing.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Encoding the client asks for in the handshake, binary ones need length-prefixed framing.
+pub fn encoding() -> Encoding {
+    std::env::var("CHAT_ENCODING")
+        .ok()
+        .and_then(|encoding| encoding.parse().ok())
+        .unwrap_or_default()
+}
+
+/// How the client reconnects after the connection dropped, never when the max attempts are zero.
+pub fn reconnect() -> ReconnectConfig {
+    let defaults = ReconnectConfig::default();
+    let var = |name: &str| std::env::var(name).ok();
+    ReconnectConfig {
+        max_attempts: var("CHAT_RECONNECT_MAX_ATTEMPTS")
+            .and_then(|attempts| attempts.parse().ok())
+            .unwrap_or(defaults.max_attempts),
+        initial_delay: var("CHAT_RECONNECT_DELAY_MS")
+            .and_then(|ms| ms.parse().ok())
+            .map_or(defaults.initial_delay, Duration::from_millis),
+        max_delay: var("CHAT_RECONNECT_MAX_DELAY_SECS")
+            .and_then(|secs| secs.parse().ok())
+            .map_or(defaults.max_delay, Duration::from_secs),
+    }
+}
+
+/// Largest frame, message content and username, enforced by the server and checked by the client
+/// before sending, so both should use the same.
+pub fn size_limits() -> SizeLimits {
+    let defaults = SizeLimits::default();
+    let limit = |name: &str, default: usize| {
+        std::env::var(name)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|&limit| limit > 0)
+            .unwrap_or(default)
+    };
+    SizeLimits {
+        max_frame_bytes: limit("CHAT_MAX_FRAME_BYTES", defaults.max_frame_bytes),
+        max_content_chars: limit("CHAT_MAX_CONTENT_CHARS", defaults.max_content_chars),
+        max_username_chars: limit("CHAT_MAX_USERNAME_CHARS", defaults.max_username_chars),
+    }
+}
+
+/// Malformed commands in a row after which a connection is closed, never when zero.
+pub fn malformed_strikes() -> u32 {
+    std::env::var("CHAT_MALFORMED_STRIKES")
+        .ok()
+        .and_then(|strikes| strikes.parse().ok())
+        .unwrap_or(DEFAULT_MALFORMED_STRIKES)
+}
+
+/// Broadcasts a room buffers for each member, a member further behind than that misses some.
+pub fn broadcast_capacity() -> usize {
+    std::env::var("CHAT_BROADCAST_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .filter(|&capacity| capacity > 0)
+        .unwrap_or(100)
+}
+
+/// Whether members that fell behind get the broadcasts they missed replayed from history.
+pub fn lag_backfill() -> bool {
+    std::env::var("CHAT_LAG_BACKFILL")
+        .ok()
+        .and_then(|backfill| backfill.parse().ok())
+        .unwrap_or(true)
+}
+
+/// Responses queued for each connection and what happens to a connection that cannot keep up.
+pub fn outbound_queue() -> OutboundConfig {
+    let capacity = std::env::var("CHAT_OUTBOUND_QUEUE")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .filter(|&capacity| capacity > 0)
+        .unwrap_or(DEFAULT_QUEUE_CAPACITY);
+    let policy = std::env::var("CHAT_SLOW_CONSUMER")
+        .ok()
+        .and_then(|policy| policy.parse().ok())
+        .unwrap_or_default();
+    OutboundConfig { capacity, policy }
+}
+
+/// How often the depth of outbound queues is logged, never when zero.
+pub fn queue_report_interval() -> Option<Duration> {
+    let secs = std::env::var("CHAT_QUEUE_REPORT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(60);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// When quiet connections are pinged, and after how long without a word they are dropped,
+/// never when the idle timeout is zero.
+pub fn heartbeat() -> HeartbeatConfig {
+    let interval = std::env::var("CHAT_PING_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(30);
+    let idle_timeout = std::env::var("CHAT_IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(90);
+    HeartbeatConfig {
+        interval: Duration::from_secs(interval),
+        idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+    }
+}
+
+/// How long the session of a dropped connection is held for the client to resume, never when zero.
+pub fn resume_grace() -> Option<Duration> {
+    let secs = std::env::var("CHAT_RESUME_GRACE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_RESUME_GRACE.as_secs());
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Rate limits of messages and joins, a rate of zero is unlimited, and how repeat offenders are
+/// muted and eventually disconnected.
+pub fn rate_limits() -> RateLimits {
+    let defaults = RateLimits::default();
+    let var = |name: &str| std::env::var(name).ok();
+    let rate = |rate: &str, burst: &str, default: Option<Rate>| {
+        let default = default.expect("default limits are set");
+        let per_sec = var(rate)
+            .and_then(|rate| rate.parse().ok())
+            .unwrap_or(default.per_sec);
+        let burst = var(burst)
+            .and_then(|burst| burst.parse().ok())
+            .unwrap_or(default.burst)
+            .max(1);
+        (per_sec > 0.0).then_some(Rate { per_sec, burst })
+    };
+    RateLimits {
+        messages: rate("CHAT_MESSAGE_RATE", "CHAT_MESSAGE_BURST", defaults.messages),
+        joins: rate("CHAT_JOIN_RATE", "CHAT_JOIN_BURST", defaults.joins),
+        mute_after: var("CHAT_MUTE_AFTER")
+            .and_then(|strikes| strikes.parse().ok())
+            .unwrap_or(defaults.mute_after),
+        mute_for: var("CHAT_MUTE_SECS")
+            .and_then(|secs| secs.parse().ok())
+            .map_or(defaults.mute_for, Duration::from_secs),
+        disconnect_after: var("CHAT_DISCONNECT_AFTER")
+            .and_then(|mutes| mutes.parse().ok())
+            .unwrap_or(defaults.disconnect_after),
+    }
+}
+
+/// Broadcast memos each room keeps to replay to users joining later.
+pub fn history_limit() -> usize {
+    std::env::var("CHAT_HISTORY_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(100)
+}
+
+/// File of registered accounts when configured, without it there are only guests.
+pub fn accounts_file() -> Option<PathBuf> {
+    std::env::var("CHAT_ACCOUNTS_FILE").ok().map(PathBuf::from)
+}
+
+/// Secret session tokens are signed with when configured, without it no tokens are issued.
+/// Has to stay the same for tokens to outlive a restart of the server.
+pub fn token_secret() -> anyhow::Result<Option<Vec<u8>>> {
+    let Ok(secret) = std::env::var("CHAT_TOKEN_SECRET") else {
+        return Ok(None);
+    };
+    if secret.len() < MIN_SECRET_BYTES {
+        anyhow::bail!(
+            "CHAT_TOKEN_SECRET needs at least {} bytes",
+            MIN_SECRET_BYTES
+        );
+    }
+    Ok(Some(secret.into_bytes()))
+}
+
+/// How long session tokens are accepted after being issued, a week by default.
+pub fn token_ttl() -> Duration {
+    let secs = std::env::var("CHAT_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(7 * 24 * 60 * 60);
+    Duration::from_secs(secs)
+}
+
+/// File of revoked session tokens when configured, revocations only last until a restart otherwise.
+pub fn token_denylist_file() -> Option<PathBuf> {
+    std::env::var("CHAT_TOKEN_DENYLIST").ok().map(PathBuf::from)
+}
+
+/// Where the client caches session tokens, in the home directory unless configured.
+pub fn token_cache_file() -> Option<PathBuf> {
+    match std::env::var("CHAT_TOKEN_CACHE") {
+        Ok(path) if path.is_empty() => None,
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => std::env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".chatty").join("tokens.json")),
+    }
+}
+
+/// Usernames guests may join with, any not registered by default.
+pub fn guest_names() -> GuestNames {
+    std::env::var("CHAT_GUEST_NAMES")
+        .ok()
+        .and_then(|guest_names| guest_names.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Durable message log when a directory for it is configured, in memory history only otherwise.
+pub fn message_log_config() -> Option<FileLogConfig> {
+    let dir = std::env::var("CHAT_LOG_DIR").ok()?;
+    let segment_bytes = std::env::var("CHAT_LOG_SEGMENT_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(1024 * 1024);
+    let retention_secs = std::env::var("CHAT_LOG_RETENTION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(7 * 24 * 60 * 60);
+    Some(FileLogConfig {
+        dir: PathBuf::from(dir),
+        segment_bytes,
+        retention: Duration::from_secs(retention_secs),
+    })
+}
@@ -1,3 +1,5 @@
 pub mod command;
 pub mod prompt;
+pub mod reconnect;
 pub mod response;
+pub mod token;
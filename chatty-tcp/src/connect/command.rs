@@ -1,83 +1,639 @@
+use crate::connect::reconnect::{self, prompt, ConnectionStatus};
+use crate::connect::response::{PendingRequests, ResponseReader, Tracked};
+use crate::connect::token::TokenCache;
+use crate::handler::WriteHalf;
 use anyhow::Context;
 use anyhow::Result;
-use chatty_types::command::{ChatCommand, ChatMessage};
-use std::io::stdout;
-use std::io::Write;
+use chatty_types::codec::{ClientCodec, Encoding};
+use chatty_types::command::{
+    BearerToken, ChatCommand, ChatMessage, Credentials, DirectMessage, Hello, HistoryRequest,
+    PasswordChange, Request, RequestId, DEFAULT_ROOM,
+};
+use chatty_types::limits::SizeLimits;
+use chatty_types::protocol::{Capability, Heartbeat, PROTOCOL_VERSION};
+use chatty_types::response::{ChatResponse, ErrorCode};
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeSet, VecDeque};
 use std::process;
-use tokio::io::{stdin, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::OwnedWriteHalf;
+use std::time::Instant;
 use tokio::select;
 use tokio::signal;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::codec::FramedWrite;
 use tracing::debug;
 
-pub async fn send_command(writer_half: OwnedWriteHalf, username: String) -> Result<()> {
-    let mut writer = writer_half;
-    let command = ChatCommand::Join(username.clone());
-    send_request(&mut writer, command).await?;
+/// Write half of the connection to the server, framing and serializing the commands sent on it.
+pub type CommandWriter = FramedWrite<WriteHalf, ClientCodec>;
 
-    debug!("Running client prompt");
-    let mut reader = BufReader::new(stdin()).lines();
+/// What the request logging in with a cached session token is tracked as, to tell its failure.
+pub const TOKEN_LOGIN_REQUEST: &str = "log in with the cached session token";
 
-    print!("> ");
-    stdout().flush()?;
+/// What revoking the session token is tracked as, to forget the cached one once the server did.
+pub const REVOKE_TOKEN_REQUEST: &str = "revoke the session token";
 
-    loop {
-        select! {
-            // Handle input from the user
-            line = reader.next_line() => {
-                if let Ok(Some(line)) = line {
-                    debug!("Read line: {:?}", line);
-                    match line.split_whitespace().next() {
-                        Some("send") => {
-                            let content = line.trim_start_matches("send").trim().to_string();
-                            let chat_message = ChatMessage {
-                                username: username.clone(),
-                                content,
-                            };
-                            let command = ChatCommand::Send(chat_message);
-                            debug!("Sending command for message: {:?}", command);
-                            send_request(&mut writer, command).await?;
-                        }
-                        Some("leave") => {
-                            let command = ChatCommand::Leave(username.clone());
-                            debug!("Sending command for leave: {:?}", command);
-                            send_request(&mut writer, command).await?;
-                            process::exit(0);
-                        }
-                        _ => println!("Unknown command. Use 'send <message>' or 'leave'"),
+/// What the server broadcasts to a room a user joins.
+const JOINED: &str = "Joined";
+
+/// How the client joins the chat once connected.
+#[derive(Clone)]
+pub enum SignIn {
+    Guest,
+    /// Logs in to an existing account with the password.
+    Login(String),
+    /// Registers a new account with the password.
+    Register(String),
+    /// Logs in to an existing account with a session token cached earlier.
+    Token(BearerToken),
+}
+
+impl SignIn {
+    /// How to sign in again after reconnecting, with the cached session token if there is one now
+    /// and the account registered by then.
+    fn rejoin(&self, cached_token: Option<BearerToken>) -> SignIn {
+        match (self, cached_token) {
+            (SignIn::Guest, _) => SignIn::Guest,
+            (_, Some(token)) => SignIn::Token(token),
+            (SignIn::Login(password) | SignIn::Register(password), None) => {
+                SignIn::Login(password.clone())
+            }
+            (SignIn::Token(token), None) => SignIn::Token(token.clone()),
+        }
+    }
+
+    fn command(self, username: String) -> ChatCommand {
+        match self {
+            SignIn::Guest => ChatCommand::Join(username),
+            SignIn::Login(password) => ChatCommand::Login(Credentials { username, password }),
+            SignIn::Register(password) => ChatCommand::Register(Credentials { username, password }),
+            SignIn::Token(token) => ChatCommand::TokenLogin(token),
+        }
+    }
+}
+
+/// What the prompt keeps across connections to the server.
+pub struct Prompt {
+    username: String,
+    sign_in: SignIn,
+    encoding: Encoding,
+    size_limits: SizeLimits,
+    tracked: Tracked,
+    active_room: String,
+    /// Rooms joined besides the default one, joined again after reconnecting without resuming.
+    rooms: BTreeSet<String>,
+    requests: Requests,
+    next_ping: u64,
+    /// Lines typed while disconnected, handled once reconnected.
+    queued: VecDeque<String>,
+    /// Responses read while rejoining, for the response handler to handle first.
+    read_ahead: Vec<ChatResponse>,
+}
+
+impl Prompt {
+    pub fn new(
+        username: String,
+        sign_in: SignIn,
+        encoding: Encoding,
+        size_limits: SizeLimits,
+        token_cache: Option<TokenCache>,
+    ) -> Self {
+        let tracked = Tracked {
+            token_cache,
+            ..Tracked::default()
+        };
+        Self {
+            username,
+            sign_in,
+            encoding,
+            size_limits,
+            requests: Requests {
+                next_id: 1,
+                pending: tracked.pending_requests.clone(),
+            },
+            tracked,
+            active_room: DEFAULT_ROOM.to_string(),
+            rooms: BTreeSet::new(),
+            next_ping: 1,
+            queued: VecDeque::new(),
+            read_ahead: Vec::new(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn size_limits(&self) -> SizeLimits {
+        self.size_limits
+    }
+
+    /// Lines typed while disconnected, not sent yet.
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn take_read_ahead(&mut self) -> Vec<ChatResponse> {
+        std::mem::take(&mut self.read_ahead)
+    }
+
+    pub fn tracked(&self) -> &Tracked {
+        &self.tracked
+    }
+
+    pub fn set_status(&self, status: ConnectionStatus) {
+        *self.tracked.status.lock().expect("status lock poisoned") = status;
+    }
+
+    /// Shakes hands with the server and joins the chat, resuming the session of the connection that
+    /// dropped when rejoining, and signing in again with the rooms joined before when that fails.
+    pub async fn join(
+        &mut self,
+        writer: &mut CommandWriter,
+        reader: &mut ResponseReader,
+        rejoining: bool,
+    ) -> Result<()> {
+        let hello = ChatCommand::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            software: client_software(),
+            capabilities: Capability::ALL.to_vec(),
+            encoding: self.encoding,
+        });
+        send_request(writer, hello).await?;
+        // the server reads everything after the Hello in the requested encoding
+        writer.encoder_mut().set_encoding(self.encoding);
+        match reader
+            .next()
+            .await
+            .context("Connection closed before the handshake")??
+        {
+            ChatResponse::Welcome(welcome) => {
+                debug!(
+                    "Server {} speaks protocol {} in {:?} with {:?}",
+                    welcome.software,
+                    welcome.protocol_version,
+                    welcome.encoding,
+                    welcome.capabilities
+                );
+                reader.decoder_mut().set_encoding(welcome.encoding);
+            }
+            ChatResponse::Incompatible(incompatible) => {
+                println!("{}", incompatible.reason);
+                println!("Disconnecting from chat server");
+                process::exit(0);
+            }
+            response => anyhow::bail!("Expected a handshake, got {:?}", response),
+        }
+
+        if rejoining && self.resume(writer, reader).await? {
+            return Ok(());
+        }
+        let sign_in = if rejoining {
+            self.tracked.forget_unanswered();
+            let cached_token = self
+                .tracked
+                .token_cache
+                .as_ref()
+                .and_then(|token_cache| token_cache.load(&self.username));
+            self.sign_in.rejoin(cached_token)
+        } else {
+            self.sign_in.clone()
+        };
+        let account = !matches!(sign_in, SignIn::Guest);
+        let command = match sign_in {
+            // tracked to forget the token if the server no longer accepts it
+            SignIn::Token(token) => self.requests.track(
+                ChatCommand::TokenLogin(token),
+                TOKEN_LOGIN_REQUEST.to_string(),
+            ),
+            sign_in => sign_in.command(self.username.clone()),
+        };
+        send_request(writer, command).await?;
+        if rejoining {
+            self.signed_in(reader).await?;
+        }
+        self.expect_echo(DEFAULT_ROOM, JOINED);
+        if account && self.tracked.token_cache.is_some() {
+            // a fresh token each time, so one in regular use never expires
+            let command = self
+                .requests
+                .track(ChatCommand::IssueToken, "get a session token".to_string());
+            send_request(writer, command).await?;
+        }
+        if rejoining {
+            for room in &self.rooms {
+                self.expect_echo(room, JOINED);
+                let command = self.requests.track(
+                    ChatCommand::JoinRoom(room.clone()),
+                    format!("join {}", room),
+                );
+                send_request(writer, command).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes the session with the latest ticket, whether the server still held it.
+    async fn resume(
+        &mut self,
+        writer: &mut CommandWriter,
+        reader: &mut ResponseReader,
+    ) -> Result<bool> {
+        let ticket = self
+            .tracked
+            .resume_ticket
+            .lock()
+            .expect("resume ticket lock poisoned")
+            .clone();
+        let Some(ticket) = ticket else {
+            return Ok(false);
+        };
+        let id = self.requests.next_id;
+        let command = self.requests.track(
+            ChatCommand::Resume(ticket.ticket),
+            "resume the session".to_string(),
+        );
+        send_request(writer, command).await?;
+        // what was held meanwhile follows the Resumed, for the response handler to show
+        while let Some(response) = reader.next().await {
+            match response? {
+                ChatResponse::Resumed(resumed) => {
+                    println!(
+                        "Resumed session in {}, {} messages held meanwhile",
+                        resumed.rooms.join(", "),
+                        resumed.held
+                    );
+                    return Ok(true);
+                }
+                ChatResponse::Error(error) if error.id == Some(id) => {
+                    debug!("Session not resumed: {}", error.message);
+                    // kept until now in case the connection dropped again before the answer
+                    self.tracked
+                        .resume_ticket
+                        .lock()
+                        .expect("resume ticket lock poisoned")
+                        .take();
+                    return Ok(false);
+                }
+                response => debug!("Skipped while resuming: {:?}", response),
+            }
+        }
+        anyhow::bail!("Connection closed while resuming the session")
+    }
+
+    /// Waits for the answer to signing in again, failing the attempt while the server still holds
+    /// the username, as it does for a while for the session of the connection that dropped.
+    async fn signed_in(&mut self, reader: &mut ResponseReader) -> Result<()> {
+        self.read_ahead.clear();
+        while let Some(response) = reader.next().await {
+            match response? {
+                ChatResponse::Duplicate(_) => {
+                    anyhow::bail!("Username {} is still taken", self.username)
+                }
+                ChatResponse::Error(error) if error.code == ErrorCode::UsernameTaken => {
+                    anyhow::bail!("Username {} is still taken", self.username)
+                }
+                // the server asks whether the connection is alive whenever it likes
+                response @ ChatResponse::Ping(_) => self.read_ahead.push(response),
+                response => {
+                    self.read_ahead.push(response);
+                    return Ok(());
+                }
+            }
+        }
+        anyhow::bail!("Connection closed while signing in")
+    }
+
+    /// Keeps a line typed while disconnected, or leaves right away.
+    pub fn queue(&mut self, line: Option<String>) -> Result<()> {
+        let Some(line) = line.filter(|line| line.split_whitespace().next() != Some("leave")) else {
+            println!("Disconnecting from chat server");
+            process::exit(0);
+        };
+        self.queued.push_back(line);
+        self.set_status(ConnectionStatus::Reconnecting {
+            queued: self.queued.len(),
+        });
+        println!("Not connected, sent once reconnected");
+        prompt(&self.tracked.status)?;
+        Ok(())
+    }
+
+    /// Takes the broadcast of this content to the room as own, not from another device.
+    fn expect_echo(&self, room: &str, content: &str) {
+        self.tracked
+            .unechoed_sends
+            .lock()
+            .expect("unechoed sends lock poisoned")
+            .entry(room.to_string())
+            .or_default()
+            .push(content.to_string());
+    }
+
+    async fn handle_line(&mut self, writer: &mut CommandWriter, line: &str) -> Result<()> {
+        debug!("Read line: {:?}", line);
+        match line.split_whitespace().next() {
+            Some("send") => {
+                let content = line.trim_start_matches("send").trim().to_string();
+                // the server would refuse it anyway
+                if let Err(e) = self.size_limits.check_content(&content) {
+                    println!("{}", e);
+                } else {
+                    let request = format!("send \"{}\" to {}", content, self.active_room);
+                    self.expect_echo(&self.active_room, &content);
+                    let chat_message = ChatMessage {
+                        username: None,
+                        room: Some(self.active_room.clone()),
+                        content,
+                    };
+                    let command = self
+                        .requests
+                        .track(ChatCommand::Send(chat_message), request);
+                    debug!("Sending command for message: {:?}", command);
+                    send_request(writer, command).await?;
+                }
+            }
+            Some("msg") => match parse_direct_message(line) {
+                Some(direct_message) => {
+                    let request = format!("message {}", direct_message.to);
+                    let command = ChatCommand::Direct(direct_message);
+                    if let Err(e) = self.size_limits.check(&command) {
+                        println!("{}", e);
+                    } else {
+                        let command = self.requests.track(command, request);
+                        debug!("Sending command for direct message: {:?}", command);
+                        send_request(writer, command).await?;
                     }
-                    print!("> ");
-                    stdout().flush()?;
+                }
+                None => println!("Use 'msg <user> <message>'"),
+            },
+            Some("join") => {
+                let room = line.trim_start_matches("join").trim().to_string();
+                if room.is_empty() {
+                    println!("Use 'join <room>'");
+                } else {
+                    // messages go to the most recently joined room
+                    self.active_room = room.clone();
+                    if room != DEFAULT_ROOM {
+                        self.rooms.insert(room.clone());
+                    }
+                    self.expect_echo(&room, JOINED);
+                    let request = format!("join {}", room);
+                    let command = self.requests.track(ChatCommand::JoinRoom(room), request);
+                    debug!("Sending command for join room: {:?}", command);
+                    send_request(writer, command).await?;
                 }
             }
-            // Handle Ctrl+C as Leave
-            _ = signal::ctrl_c() => {
-                let command = ChatCommand::Leave(username.clone());
-                debug!("Ctrl+C detected. Sending command for leave: {:?}", command);
-                send_request(&mut writer, command).await?;
-                process::exit(0);
+            Some("part") => {
+                let room = line.trim_start_matches("part").trim().to_string();
+                if room == self.active_room {
+                    self.active_room = DEFAULT_ROOM.to_string();
+                }
+                self.rooms.remove(&room);
+                let request = format!("part {}", room);
+                let command = self.requests.track(ChatCommand::PartRoom(room), request);
+                debug!("Sending command for part room: {:?}", command);
+                send_request(writer, command).await?;
+            }
+            Some("history") => {
+                let cursor = self
+                    .tracked
+                    .history_cursors
+                    .lock()
+                    .expect("history cursors lock poisoned")
+                    .get(&self.active_room)
+                    .copied();
+                if let Some(None) = cursor {
+                    // the start of the kept history was already reached
+                    println!("No older history in {}", self.active_room);
+                } else {
+                    let command = ChatCommand::History(HistoryRequest {
+                        room: self.active_room.clone(),
+                        before: cursor.flatten(),
+                    });
+                    let request = format!("show history of {}", self.active_room);
+                    let command = self.requests.track(command, request);
+                    debug!("Sending command for history: {:?}", command);
+                    send_request(writer, command).await?;
+                }
+            }
+            Some("rooms") => {
+                let command = self
+                    .requests
+                    .track(ChatCommand::ListRooms, "list rooms".to_string());
+                send_request(writer, command).await?;
+            }
+            Some("ping") => {
+                let nonce = self.next_ping;
+                self.next_ping += 1;
+                self.tracked
+                    .pending_pings
+                    .lock()
+                    .expect("pending pings lock poisoned")
+                    .insert(nonce, Instant::now());
+                let command = ChatCommand::Ping(Heartbeat { nonce });
+                debug!("Sending command for ping: {:?}", command);
+                send_request(writer, command).await?;
             }
+            Some("passwd") => {
+                let mut words = line.split_whitespace().skip(1);
+                match (words.next(), words.next(), words.next()) {
+                    (Some(current), Some(new), None) => {
+                        let command = ChatCommand::ChangePassword(PasswordChange {
+                            current: current.to_string(),
+                            new: new.to_string(),
+                        });
+                        let command = self.requests.track(command, "change password".to_string());
+                        send_request(writer, command).await?;
+                    }
+                    _ => println!("Use 'passwd <current> <new>'"),
+                }
+            }
+            Some("revoke") => {
+                let command = self.requests.track(
+                    ChatCommand::RevokeToken(None),
+                    REVOKE_TOKEN_REQUEST.to_string(),
+                );
+                send_request(writer, command).await?;
+            }
+            Some("leave") => leave(writer).await?,
+            _ => println!(
+                "Unknown command. Use 'send <message>', 'msg <user> <message>', 'join <room>', 'part <room>', 'rooms', 'history', 'ping', 'passwd <current> <new>', 'revoke' or 'leave'"
+            ),
         }
+        Ok(())
+    }
+}
+
+/// Sends what is typed at the prompt, first what was typed while disconnected,
+/// until sending fails.
+pub async fn send_command(
+    writer: &mut CommandWriter,
+    prompt: &mut Prompt,
+    input: &mut UnboundedReceiver<String>,
+    replies: &mut UnboundedReceiver<ChatCommand>,
+) -> Result<()> {
+    debug!("Running client prompt");
+    if !prompt.queued.is_empty() {
+        println!(
+            "Sending {} lines typed while disconnected",
+            prompt.queued.len()
+        );
+    }
+    reconnect::prompt(&prompt.tracked.status)?;
+
+    loop {
+        let line = match prompt.queued.pop_front() {
+            Some(line) => line,
+            None => select! {
+                // Handle input from the user
+                line = input.recv() => match line {
+                    Some(line) => line,
+                    // nothing more to send once the input is closed
+                    None => return leave(writer).await,
+                },
+                // Answers to the server, like pongs to its pings
+                Some(command) = replies.recv() => {
+                    debug!("Sending reply: {:?}", command);
+                    send_request(writer, command).await?;
+                    continue;
+                }
+                // Handle Ctrl+C as Leave
+                _ = signal::ctrl_c() => {
+                    debug!("Ctrl+C detected");
+                    return leave(writer).await;
+                }
+            },
+        };
+        prompt.handle_line(writer, &line).await?;
+        reconnect::prompt(&prompt.tracked.status)?;
+    }
+}
+
+async fn leave(writer: &mut CommandWriter) -> Result<()> {
+    let command = ChatCommand::Leave(None);
+    debug!("Sending command for leave: {:?}", command);
+    send_request(writer, command).await?;
+    process::exit(0);
+}
+
+/// Gives commands a request id so an `Error` for them can say which command failed.
+struct Requests {
+    next_id: RequestId,
+    pending: PendingRequests,
+}
+
+impl Requests {
+    fn track(&mut self, command: ChatCommand, request: String) -> ChatCommand {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending
+            .lock()
+            .expect("pending requests lock poisoned")
+            .insert(id, request);
+        ChatCommand::Request(Request {
+            id,
+            command: Box::new(command),
+        })
     }
 }
 
-pub async fn send_request(writer: &mut OwnedWriteHalf, command: ChatCommand) -> Result<()> {
-    let serialized = serde_json::to_string(&command)?;
+pub fn client_software() -> String {
+    format!("chatty-tcp-client/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Parses `msg <user> <message>` typed at the prompt.
+fn parse_direct_message(line: &str) -> Option<DirectMessage> {
+    let rest = line.trim().strip_prefix("msg")?.trim_start();
+    let (to, content) = rest.split_once(char::is_whitespace)?;
+    let content = content.trim();
+    if content.is_empty() {
+        return None;
+    }
+    Some(DirectMessage {
+        to: to.to_string(),
+        content: content.to_string(),
+    })
+}
+
+pub async fn send_request(writer: &mut CommandWriter, command: ChatCommand) -> Result<()> {
     writer
-        .write_all(serialized.as_bytes())
+        .send(&command)
         .await
         .context(format!("Failed to send command {:?} from user", command))?;
-    writer
-        .write_all(b"\n")
-        .await
-        .context("Failed to send newline for framing")?; // Add newline for framing
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::handler::ReadHalf;
+    use chatty_types::codec::{Framing, ServerCodec};
+    use chatty_types::response::{ChatMemo, Welcome};
+    use tokio::io::{AsyncBufReadExt, BufReader};
     use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::FramedRead;
+
+    /// Rejoins as a guest with a server that welcomes and then answers signing in with the answer.
+    async fn rejoin_answered(answer: ChatResponse) -> (Prompt, Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader_half, writer_half) = stream.into_split();
+            let mut reader = FramedRead::new(reader_half, ServerCodec::new(Framing::Lines));
+            let mut writer = FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines));
+            let hello = reader.next().await.unwrap().unwrap();
+            assert!(matches!(hello, ChatCommand::Hello(_)));
+            let welcome = ChatResponse::Welcome(Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                software: "test".to_string(),
+                capabilities: Capability::ALL.to_vec(),
+                encoding: Encoding::Json,
+            });
+            writer.send(&welcome).await.unwrap();
+            let join = reader.next().await.unwrap().unwrap();
+            assert_eq!(join, ChatCommand::Join("carl".to_string()));
+            writer.send(&answer).await.unwrap();
+            // kept open until the client is done
+            let _ = reader.next().await;
+        });
+
+        let (reader_half, writer_half) = TcpStream::connect(addr).await.unwrap().into_split();
+        let reader_half: ReadHalf = Box::new(reader_half);
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let mut reader = FramedRead::new(reader_half, ClientCodec::new(Framing::Lines));
+        let mut writer = FramedWrite::new(writer_half, ClientCodec::new(Framing::Lines));
+        let mut prompt = Prompt::new(
+            "carl".to_string(),
+            SignIn::Guest,
+            Encoding::Json,
+            SizeLimits::default(),
+            None,
+        );
+        let joined = prompt.join(&mut writer, &mut reader, true).await;
+        (prompt, joined)
+    }
+
+    fn memo(content: &str) -> ChatMemo {
+        ChatMemo {
+            room: DEFAULT_ROOM.to_string(),
+            username: "carl".to_string(),
+            content: content.to_string(),
+            stamp: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejoin_fails_while_username_still_taken() {
+        let (_, joined) = rejoin_answered(ChatResponse::Duplicate(memo("Sorry"))).await;
+        let e = joined.unwrap_err();
+        assert_eq!(e.to_string(), "Username carl is still taken");
+
+        let welcome = ChatResponse::Joined(memo("Warm Welcome"));
+        let (mut prompt, joined) = rejoin_answered(welcome.clone()).await;
+        joined.unwrap();
+        // for the response handler to show
+        assert_eq!(prompt.take_read_ahead(), vec![welcome]);
+    }
 
     #[tokio::test]
     async fn test_send_request() {
@@ -93,10 +649,13 @@ mod tests {
         });
 
         let stream = TcpStream::connect(addr).await.unwrap();
-        let (_, mut writer) = stream.into_split();
+        let (_, writer_half) = stream.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let mut writer = FramedWrite::new(writer_half, ClientCodec::new(Framing::Lines));
 
         let test_message = ChatMessage {
-            username: "test_user".to_string(),
+            username: Some("test_user".to_string()),
+            room: None,
             content: "Hello world".to_string(),
         };
         let command = ChatCommand::Send(test_message);
@@ -108,4 +667,15 @@ mod tests {
         assert!(received.contains("Hello world"));
         assert!(received.ends_with("\n"));
     }
+
+    #[test]
+    fn test_parse_direct_message() {
+        let direct_message = parse_direct_message("msg david  see you at standup ").unwrap();
+        assert_eq!(direct_message.to, "david");
+        assert_eq!(direct_message.content, "see you at standup");
+
+        assert!(parse_direct_message("msg david").is_none());
+        assert!(parse_direct_message("msg david   ").is_none());
+        assert!(parse_direct_message("msg").is_none());
+    }
 }
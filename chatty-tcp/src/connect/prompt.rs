@@ -1,21 +1,139 @@
-use crate::connect::command::send_command;
-use crate::connect::response::process_response;
-use crate::handler::ChatHandler;
-use anyhow::Result;
+use crate::connect::command::{send_command, CommandWriter, Prompt};
+use crate::connect::reconnect::{self, ConnectionStatus, Connector, ReconnectConfig};
+use crate::connect::response::{process_response, ResponseReader};
+use anyhow::{Context, Result};
+use chatty_types::codec::ClientCodec;
+use std::io::BufRead;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tokio::select;
+use tokio::signal;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, info};
 
-pub async fn run(handler: ChatHandler, username: String) -> Result<()> {
-    let ChatHandler {
-        writer_half,
-        reader_half,
-    } = handler;
+/// How long the server has to answer the handshake and a resume, or the attempt failed.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let response_task = tokio::spawn(process_response(reader_half));
-    let command_task = tokio::spawn(send_command(writer_half, username));
+/// Lines typed at the prompt, read on a thread of their own as reading stdin blocks.
+pub fn stdin_lines() -> UnboundedReceiver<String> {
+    let (lines_tx, lines_rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    lines_rx
+}
 
-    let (command_result, response_result) = tokio::try_join!(command_task, response_task)?;
+/// Chats on connections to the server until leaving, connecting again whenever one drops.
+pub async fn run(
+    connector: Connector,
+    mut prompt: Prompt,
+    reconnect: ReconnectConfig,
+    mut input: UnboundedReceiver<String>,
+) -> Result<()> {
+    let mut connection = open(&connector, &mut prompt, false).await?;
+    loop {
+        let (reader, mut writer) = connection;
+        prompt.set_status(ConnectionStatus::Connected);
+        let (replies_tx, mut replies_rx) = mpsc::unbounded_channel();
+        let response = process_response(
+            reader,
+            prompt.username().to_string(),
+            prompt.tracked().clone(),
+            replies_tx,
+            prompt.take_read_ahead(),
+        );
+        let result = select! {
+            result = response => result,
+            result = send_command(&mut writer, &mut prompt, &mut input, &mut replies_rx) => result,
+        };
+        match result {
+            Ok(()) => println!("Connection closed by chat server"),
+            Err(e) => println!("Connection to chat server lost: {:#}", e),
+        }
+        if reconnect.max_attempts == 0 {
+            process::exit(0);
+        }
+        connection = rejoin(&connector, &mut prompt, &reconnect, &mut input).await?;
+    }
+}
 
-    command_result?;
-    response_result?;
+/// Connects with backoff and joins again, keeping what is typed meanwhile.
+async fn rejoin(
+    connector: &Connector,
+    prompt: &mut Prompt,
+    reconnect: &ReconnectConfig,
+    input: &mut UnboundedReceiver<String>,
+) -> Result<(ResponseReader, CommandWriter)> {
+    for attempt in 1..=reconnect.max_attempts {
+        let delay = reconnect.delay(attempt);
+        prompt.set_status(ConnectionStatus::Reconnecting {
+            queued: prompt.queued(),
+        });
+        println!(
+            "Reconnecting in {:.1} s, attempt {} of {}",
+            delay.as_secs_f64(),
+            attempt,
+            reconnect.max_attempts
+        );
+        reconnect::prompt(&prompt.tracked().status)?;
+        let wait = time::sleep(delay);
+        tokio::pin!(wait);
+        loop {
+            select! {
+                _ = &mut wait => break,
+                line = input.recv() => prompt.queue(line)?,
+                _ = signal::ctrl_c() => prompt.queue(None)?,
+            }
+        }
+        match open(connector, prompt, true).await {
+            Ok(connection) => {
+                println!("Reconnected to chat server");
+                return Ok(connection);
+            }
+            Err(e) => {
+                debug!("Reconnect attempt {} failed: {:#}", attempt, e);
+                println!("Failed to reconnect: {:#}", e);
+            }
+        }
+    }
+    anyhow::bail!(
+        "Gave up reconnecting to chat server after {} attempts",
+        reconnect.max_attempts
+    )
+}
 
-    Ok(())
+/// Connects and joins the chat, or rejoins it after a connection dropped.
+async fn open(
+    connector: &Connector,
+    prompt: &mut Prompt,
+    rejoining: bool,
+) -> Result<(ResponseReader, CommandWriter)> {
+    let handler = connector.connect().await?;
+    info!("Connected to server at {}", connector.addr);
+    let mut writer = FramedWrite::new(
+        handler.writer_half,
+        ClientCodec::new(handler.framing)
+            .with_max_frame_bytes(prompt.size_limits().max_frame_bytes),
+    );
+    // the server sends nothing larger than it reads
+    let mut reader = FramedRead::new(
+        handler.reader_half,
+        ClientCodec::new(handler.framing)
+            .with_max_frame_bytes(prompt.size_limits().max_frame_bytes),
+    );
+    time::timeout(
+        JOIN_TIMEOUT,
+        prompt.join(&mut writer, &mut reader, rejoining),
+    )
+    .await
+    .context("Chat server did not answer in time")??;
+    Ok((reader, writer))
 }
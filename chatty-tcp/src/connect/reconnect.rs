@@ -0,0 +1,150 @@
+use crate::handler::ChatHandler;
+use crate::tls;
+use chatty_types::codec::Framing;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{self, stdout, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How the client gets back to the server after the connection dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectConfig {
+    /// Attempts in a row before giving up, never reconnecting when zero.
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled for each one after up to the max delay.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Delay before the attempt, counted from one, with a random part so clients dropped together
+    /// do not all come back at once.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.jittered_delay(attempt, jitter())
+    }
+
+    /// Between half and all of the backoff, depending on the jitter from zero to one.
+    fn jittered_delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        backoff / 2 + backoff.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+fn jitter() -> f64 {
+    let mut bytes = [0u8; 4];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX)
+}
+
+/// Opens connections to the server, the first one and those after it dropped.
+#[derive(Clone)]
+pub struct Connector {
+    pub addr: String,
+    pub framing: Framing,
+    /// TLS connector with the name the server's certificate has to be for, plain TCP when None.
+    pub tls: Option<(TlsConnector, String)>,
+}
+
+impl Connector {
+    pub async fn connect(&self) -> io::Result<ChatHandler> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        match &self.tls {
+            Some((connector, server_name)) => {
+                tls::connect(connector, server_name, stream, self.framing).await
+            }
+            None => ChatHandler::with_framing(stream, self.framing),
+        }
+    }
+}
+
+/// Whether the client is connected, shown in the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionStatus {
+    #[default]
+    Connected,
+    /// Lost the connection and trying to get it back, with the lines typed meanwhile.
+    Reconnecting { queued: usize },
+}
+
+/// Connection status shared by the tasks printing the prompt.
+pub type Status = Arc<Mutex<ConnectionStatus>>;
+
+impl ConnectionStatus {
+    pub fn prompt(&self) -> String {
+        match self {
+            ConnectionStatus::Connected => "> ".to_string(),
+            ConnectionStatus::Reconnecting { queued: 0 } => "(reconnecting) > ".to_string(),
+            ConnectionStatus::Reconnecting { queued } => {
+                format!("(reconnecting, {} queued) > ", queued)
+            }
+        }
+    }
+}
+
+/// Prints the prompt for the next line of input.
+pub fn prompt(status: &Status) -> io::Result<()> {
+    let prompt = status.lock().expect("status lock poisoned").prompt();
+    print!("{}", prompt);
+    stdout().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max_delay() {
+        let config = ReconnectConfig {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        assert_eq!(config.jittered_delay(1, 1.0), Duration::from_millis(100));
+        assert_eq!(config.jittered_delay(2, 1.0), Duration::from_millis(200));
+        assert_eq!(config.jittered_delay(4, 1.0), Duration::from_millis(800));
+        assert_eq!(config.jittered_delay(5, 1.0), Duration::from_secs(1));
+        assert_eq!(config.jittered_delay(u32::MAX, 1.0), Duration::from_secs(1));
+        // the jitter takes off up to half
+        assert_eq!(config.jittered_delay(2, 0.0), Duration::from_millis(100));
+        assert_eq!(config.jittered_delay(2, 0.5), Duration::from_millis(150));
+        for attempt in 1..10 {
+            let delay = config.delay(attempt);
+            assert!(delay >= config.jittered_delay(attempt, 0.0));
+            assert!(delay <= config.jittered_delay(attempt, 1.0));
+        }
+    }
+
+    #[test]
+    fn test_prompt_shows_the_connection_status() {
+        assert_eq!(ConnectionStatus::Connected.prompt(), "> ");
+        assert_eq!(
+            ConnectionStatus::Reconnecting { queued: 0 }.prompt(),
+            "(reconnecting) > "
+        );
+        assert_eq!(
+            ConnectionStatus::Reconnecting { queued: 2 }.prompt(),
+            "(reconnecting, 2 queued) > "
+        );
+    }
+}
@@ -1,23 +1,171 @@
+use crate::connect::command::{REVOKE_TOKEN_REQUEST, TOKEN_LOGIN_REQUEST};
+use crate::connect::reconnect::{prompt, Status};
+use crate::connect::token::TokenCache;
+use crate::handler::ReadHalf;
 use anyhow::Result;
-use chatty_types::response::ChatResponse;
-use std::io::{stdout, Write};
+use chatty_types::codec::ClientCodec;
+use chatty_types::command::{ChatCommand, RequestId};
+use chatty_types::response::{ChatMemo, ChatResponse, ErrorCode, ResumeTicket};
+use futures::StreamExt;
+use std::collections::HashMap;
 use std::process;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::tcp::OwnedReadHalf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::codec::FramedRead;
 use tracing::debug;
 
-pub async fn process_response(reader_half: OwnedReadHalf) -> Result<()> {
-    debug!("Running response handler");
-    let mut reader = BufReader::new(reader_half).lines();
+/// Read half of the connection to the server, decoding the responses received on it.
+pub type ResponseReader = FramedRead<ReadHalf, ClientCodec>;
+
+/// Cursor per room for scrolling further back in history, None once there is nothing older.
+pub type HistoryCursors = Arc<Mutex<HashMap<String, Option<u64>>>>;
+
+/// What each request sent with an id was for, until the server answered it.
+pub type PendingRequests = Arc<Mutex<HashMap<RequestId, String>>>;
+
+/// When each ping typed at the prompt was sent, until its pong arrived.
+pub type PendingPings = Arc<Mutex<HashMap<u64, Instant>>>;
+
+/// Latest ticket the server handed out for resuming the session after a dropped connection.
+pub type LatestTicket = Arc<Mutex<Option<ResumeTicket>>>;
+
+/// Contents sent per room whose echo has not come back yet, to tell own broadcasts apart from
+/// those of the same user on other devices.
+pub type UnechoedSends = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+/// What the prompt sent that the responses to it are matched with.
+#[derive(Debug, Clone, Default)]
+pub struct Tracked {
+    pub history_cursors: HistoryCursors,
+    pub pending_requests: PendingRequests,
+    pub pending_pings: PendingPings,
+    /// Where session tokens the server issues are cached, none are asked for when None.
+    pub token_cache: Option<TokenCache>,
+    pub resume_ticket: LatestTicket,
+    pub unechoed_sends: UnechoedSends,
+    pub status: Status,
+}
+
+impl Tracked {
+    /// Forgets what the server was asked on a connection that dropped, which it will never answer.
+    pub fn forget_unanswered(&self) {
+        self.pending_requests
+            .lock()
+            .expect("pending requests lock poisoned")
+            .clear();
+        self.pending_pings
+            .lock()
+            .expect("pending pings lock poisoned")
+            .clear();
+        self.unechoed_sends
+            .lock()
+            .expect("unechoed sends lock poisoned")
+            .clear();
+    }
+}
+
+/// Last broadcast sequence seen per room, to notice broadcasts that never arrived.
+#[derive(Debug, Default)]
+struct Sequences(HashMap<String, u64>);
+
+impl Sequences {
+    /// Number of broadcasts missed in the room right before the one with this sequence.
+    /// A sequence not above the last one means the room started over, which is no gap.
+    fn missed(&mut self, room: &str, seq: u64) -> u64 {
+        match self.0.insert(room.to_string(), seq) {
+            Some(last) if seq > last + 1 => seq - last - 1,
+            _ => 0,
+        }
+    }
+
+    /// Takes broadcasts up to the sequence as seen, as the server already told about them.
+    fn skip_to(&mut self, room: &str, seq: u64) {
+        self.0.insert(room.to_string(), seq);
+    }
+
+    fn forget(&mut self, room: &str) {
+        self.0.remove(room);
+    }
+}
+
+/// Time of day in UTC of a memo's stamp, empty for memos of a server without stamps.
+fn time_of(memo: &ChatMemo) -> String {
+    memo.stamp.map_or_else(String::new, |stamp| {
+        let secs_of_day = stamp.timestamp_ms / 1000 % 86_400;
+        format!(
+            "{:02}:{:02}:{:02} ",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60
+        )
+    })
+}
+
+/// Whether the content was sent from here to the room, then no longer waiting for its echo.
+fn take_unechoed(unechoed_sends: &UnechoedSends, room: &str, content: &str) -> bool {
+    let mut unechoed_sends = unechoed_sends.lock().expect("unechoed sends lock poisoned");
+    let Some(sent) = unechoed_sends.get_mut(room) else {
+        return false;
+    };
+    // searched rather than taken from the front, as refused sends are never echoed
+    let Some(position) = sent.iter().position(|sent| sent == content) else {
+        return false;
+    };
+    sent.remove(position);
+    true
+}
 
-    while let Some(line) = reader.next_line().await? {
-        let response = serde_json::from_str::<ChatResponse>(&line)?;
+pub async fn process_response(
+    mut reader: ResponseReader,
+    username: String,
+    tracked: Tracked,
+    replies: UnboundedSender<ChatCommand>,
+    read_ahead: Vec<ChatResponse>,
+) -> Result<()> {
+    let Tracked {
+        history_cursors,
+        pending_requests,
+        pending_pings,
+        token_cache,
+        resume_ticket,
+        unechoed_sends,
+        status,
+    } = tracked;
+    debug!("Running response handler");
+    let mut sequences = Sequences::default();
+    let mut read_ahead = read_ahead.into_iter();
+    loop {
+        let response = match read_ahead.next() {
+            Some(response) => response,
+            None => match reader.next().await {
+                Some(response) => response?,
+                None => break,
+            },
+        };
         match response {
+            ChatResponse::Welcome(welcome) => {
+                debug!(
+                    "Server {} speaks protocol {} in {:?} with {:?}",
+                    welcome.software,
+                    welcome.protocol_version,
+                    welcome.encoding,
+                    welcome.capabilities
+                );
+                reader.decoder_mut().set_encoding(welcome.encoding);
+            }
+            ChatResponse::Incompatible(incompatible) => {
+                println!("{}", incompatible.reason);
+                println!("Disconnecting from chat server");
+                process::exit(0);
+            }
             ChatResponse::Joined(message) => {
-                debug!("{} Joined", message.username);
-                println!("{}, {}", message.content, message.username);
-                print!("> ");
-                stdout().flush()?;
+                debug!("{} Joined {}", message.username, message.room);
+                println!(
+                    "[{}] {}, {}",
+                    message.room, message.content, message.username
+                );
+                prompt(&status)?;
             }
             ChatResponse::Duplicate(message) => {
                 println!(
@@ -27,18 +175,300 @@ pub async fn process_response(reader_half: OwnedReadHalf) -> Result<()> {
                 println!("Disconnecting from chat server");
                 process::exit(0);
             }
+            ChatResponse::Rejected(message) => {
+                println!("[{}] Rejected: {}", message.room, message.content);
+                prompt(&status)?;
+            }
+            ChatResponse::Parted(message) => {
+                if message.username == username {
+                    sequences.forget(&message.room);
+                }
+                println!(
+                    "[{}] {}, {}",
+                    message.room, message.content, message.username
+                );
+                prompt(&status)?;
+            }
+            ChatResponse::Rooms(rooms) => {
+                for room in rooms {
+                    println!("{} ({} members)", room.name, room.members);
+                }
+                prompt(&status)?;
+            }
+            ChatResponse::Direct(message) => {
+                debug!("Received direct message from {}", message.from);
+                if message.from == username {
+                    // sent from another device of the same account
+                    println!("<whispered to {}>: {}", message.to, message.content);
+                } else {
+                    println!("<{} whispers>: {}", message.from, message.content);
+                }
+                prompt(&status)?;
+            }
+            ChatResponse::Undelivered(message) => {
+                println!(
+                    "{} is not online, message not delivered: {}",
+                    message.to, message.content
+                );
+                prompt(&status)?;
+            }
+            ChatResponse::History(page) => {
+                println!("[{}] --- history ---", page.room);
+                for message in page.memos {
+                    println!(
+                        "{}[{}] ({}): {}",
+                        time_of(&message),
+                        message.room,
+                        message.username,
+                        message.content
+                    );
+                }
+                println!("[{}] --- end of history ---", page.room);
+                history_cursors
+                    .lock()
+                    .expect("history cursors lock poisoned")
+                    .insert(page.room, page.before);
+                prompt(&status)?;
+            }
+            ChatResponse::Ack(ack) => {
+                let request = pending_requests
+                    .lock()
+                    .expect("pending requests lock poisoned")
+                    .remove(&ack.id);
+                debug!("Request {} acknowledged: {:?}", ack.id, request);
+                // kept until the server no longer takes it, a failed revocation leaves it valid
+                if request.as_deref() == Some(REVOKE_TOKEN_REQUEST) {
+                    if let Some(token_cache) = &token_cache {
+                        token_cache.remove(&username)?;
+                    }
+                }
+            }
+            ChatResponse::Error(error) => {
+                let request = error.id.and_then(|id| {
+                    pending_requests
+                        .lock()
+                        .expect("pending requests lock poisoned")
+                        .remove(&id)
+                });
+                match &request {
+                    Some(request) => println!("Failed to {}: {}", request, error.message),
+                    None => println!("Error from chat server: {}", error.message),
+                }
+                if error.code == ErrorCode::Unauthorized
+                    && request.as_deref() == Some(TOKEN_LOGIN_REQUEST)
+                {
+                    if let Some(token_cache) = &token_cache {
+                        token_cache.remove(&username)?;
+                    }
+                    println!("Forgot the cached session token, log in with the password instead");
+                }
+                prompt(&status)?;
+            }
+            ChatResponse::Throttled(throttled) => {
+                let request = throttled.id.and_then(|id| {
+                    pending_requests
+                        .lock()
+                        .expect("pending requests lock poisoned")
+                        .remove(&id)
+                });
+                let request = request.unwrap_or_else(|| "send that".to_string());
+                let wait = throttled.retry_after_ms.div_ceil(1000);
+                if throttled.muted {
+                    println!(
+                        "Muted for sending too much, could not {}, wait {} s",
+                        request, wait
+                    );
+                } else {
+                    println!("Slow down, could not {}, try again in {} s", request, wait);
+                }
+                prompt(&status)?;
+            }
+            ChatResponse::Lagged(lagged) => {
+                println!(
+                    "[{}] Fell behind, {} messages missed (seq {} to {}), {} recovered from history",
+                    lagged.room, lagged.missed, lagged.first_seq, lagged.last_seq, lagged.recovered
+                );
+                // the recovered ones follow right after
+                sequences.skip_to(
+                    &lagged.room,
+                    lagged.last_seq.saturating_sub(lagged.recovered),
+                );
+                prompt(&status)?;
+            }
+            ChatResponse::Token(token) => {
+                debug!(
+                    "Session token of {} valid until {}",
+                    token.username, token.expires_at_ms
+                );
+                if let Some(token_cache) = &token_cache {
+                    token_cache.store(token)?;
+                }
+            }
+            ChatResponse::Resumable(ticket) => {
+                debug!("Session held for {} ms if disconnected", ticket.grace_ms);
+                *resume_ticket.lock().expect("resume ticket lock poisoned") = Some(ticket);
+            }
+            ChatResponse::Resumed(resumed) => {
+                println!(
+                    "Resumed session as {} in {}, {} messages held meanwhile",
+                    resumed.username,
+                    resumed.rooms.join(", "),
+                    resumed.held
+                );
+                prompt(&status)?;
+            }
+            ChatResponse::Ping(ping) => {
+                debug!("Answering ping {}", ping.nonce);
+                // written by the command task, which owns the write half
+                replies.send(ChatCommand::Pong(ping))?;
+            }
+            ChatResponse::Pong(pong) => {
+                let sent = pending_pings
+                    .lock()
+                    .expect("pending pings lock poisoned")
+                    .remove(&pong.nonce);
+                if let Some(sent) = sent {
+                    println!(
+                        "Round trip to chat server: {:.1} ms",
+                        sent.elapsed().as_secs_f64() * 1000.0
+                    );
+                    prompt(&status)?;
+                }
+            }
             ChatResponse::Broadcast(message) => {
                 debug!(
-                    "Received message from {}: {:?}",
-                    message.username, message.content
+                    "Received message from {} in {}: {:?}",
+                    message.username, message.room, message.content
                 );
-                println!("({}): {}", message.username, message.content);
-                print!("> ");
-                stdout().flush()?;
+                if let Some(stamp) = message.stamp {
+                    let missed = sequences.missed(&message.room, stamp.seq);
+                    if missed > 0 {
+                        println!("[{}] {} messages missed", message.room, missed);
+                    }
+                }
+                if message.username == username
+                    && take_unechoed(&unechoed_sends, &message.room, &message.content)
+                {
+                    // own broadcast echoed back, already shown when typed
+                    continue;
+                }
+                println!(
+                    "{}[{}] ({}): {}",
+                    time_of(&message),
+                    message.room,
+                    message.username,
+                    message.content
+                );
+                prompt(&status)?;
             }
         }
     }
 
-    println!("Connection closed by chat server");
-    process::exit(0);
+    debug!("Connection closed by chat server");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatty_types::codec::{Framing, ServerCodec};
+    use chatty_types::command::BearerToken;
+    use chatty_types::response::{Ack, ChatError, SessionToken, Stamp};
+    use futures::SinkExt;
+    use tokio_util::codec::FramedWrite;
+    use uuid::Uuid;
+
+    /// Handles the responses as if the server answered revoking the token with them.
+    async fn answer_revoke(token_cache: &TokenCache, answer: ChatResponse) {
+        let tracked = Tracked {
+            token_cache: Some(token_cache.clone()),
+            ..Tracked::default()
+        };
+        tracked
+            .pending_requests
+            .lock()
+            .unwrap()
+            .insert(1, REVOKE_TOKEN_REQUEST.to_string());
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = FramedWrite::new(server, ServerCodec::new(Framing::Lines));
+        writer.send(&answer).await.unwrap();
+        drop(writer);
+        let reader_half: ReadHalf = Box::new(client);
+        let reader = FramedRead::new(reader_half, ClientCodec::new(Framing::Lines));
+        let (replies, _) = tokio::sync::mpsc::unbounded_channel();
+        process_response(reader, "carl".to_string(), tracked, replies, Vec::new())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cached_token_forgotten_once_revoked() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_cache = TokenCache::new(dir.path().join("tokens.json"));
+        token_cache
+            .store(SessionToken {
+                token: BearerToken("token of carl".to_string()),
+                username: "carl".to_string(),
+                roles: Vec::new(),
+                expires_at_ms: u64::MAX,
+            })
+            .unwrap();
+
+        let failed = ChatResponse::Error(ChatError {
+            id: Some(1),
+            code: ErrorCode::Internal,
+            message: "Failed to revoke".to_string(),
+        });
+        answer_revoke(&token_cache, failed).await;
+        assert!(token_cache.load("carl").is_some());
+
+        answer_revoke(&token_cache, ChatResponse::Ack(Ack { id: 1 })).await;
+        assert_eq!(token_cache.load("carl"), None);
+    }
+
+    #[test]
+    fn test_sequences_count_missed_broadcasts() {
+        let mut sequences = Sequences::default();
+        assert_eq!(sequences.missed("lobby", 4), 0);
+        assert_eq!(sequences.missed("lobby", 5), 0);
+        assert_eq!(sequences.missed("lobby", 8), 2);
+        assert_eq!(sequences.missed("rust", 1), 0);
+        // restarted room, no gap
+        assert_eq!(sequences.missed("lobby", 1), 0);
+        sequences.skip_to("lobby", 10);
+        assert_eq!(sequences.missed("lobby", 11), 0);
+        sequences.forget("rust");
+        assert_eq!(sequences.missed("rust", 7), 0);
+    }
+
+    #[test]
+    fn test_unechoed_sends_are_taken_once() {
+        let unechoed_sends = UnechoedSends::default();
+        unechoed_sends.lock().unwrap().insert(
+            "lobby".to_string(),
+            vec!["hi".to_string(), "bye".to_string()],
+        );
+        assert!(take_unechoed(&unechoed_sends, "lobby", "bye"));
+        // sent from another device
+        assert!(!take_unechoed(&unechoed_sends, "lobby", "bye"));
+        assert!(!take_unechoed(&unechoed_sends, "rust", "hi"));
+        assert!(take_unechoed(&unechoed_sends, "lobby", "hi"));
+    }
+
+    #[test]
+    fn test_time_of_stamped_memo() {
+        let mut memo = ChatMemo {
+            room: "lobby".to_string(),
+            username: "carl".to_string(),
+            content: "hi".to_string(),
+            stamp: None,
+        };
+        assert_eq!(time_of(&memo), "");
+        memo.stamp = Some(Stamp {
+            id: Uuid::now_v7(),
+            timestamp_ms: 1_700_000_000_123,
+            seq: 1,
+        });
+        assert_eq!(time_of(&memo), "22:13:20 ");
+    }
 }
@@ -0,0 +1,135 @@
+use chatty_types::command::BearerToken;
+use chatty_types::response::SessionToken;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Session tokens the server issued, per username, kept in a JSON file
+/// so later runs of the client log in without the password.
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+    path: PathBuf,
+}
+
+impl TokenCache {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Token of the username that has not expired yet, if one is cached.
+    pub fn load(&self, username: &str) -> Option<BearerToken> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.read()
+            .ok()?
+            .remove(username)
+            .filter(|token| token.expires_at_ms > now_ms)
+            .map(|token| token.token)
+    }
+
+    /// Caches the token, replacing any earlier one of its username.
+    pub fn store(&self, token: SessionToken) -> io::Result<()> {
+        let mut tokens = self.read()?;
+        tokens.insert(token.username.clone(), token);
+        self.write(&tokens)
+    }
+
+    /// Forgets the token of the username, once revoked or refused by the server.
+    pub fn remove(&self, username: &str) -> io::Result<()> {
+        let mut tokens = self.read()?;
+        if tokens.remove(username).is_some() {
+            self.write(&tokens)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<BTreeMap<String, SessionToken>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(&self, tokens: &BTreeMap<String, SessionToken>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        // left over by a process that died, and maybe readable by others
+        match fs::remove_file(&temporary) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // anyone able to read the tokens can log in with them, so only the owner can from the start
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temporary)?;
+        file.write_all(&serde_json::to_vec_pretty(tokens)?)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_token(username: &str, expires_at_ms: u64) -> SessionToken {
+        SessionToken {
+            token: BearerToken(format!("token of {}", username)),
+            username: username.to_string(),
+            roles: Vec::new(),
+            expires_at_ms,
+        }
+    }
+
+    #[test]
+    fn test_cached_tokens_per_username() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TokenCache::new(dir.path().join("client").join("tokens.json"));
+        assert_eq!(cache.load("carl"), None);
+
+        cache.store(session_token("carl", u64::MAX)).unwrap();
+        cache.store(session_token("david", u64::MAX)).unwrap();
+        // long expired
+        cache.store(session_token("erin", 1)).unwrap();
+        assert_eq!(
+            cache.load("carl"),
+            Some(BearerToken("token of carl".to_string()))
+        );
+        assert_eq!(cache.load("erin"), None);
+
+        cache.remove("carl").unwrap();
+        assert_eq!(cache.load("carl"), None);
+        assert!(cache.load("david").is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cache_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        // a temporary file left readable by a process that died meanwhile
+        fs::write(path.with_extension("tmp"), b"{}").unwrap();
+        fs::set_permissions(
+            path.with_extension("tmp"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        let cache = TokenCache::new(path.clone());
+        cache.store(session_token("carl", u64::MAX)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(cache.load("carl").is_some());
+    }
+}
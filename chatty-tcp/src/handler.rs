@@ -1,17 +1,58 @@
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use chatty_types::codec::Framing;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
+/// Read half of a connection, plain TCP or TLS on top of it.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Write half of a connection, plain TCP or TLS on top of it.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
 pub struct ChatHandler {
-    pub writer_half: OwnedWriteHalf,
-    pub reader_half: OwnedReadHalf,
+    pub writer_half: WriteHalf,
+    pub reader_half: ReadHalf,
+    pub framing: Framing,
+    pub peer_addr: SocketAddr,
+    /// Username of the client certificate, the only one the client may join as.
+    pub certified: Option<String>,
 }
 
 impl ChatHandler {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        Self::with_framing(stream, Framing::default())
+    }
+
+    pub fn with_framing(stream: TcpStream, framing: Framing) -> io::Result<Self> {
+        let peer_addr = stream.peer_addr()?;
         let (read, write) = stream.into_split();
+        Ok(Self {
+            writer_half: Box::new(write),
+            reader_half: Box::new(read),
+            framing,
+            peer_addr,
+            certified: None,
+        })
+    }
+
+    /// Handler for a stream on top of the TCP connection to the peer, such as TLS.
+    pub fn from_stream<S>(stream: S, peer_addr: SocketAddr, framing: Framing) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
         Self {
-            writer_half: write,
-            reader_half: read,
+            writer_half: Box::new(write),
+            reader_half: Box::new(read),
+            framing,
+            peer_addr,
+            certified: None,
         }
     }
+
+    pub fn with_certified(mut self, certified: Option<String>) -> Self {
+        self.certified = certified;
+        self
+    }
 }
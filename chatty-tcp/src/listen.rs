@@ -1,4 +1,12 @@
+pub mod account;
 pub mod command;
+pub mod connection;
+pub mod handshake;
+pub mod limit;
+pub mod outbound;
+pub mod persist;
+pub mod registry;
 pub mod response;
 pub mod room;
 pub mod state;
+pub mod token;
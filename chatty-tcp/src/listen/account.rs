@@ -0,0 +1,327 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use tracing::info;
+
+/// Shortest password accepted when registering or changing it.
+pub const MIN_PASSWORD_CHARS: usize = 8;
+
+/// A registered username with the argon2 hash of its password, in PHC string format.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+    /// Given by editing the accounts file, and carried in the session tokens of the account.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+}
+
+/// Where accounts are kept, so registered usernames stay reserved for their owners.
+pub trait AccountStore: Send + Sync {
+    fn find(&self, username: &str) -> io::Result<Option<Account>>;
+
+    /// Adds the account unless its username is already registered, returning whether it was added.
+    fn create(&self, account: Account) -> io::Result<bool>;
+
+    /// Replaces the password hash of a registered username.
+    fn update(&self, account: Account) -> io::Result<()>;
+}
+
+/// Accounts only kept in memory, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryAccountStore {
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl AccountStore for MemoryAccountStore {
+    fn find(&self, username: &str) -> io::Result<Option<Account>> {
+        let accounts = self.accounts.lock().expect("accounts lock poisoned");
+        Ok(accounts.get(username).cloned())
+    }
+
+    fn create(&self, account: Account) -> io::Result<bool> {
+        let mut accounts = self.accounts.lock().expect("accounts lock poisoned");
+        if accounts.contains_key(&account.username) {
+            return Ok(false);
+        }
+        accounts.insert(account.username.clone(), account);
+        Ok(true)
+    }
+
+    fn update(&self, account: Account) -> io::Result<()> {
+        let mut accounts = self.accounts.lock().expect("accounts lock poisoned");
+        accounts.insert(account.username.clone(), account);
+        Ok(())
+    }
+}
+
+/// Accounts kept in a JSON file, read once when opened and rewritten on every change.
+/// The new contents go to a temporary file, only readable by the owner, synced and renamed over
+/// the old one, so a crash mid-write never leaves a torn file behind nor loses what was saved.
+pub struct FileAccountStore {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl FileAccountStore {
+    /// Opens the accounts file, which is created on the first registration if missing.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let accounts: Vec<Account> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        info!("Loaded {} accounts from {}", accounts.len(), path.display());
+        let accounts = accounts
+            .into_iter()
+            .map(|account| (account.username.clone(), account))
+            .collect();
+        Ok(Self {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    fn save(&self, accounts: &HashMap<String, Account>) -> io::Result<()> {
+        let mut sorted: Vec<&Account> = accounts.values().collect();
+        sorted.sort_by(|a, b| a.username.cmp(&b.username));
+        let temporary = self.path.with_extension("tmp");
+        // left over by a crash, and maybe readable by others
+        match fs::remove_file(&temporary) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // password hashes can be guessed at offline by anyone able to read them
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temporary)?;
+        file.write_all(&serde_json::to_vec_pretty(&sorted)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        // the rename is only on disk once the directory is
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn find(&self, username: &str) -> io::Result<Option<Account>> {
+        let accounts = self.accounts.lock().expect("accounts lock poisoned");
+        Ok(accounts.get(username).cloned())
+    }
+
+    fn create(&self, account: Account) -> io::Result<bool> {
+        let mut accounts = self.accounts.lock().expect("accounts lock poisoned");
+        if accounts.contains_key(&account.username) {
+            return Ok(false);
+        }
+        accounts.insert(account.username.clone(), account.clone());
+        if let Err(e) = self.save(&accounts) {
+            accounts.remove(&account.username);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn update(&self, account: Account) -> io::Result<()> {
+        let mut accounts = self.accounts.lock().expect("accounts lock poisoned");
+        let previous = accounts.insert(account.username.clone(), account.clone());
+        if let Err(e) = self.save(&accounts) {
+            match previous {
+                Some(previous) => accounts.insert(account.username, previous),
+                None => accounts.remove(&account.username),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Usernames guests, users joining without an account, may take.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum GuestNames {
+    /// Any username not registered.
+    #[default]
+    Any,
+    /// Only usernames with the prefix, which in turn cannot be registered.
+    Prefixed(String),
+    /// None, everyone has to log in.
+    Disabled,
+}
+
+impl GuestNames {
+    /// Why a guest cannot join with the username, if the rule forbids it.
+    pub fn check_guest(&self, username: &str) -> Result<(), String> {
+        match self {
+            GuestNames::Any => Ok(()),
+            GuestNames::Prefixed(prefix) if username.starts_with(prefix.as_str()) => Ok(()),
+            GuestNames::Prefixed(prefix) => Err(format!("Guest usernames start with {}", prefix)),
+            GuestNames::Disabled => Err("Guests are not allowed, log in instead".to_string()),
+        }
+    }
+
+    /// Why the username cannot be registered, if it is kept for guests.
+    pub fn check_registered(&self, username: &str) -> Result<(), String> {
+        match self {
+            GuestNames::Prefixed(prefix) if username.starts_with(prefix.as_str()) => {
+                Err(format!("Usernames starting with {} are for guests", prefix))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for GuestNames {
+    type Err = String;
+
+    /// "any", "none", or the prefix guest usernames need.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("Empty guest naming rule".to_string()),
+            "any" => Ok(GuestNames::Any),
+            "none" => Ok(GuestNames::Disabled),
+            prefix => Ok(GuestNames::Prefixed(prefix.to_string())),
+        }
+    }
+}
+
+pub fn check_password_strength(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_CHARS {
+        return Err(format!(
+            "Passwords need at least {} characters",
+            MIN_PASSWORD_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// Argon2 hash of the password with a random salt. Slow by design, so run it off the runtime's workers.
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Whether the password matches the account's, or without an account spends as long
+/// as if it had one, so timing does not tell which usernames are registered.
+pub fn verify_password(account: Option<&Account>, password: &str) -> bool {
+    static UNKNOWN: OnceLock<String> = OnceLock::new();
+    let password_hash = match account {
+        Some(account) => account.password_hash.as_str(),
+        None => UNKNOWN.get_or_init(|| hash_password("no account").unwrap_or_default()),
+    };
+    let Ok(parsed) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok()
+        && account.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passwords_are_salted_and_verified() {
+        let first = hash_password("correct horse").unwrap();
+        let second = hash_password("correct horse").unwrap();
+        assert_ne!(first, second);
+        let account = Account {
+            username: "carl".to_string(),
+            password_hash: first,
+            roles: Vec::new(),
+        };
+        assert!(verify_password(Some(&account), "correct horse"));
+        assert!(!verify_password(Some(&account), "battery staple"));
+        assert!(!verify_password(None, "correct horse"));
+    }
+
+    #[test]
+    fn test_file_store_keeps_accounts_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+        let store = FileAccountStore::open(path.clone()).unwrap();
+        let account = |password_hash: &str| Account {
+            username: "carl".to_string(),
+            password_hash: password_hash.to_string(),
+            roles: Vec::new(),
+        };
+        assert!(store.create(account("first")).unwrap());
+        assert!(!store.create(account("second")).unwrap());
+        drop(store);
+
+        let store = FileAccountStore::open(path.clone()).unwrap();
+        assert_eq!(store.find("carl").unwrap(), Some(account("first")));
+        store.update(account("changed")).unwrap();
+        assert_eq!(store.find("david").unwrap(), None);
+        drop(store);
+        let store = FileAccountStore::open(path).unwrap();
+        assert_eq!(store.find("carl").unwrap(), Some(account("changed")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_store_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+        // a temporary file left readable by a crash
+        fs::write(path.with_extension("tmp"), b"[]").unwrap();
+        fs::set_permissions(
+            path.with_extension("tmp"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        let store = FileAccountStore::open(path.clone()).unwrap();
+        let account = Account {
+            username: "carl".to_string(),
+            password_hash: hash_password("correct horse").unwrap(),
+            roles: Vec::new(),
+        };
+        assert!(store.create(account).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_guest_naming_rules() {
+        let prefixed: GuestNames = "guest-".parse().unwrap();
+        assert_eq!(prefixed.check_guest("guest-carl"), Ok(()));
+        assert!(prefixed.check_guest("carl").is_err());
+        assert!(prefixed.check_registered("guest-carl").is_err());
+        assert_eq!(prefixed.check_registered("carl"), Ok(()));
+        assert_eq!(
+            "any".parse::<GuestNames>().unwrap().check_guest("carl"),
+            Ok(())
+        );
+        assert!("none"
+            .parse::<GuestNames>()
+            .unwrap()
+            .check_guest("carl")
+            .is_err());
+    }
+}
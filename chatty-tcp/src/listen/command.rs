@@ -1,16 +1,33 @@
+use crate::handler::{ReadHalf, WriteHalf};
+use crate::listen::account::{check_password_strength, hash_password, verify_password, Account};
+use crate::listen::connection::{ConnectionState, HeartbeatConfig};
+use crate::listen::handshake::negotiate;
+use crate::listen::limit::{Action, Verdict};
+use crate::listen::outbound::Outbound;
+use crate::listen::registry::{RoomRegistry, SessionId};
 use crate::listen::response::{
-    send_from_broadcast_channel, send_response, send_to_broadcast_channel,
+    send_from_broadcast_channel, send_to_broadcast_channel, SharedResponse,
 };
 use crate::listen::state::RoomState;
+use crate::listen::token::ADMIN_ROLE;
 use anyhow::Result;
-use chatty_types::command::ChatCommand;
-use chatty_types::response::{ChatMemo, ChatResponse};
+use chatty_types::codec::{CodecError, Framing, ServerCodec, Tolerant};
+use chatty_types::command::{ChatCommand, Credentials, PasswordChange, RequestId, DEFAULT_ROOM};
+use chatty_types::protocol::Capability;
+use chatty_types::response::{
+    Ack, ChatError, ChatMemo, ChatResponse, DirectMemo, ErrorCode, ResumeTicket, Resumed, Throttled,
+};
+use futures::StreamExt;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::Mutex;
-use tracing::{debug, info};
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Error)]
 pub enum RoomError {
@@ -24,132 +41,1166 @@ pub enum RoomError {
     BroadcastReceive(String),
 
     #[error("Broadcast send error: {0}")]
-    BroadcastSend(#[from] tokio::sync::broadcast::error::SendError<ChatResponse>),
+    BroadcastSend(#[from] tokio::sync::broadcast::error::SendError<SharedResponse>),
+
+    #[error("Codec error is: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error("Connection closed")]
+    ConnectionClosed,
+
+    #[error("Client too slow, disconnected with {0} responses queued")]
+    SlowConsumer(usize),
+
+    #[error("Nothing heard from the client for {0:?}")]
+    IdleTimeout(Duration),
+
+    #[error("Client kept going over the rate limits")]
+    Flooding,
+
+    #[error("{0} malformed commands in a row, the last one: {1}")]
+    Malformed(u32, CodecError),
+
+    #[error("Session resumed on another connection")]
+    TakenOver,
+}
+
+impl RoomError {
+    /// Whether the connection may have dropped with the client coming back,
+    /// rather than having been cut off for misbehaving.
+    fn is_resumable(&self) -> bool {
+        matches!(
+            self,
+            RoomError::Io(_)
+                | RoomError::Codec(CodecError::Io(_))
+                | RoomError::ConnectionClosed
+                | RoomError::IdleTimeout(_)
+                | RoomError::TakenOver
+        )
+    }
 }
+
+/// Read half of a client connection, decoding commands and going on after those it cannot decode.
+pub type CommandReader = FramedRead<ReadHalf, Tolerant<ChatResponse, ChatCommand>>;
+
 pub async fn process_command(
-    writer_half: OwnedWriteHalf,
-    reader_half: OwnedReadHalf,
-    room_state: Arc<RoomState>,
+    writer_half: WriteHalf,
+    reader_half: ReadHalf,
+    addr: SocketAddr,
+    certified: Option<String>,
+    framing: Framing,
+    registry: Arc<RoomRegistry>,
 ) -> Result<(), RoomError> {
-    let addr = reader_half.peer_addr()?;
     debug!("handling client connection from {}", addr);
-    let writer = Arc::new(Mutex::new(writer_half));
-    let mut reader = BufReader::new(reader_half).lines();
-    while let Some(line) = reader.next_line().await? {
-        debug!("Received line for command: {:?}", line);
-        let command: ChatCommand = serde_json::from_str(&line)?;
-        match command {
-            ChatCommand::Join(username) => {
-                let user_already_exist =
-                    room_state.task_handles.lock().await.contains_key(&username);
-
-                let chat_response = if !user_already_exist {
-                    let rx = room_state.tx.subscribe();
-                    let send_task_handle = tokio::spawn(send_from_broadcast_channel(
-                        writer.clone(),
-                        rx,
-                        username.clone(),
-                    ));
-                    room_state
-                        .task_handles
-                        .lock()
-                        .await
-                        .insert(username.clone(), send_task_handle);
-                    info!(
-                        "Users in room after addition: {:?}",
-                        room_state.task_handles.lock().await.keys()
-                    );
-                    info!("Client {} joined as {}", addr, username);
-                    send_to_broadcast_channel(
-                        ChatResponse::Broadcast(ChatMemo {
-                            username: username.clone(),
-                            content: "Joined".to_string(),
-                        }),
-                        room_state.clone(),
-                    )
-                    .await?;
-                    ChatResponse::Joined(ChatMemo {
-                        username,
-                        content: "Warm Welcome".to_string(),
-                    })
-                } else {
-                    ChatResponse::Duplicate(ChatMemo {
-                        username,
-                        content: "Sorry".to_string(),
-                    })
+    let mut connection = ConnectionState::new(addr, registry.clone()).with_certified(certified);
+    // both ways bounded by the configured frame size, history pages are cut to fit it
+    let max_frame_bytes = registry.size_limits().max_frame_bytes;
+    let writer = FramedWrite::new(
+        writer_half,
+        ServerCodec::new(framing).with_max_frame_bytes(max_frame_bytes),
+    );
+    let outbound = Outbound::spawn(writer, addr, registry.outbound_queue());
+    let reader = FramedRead::new(
+        reader_half,
+        Tolerant::new(ServerCodec::new(framing).with_max_frame_bytes(max_frame_bytes)),
+    );
+    let result = handle_commands(outbound.clone(), reader, registry, &mut connection).await;
+    if let Err(e) = &result {
+        info!("Connection from {} ended with error: {}", addr, e);
+    }
+    // EOF or error: whoever joined through this connection has to leave the chat,
+    // unless the session is held for the client to come back
+    let resumable = result
+        .as_ref()
+        .map_or_else(RoomError::is_resumable, |_| true);
+    if !(resumable && connection.park(&outbound).await) {
+        connection.release_on_disconnect().await;
+        outbound.close();
+    }
+    result
+}
+
+/// What came of a single command.
+enum Handled {
+    Done,
+    Failed(Failure),
+    /// The connection is closed after the responses already sent.
+    Close,
+}
+
+/// A command that could not be carried out. Clients that gave the command a request id
+/// get an `Error`, all others the response from before request ids.
+#[derive(Debug)]
+struct Failure {
+    code: ErrorCode,
+    message: String,
+    // boxed to keep results with a failure small
+    response: Box<ChatResponse>,
+}
+
+impl Failure {
+    fn rejected(code: ErrorCode, room: &str, username: String, message: String) -> Self {
+        Self {
+            code,
+            response: Box::new(ChatResponse::Rejected(ChatMemo {
+                room: room.to_string(),
+                username,
+                content: message.clone(),
+                stamp: None,
+            })),
+            message,
+        }
+    }
+
+    /// Failures of the server itself, which had no response before request ids.
+    fn internal(message: String) -> Self {
+        Self {
+            code: ErrorCode::Internal,
+            response: Box::new(ChatResponse::Error(ChatError {
+                id: None,
+                code: ErrorCode::Internal,
+                message: message.clone(),
+            })),
+            message,
+        }
+    }
+
+    fn into_response(self, id: Option<RequestId>) -> ChatResponse {
+        match id {
+            Some(id) => ChatResponse::Error(ChatError {
+                id: Some(id),
+                code: self.code,
+                message: self.message,
+            }),
+            None => *self.response,
+        }
+    }
+}
+
+async fn handle_commands(
+    outbound: Outbound,
+    mut reader: CommandReader,
+    registry: Arc<RoomRegistry>,
+    connection: &mut ConnectionState,
+) -> Result<(), RoomError> {
+    let heartbeat = registry.heartbeat();
+    let mut checks = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let takeover = connection.takeover();
+    loop {
+        let decoded = select! {
+            decoded = reader.next() => decoded,
+            // disconnected for being too slow or the client stopped reading
+            _ = outbound.closed() => return Err(RoomError::ConnectionClosed),
+            // the client came back on another connection before this one was noticed to be gone
+            _ = takeover.notified() => return Err(RoomError::TakenOver),
+            _ = checks.tick() => {
+                check_on_quiet(&outbound, connection, heartbeat).await?;
+                continue;
+            }
+        };
+        let Some(decoded) = decoded else {
+            break;
+        };
+        connection.heard();
+        let command = match decoded {
+            Ok(Ok(command)) => {
+                connection.well_formed();
+                command
+            }
+            Ok(Err(e)) => {
+                // a command of a newer client or garbage, the frame itself was fine
+                let strikes = connection.malformed();
+                debug!("Malformed command {} in a row: {}", strikes, e);
+                let error = ChatResponse::Error(ChatError {
+                    id: None,
+                    code: ErrorCode::Malformed,
+                    message: e.to_string(),
+                });
+                let max_strikes = registry.malformed_strikes();
+                if max_strikes > 0 && strikes >= max_strikes {
+                    let _ = outbound.send(error).await;
+                    return Err(RoomError::Malformed(strikes, e));
+                }
+                outbound.send(error).await?;
+                continue;
+            }
+            Err(e) => {
+                // nothing more is read after a frame that could not be delimited,
+                // but the client gets to know why before the connection is closed
+                let code = match e {
+                    CodecError::FrameTooLarge { .. } => ErrorCode::TooLarge,
+                    _ => ErrorCode::Malformed,
                 };
-                send_response(chat_response, writer.clone()).await?;
+                let error = ChatResponse::Error(ChatError {
+                    id: None,
+                    code,
+                    message: e.to_string(),
+                });
+                let _ = outbound.send(error).await;
+                return Err(e.into());
             }
-            ChatCommand::Send(message) => {
-                debug!(
-                    "Received message from {}: {:?}",
-                    message.username, message.content
-                );
-                let chat_response = ChatResponse::Broadcast(ChatMemo {
-                    username: message.username.clone(),
-                    content: message.content.clone(),
+        };
+        debug!("Received command: {:?}", command);
+        let (id, command) = match command {
+            ChatCommand::Request(request) => (Some(request.id), *request.command),
+            command => (None, command),
+        };
+        let verdict = match Action::of(&command) {
+            Some(action) => connection.check_limit(action).await,
+            None => Verdict::Allowed,
+        };
+        let throttled = match verdict {
+            Verdict::Allowed => None,
+            Verdict::Throttled(retry_after) => Some((retry_after, false)),
+            Verdict::Muted(retry_after) => Some((retry_after, true)),
+            Verdict::Disconnect => {
+                warn!("Disconnecting {} for flooding", connection.addr());
+                let error = ChatResponse::Error(ChatError {
+                    id,
+                    code: ErrorCode::Flooding,
+                    message: "Disconnected for going over the rate limits repeatedly".to_string(),
                 });
+                let _ = outbound.send(error).await;
+                return Err(RoomError::Flooding);
+            }
+        };
+        if let Some((retry_after, muted)) = throttled {
+            debug!("Throttled {} for {:?}", connection.addr(), retry_after);
+            let throttled = Throttled {
+                id,
+                retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u64::MAX),
+                muted,
+            };
+            outbound.send(ChatResponse::Throttled(throttled)).await?;
+            continue;
+        }
+        let handled = if let ChatCommand::Request(_) = command {
+            Handled::Failed(Failure::rejected(
+                ErrorCode::Malformed,
+                DEFAULT_ROOM,
+                connection.username().unwrap_or_default().to_string(),
+                "Requests cannot be nested".to_string(),
+            ))
+        } else if let Err(e) = registry.size_limits().check(&command) {
+            Handled::Failed(Failure::rejected(
+                ErrorCode::TooLarge,
+                DEFAULT_ROOM,
+                connection.username().unwrap_or_default().to_string(),
+                e.to_string(),
+            ))
+        } else {
+            handle_command(command, &outbound, &mut reader, &registry, connection).await?
+        };
+        match handled {
+            Handled::Done => {
+                if let Some(id) = id {
+                    outbound.send(ChatResponse::Ack(Ack { id })).await?;
+                }
+            }
+            Handled::Failed(failure) => {
                 debug!(
-                    "Going to Broadcast for others the Received message {:?}",
-                    chat_response
+                    "Command failed with {:?}: {}",
+                    failure.code, failure.message
                 );
-                send_to_broadcast_channel(chat_response, room_state.clone()).await?;
-            }
-            ChatCommand::Leave(username) => {
-                remove_username(username.clone(), room_state.clone()).await;
-                debug!("User {} has left", username);
-
-                debug!("User {} has left so sending broadcast message", username);
-                send_to_broadcast_channel(
-                    ChatResponse::Broadcast(ChatMemo {
-                        username: username.clone(),
-                        content: "Left".to_string(),
-                    }),
-                    room_state.clone(),
-                )
-                .await?;
-                debug!("completed User {} leave handling", username);
+                outbound.send(failure.into_response(id)).await?;
             }
+            Handled::Close => return Ok(()),
         }
     }
     Ok(())
 }
 
-pub async fn remove_username(username: String, room_state: Arc<RoomState>) {
+/// Disconnects a connection quiet for longer than the idle timeout, which also catches
+/// half-open connections, and pings one with the heartbeat capability quiet for an interval.
+async fn check_on_quiet(
+    outbound: &Outbound,
+    connection: &mut ConnectionState,
+    heartbeat: HeartbeatConfig,
+) -> Result<(), RoomError> {
+    let quiet_for = connection.quiet_for();
+    if heartbeat
+        .idle_timeout
+        .is_some_and(|idle_timeout| quiet_for >= idle_timeout)
+    {
+        return Err(RoomError::IdleTimeout(quiet_for));
+    }
+    if quiet_for >= heartbeat.interval && connection.supports(Capability::Heartbeat) {
+        outbound.send(ChatResponse::Ping(connection.ping())).await?;
+    }
+    Ok(())
+}
+
+async fn handle_command(
+    command: ChatCommand,
+    outbound: &Outbound,
+    reader: &mut CommandReader,
+    registry: &Arc<RoomRegistry>,
+    connection: &mut ConnectionState,
+) -> Result<Handled, RoomError> {
+    let addr = connection.addr();
+    match command {
+        ChatCommand::Hello(hello) => {
+            if connection.greeted() || connection.username().is_some() {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::UnexpectedHello,
+                    DEFAULT_ROOM,
+                    connection.username().unwrap_or_default().to_string(),
+                    "Hello is only accepted as the first command".to_string(),
+                )));
+            }
+            match negotiate(&hello, reader.decoder().framing()) {
+                Ok(welcome) => {
+                    info!(
+                        "Client {} running {} speaks protocol {} in {:?} with {:?}",
+                        addr,
+                        hello.software,
+                        welcome.protocol_version,
+                        welcome.encoding,
+                        welcome.capabilities
+                    );
+                    connection.greet(welcome.capabilities.clone());
+                    let encoding = welcome.encoding;
+                    // the client switched right after its Hello, the server right after the Welcome
+                    reader.decoder_mut().set_encoding(encoding);
+                    outbound
+                        .send_then_encode(ChatResponse::Welcome(welcome), encoding)
+                        .await?;
+                }
+                Err(incompatible) => {
+                    info!("Client {} refused: {}", addr, incompatible.reason);
+                    outbound
+                        .send(ChatResponse::Incompatible(incompatible))
+                        .await?;
+                    return Ok(Handled::Close);
+                }
+            }
+        }
+        ChatCommand::Join(username) => {
+            if let Some(failure) = check_sign_in(connection, registry, &username) {
+                return Ok(Handled::Failed(failure));
+            }
+            let registered = match registry.accounts() {
+                Some(accounts) => match accounts.find(&username) {
+                    Ok(account) => account.is_some(),
+                    Err(e) => return Ok(Handled::Failed(Failure::internal(e.to_string()))),
+                },
+                None => false,
+            };
+            if registered {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::Unauthorized,
+                    DEFAULT_ROOM,
+                    username.clone(),
+                    format!("Username {} is registered, log in instead", username),
+                )));
+            }
+            if let Err(message) = registry.guest_names().check_guest(&username) {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::InvalidUsername,
+                    DEFAULT_ROOM,
+                    username,
+                    message,
+                )));
+            }
+            if !registry
+                .reserve_username(&username, connection.session_id(), outbound.clone(), false)
+                .await
+            {
+                return Ok(Handled::Failed(username_taken(username)));
+            }
+            connection.claim(username.clone());
+            info!("Client {} joined as {}", addr, username);
+            return enter_chat(&username, outbound, registry, connection).await;
+        }
+        ChatCommand::Register(Credentials { username, password }) => {
+            if let Some(failure) = check_sign_in(connection, registry, &username) {
+                return Ok(Handled::Failed(failure));
+            }
+            let Some(accounts) = registry.accounts().cloned() else {
+                return Ok(Handled::Failed(accounts_unsupported(username)));
+            };
+            if let Err(message) = registry.guest_names().check_registered(&username) {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::InvalidUsername,
+                    DEFAULT_ROOM,
+                    username,
+                    message,
+                )));
+            }
+            if let Err(message) = check_password_strength(&password) {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::WeakPassword,
+                    DEFAULT_ROOM,
+                    username,
+                    message,
+                )));
+            }
+            // held while hashing, so no guest takes the username in the meantime
+            let session = connection.session_id();
+            if !registry
+                .reserve_username(&username, session, outbound.clone(), true)
+                .await
+            {
+                return Ok(Handled::Failed(username_taken(username)));
+            }
+            let account_username = username.clone();
+            let created = blocking(move || {
+                let password_hash = hash_password(&password)?;
+                accounts.create(Account {
+                    username: account_username,
+                    password_hash,
+                    roles: Vec::new(),
+                })
+            })
+            .await;
+            match created {
+                Ok(true) => {}
+                Ok(false) => {
+                    registry.release_username(&username, session).await;
+                    return Ok(Handled::Failed(username_taken(username)));
+                }
+                Err(e) => {
+                    registry.release_username(&username, session).await;
+                    return Ok(Handled::Failed(Failure::internal(e.to_string())));
+                }
+            }
+            connection.log_in(username.clone(), Vec::new());
+            info!("Client {} registered as {}", addr, username);
+            return enter_chat(&username, outbound, registry, connection).await;
+        }
+        ChatCommand::Login(Credentials { username, password }) => {
+            if let Some(failure) = check_sign_in(connection, registry, &username) {
+                return Ok(Handled::Failed(failure));
+            }
+            let Some(accounts) = registry.accounts().cloned() else {
+                return Ok(Handled::Failed(accounts_unsupported(username)));
+            };
+            let account_username = username.clone();
+            let verified = blocking(move || {
+                let account = accounts.find(&account_username)?;
+                Ok(verify_password(account.as_ref(), &password)
+                    .then(|| account.map(|account| account.roles).unwrap_or_default()))
+            })
+            .await;
+            let roles = match verified {
+                Ok(Some(roles)) => roles,
+                Ok(None) => {
+                    info!("Client {} failed to log in as {}", addr, username);
+                    return Ok(Handled::Failed(Failure::rejected(
+                        ErrorCode::Unauthorized,
+                        DEFAULT_ROOM,
+                        username,
+                        "Wrong username or password".to_string(),
+                    )));
+                }
+                Err(e) => return Ok(Handled::Failed(Failure::internal(e.to_string()))),
+            };
+            // the account may be online on other devices already
+            if !registry
+                .reserve_username(&username, connection.session_id(), outbound.clone(), true)
+                .await
+            {
+                return Ok(Handled::Failed(username_taken(username)));
+            }
+            connection.log_in(username.clone(), roles);
+            info!("Client {} logged in as {}", addr, username);
+            return enter_chat(&username, outbound, registry, connection).await;
+        }
+        ChatCommand::ChangePassword(PasswordChange { current, new }) => {
+            let username = match verify_identity(connection, None, DEFAULT_ROOM) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            let Some(accounts) = registry.accounts().cloned() else {
+                return Ok(Handled::Failed(accounts_unsupported(username)));
+            };
+            if !connection.logged_in() {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::Unauthorized,
+                    DEFAULT_ROOM,
+                    username,
+                    "Guests have no password, register an account instead".to_string(),
+                )));
+            }
+            if let Err(message) = check_password_strength(&new) {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::WeakPassword,
+                    DEFAULT_ROOM,
+                    username,
+                    message,
+                )));
+            }
+            let account_username = username.clone();
+            let changed = blocking(move || {
+                let account = accounts.find(&account_username)?;
+                if !verify_password(account.as_ref(), &current) {
+                    return Ok(false);
+                }
+                let Some(account) = account else {
+                    return Ok(false);
+                };
+                let password_hash = hash_password(&new)?;
+                accounts.update(Account {
+                    password_hash,
+                    ..account
+                })?;
+                Ok(true)
+            })
+            .await;
+            match changed {
+                Ok(true) => info!("User {} changed their password", username),
+                Ok(false) => {
+                    return Ok(Handled::Failed(Failure::rejected(
+                        ErrorCode::Unauthorized,
+                        DEFAULT_ROOM,
+                        username,
+                        "Wrong password".to_string(),
+                    )))
+                }
+                Err(e) => return Ok(Handled::Failed(Failure::internal(e.to_string()))),
+            }
+        }
+        ChatCommand::IssueToken => {
+            let username = match verify_identity(connection, None, DEFAULT_ROOM) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            let Some(tokens) = registry.tokens() else {
+                return Ok(Handled::Failed(tokens_unsupported(username)));
+            };
+            if !connection.logged_in() {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::Unauthorized,
+                    DEFAULT_ROOM,
+                    username,
+                    "Only accounts get session tokens, register one instead".to_string(),
+                )));
+            }
+            let token = tokens.issue(&username, connection.roles().to_vec());
+            info!("Issued session token to {}", username);
+            outbound.send(ChatResponse::Token(token)).await?;
+        }
+        ChatCommand::TokenLogin(token) => {
+            let Some(tokens) = registry.tokens() else {
+                return Ok(Handled::Failed(tokens_unsupported(
+                    connection.username().unwrap_or_default().to_string(),
+                )));
+            };
+            let claims = match tokens.verify(&token) {
+                Ok(claims) => claims,
+                Err(e) => {
+                    info!("Client {} failed to log in with a token: {}", addr, e);
+                    return Ok(Handled::Failed(Failure::rejected(
+                        ErrorCode::Unauthorized,
+                        DEFAULT_ROOM,
+                        connection.username().unwrap_or_default().to_string(),
+                        e.to_string(),
+                    )));
+                }
+            };
+            if let Some(failure) = check_sign_in(connection, registry, &claims.sub) {
+                return Ok(Handled::Failed(failure));
+            }
+            let username = claims.sub.clone();
+            if !registry
+                .reserve_username(&username, connection.session_id(), outbound.clone(), true)
+                .await
+            {
+                return Ok(Handled::Failed(username_taken(username)));
+            }
+            connection.log_in_with_token(claims);
+            info!("Client {} logged in with a token as {}", addr, username);
+            return enter_chat(&username, outbound, registry, connection).await;
+        }
+        ChatCommand::RevokeToken(token) => {
+            let username = match verify_identity(connection, None, DEFAULT_ROOM) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            let Some(tokens) = registry.tokens() else {
+                return Ok(Handled::Failed(tokens_unsupported(username)));
+            };
+            let claims = match token {
+                Some(token) => match tokens.signed_claims(&token) {
+                    Ok(claims) => claims,
+                    Err(e) => {
+                        return Ok(Handled::Failed(Failure::rejected(
+                            ErrorCode::Unauthorized,
+                            DEFAULT_ROOM,
+                            username,
+                            e.to_string(),
+                        )))
+                    }
+                },
+                None => match connection.session() {
+                    Some(claims) => claims.clone(),
+                    None => {
+                        return Ok(Handled::Failed(Failure::rejected(
+                            ErrorCode::Unauthorized,
+                            DEFAULT_ROOM,
+                            username,
+                            "Not logged in with a session token".to_string(),
+                        )))
+                    }
+                },
+            };
+            if claims.sub != username && !connection.has_role(ADMIN_ROLE) {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::WrongIdentity,
+                    DEFAULT_ROOM,
+                    username,
+                    format!(
+                        "Token is for {}, only admins revoke those of others",
+                        claims.sub
+                    ),
+                )));
+            }
+            let revoked_for = claims.sub.clone();
+            let tokens = tokens.clone();
+            if let Err(e) = blocking(move || tokens.revoke(&claims)).await {
+                return Ok(Handled::Failed(Failure::internal(e.to_string())));
+            }
+            info!(
+                "User {} revoked a session token of {}",
+                username, revoked_for
+            );
+        }
+        ChatCommand::Resume(ticket) => {
+            if let Some(joined) = connection.username() {
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::AlreadyJoined,
+                    DEFAULT_ROOM,
+                    joined.to_string(),
+                    format!("Already joined as {} on this connection", joined),
+                )));
+            }
+            let Some(parked) = registry.resume(&ticket).await else {
+                info!("Client {} failed to resume a session", addr);
+                return Ok(Handled::Failed(Failure::rejected(
+                    ErrorCode::UnknownSession,
+                    DEFAULT_ROOM,
+                    String::new(),
+                    "No session to resume with this ticket, join instead".to_string(),
+                )));
+            };
+            if let Some(failure) = check_sign_in(connection, registry, &parked.username) {
+                // not for this client to take, held for whoever can
+                registry.park(ticket, parked).await;
+                return Ok(Handled::Failed(failure));
+            }
+            let held = connection.resume(parked);
+            let username = connection.username().unwrap_or_default().to_string();
+            let mut rooms: Vec<String> = connection.rooms().iter().cloned().collect();
+            rooms.sort();
+            info!("Client {} resumed the session of {}", addr, username);
+            // what was held goes out right after the Resumed, ahead of anything sent from now on
+            held.redirect(outbound, |held| {
+                ChatResponse::Resumed(Resumed {
+                    username,
+                    rooms,
+                    held: held as u64,
+                })
+            });
+            offer_resume(outbound, registry, connection).await?;
+        }
+        ChatCommand::JoinRoom(room) => {
+            let username = match verify_identity(connection, None, &room) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            if !connection.supports(Capability::Rooms) {
+                return Ok(Handled::Failed(not_negotiated(Capability::Rooms, username)));
+            }
+            return join_room(&room, &username, outbound.clone(), registry, connection).await;
+        }
+        ChatCommand::History(request) => {
+            let username = match verify_identity(connection, None, &request.room) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            let Some(room_state) = registry
+                .room(&request.room)
+                .await
+                .filter(|_| connection.in_room(&request.room))
+            else {
+                return Ok(Handled::Failed(not_in_room(&request.room, username)));
+            };
+            let page = room_state
+                .history_page(request.before, registry.size_limits().max_frame_bytes)
+                .await;
+            outbound.send(ChatResponse::History(page)).await?;
+        }
+        ChatCommand::PartRoom(room) => {
+            let username = match verify_identity(connection, None, &room) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            if !connection.supports(Capability::Rooms) {
+                return Ok(Handled::Failed(not_negotiated(Capability::Rooms, username)));
+            }
+            if !connection.part_room(&room) {
+                return Ok(Handled::Failed(not_in_room(&room, username)));
+            }
+            part_room(&room, &username, connection.session_id(), registry).await;
+            let parted = ChatResponse::Parted(ChatMemo {
+                room,
+                username,
+                content: "Goodbye".to_string(),
+                stamp: None,
+            });
+            outbound.send(parted).await?;
+        }
+        ChatCommand::ListRooms => {
+            if !connection.supports(Capability::Rooms) {
+                let username = connection.username().unwrap_or_default().to_string();
+                return Ok(Handled::Failed(not_negotiated(Capability::Rooms, username)));
+            }
+            let rooms = registry.summaries().await;
+            outbound.send(ChatResponse::Rooms(rooms)).await?;
+        }
+        ChatCommand::Direct(message) => {
+            let from = match verify_identity(connection, None, DEFAULT_ROOM) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            if !connection.supports(Capability::DirectMessages) {
+                return Ok(Handled::Failed(not_negotiated(
+                    Capability::DirectMessages,
+                    from,
+                )));
+            }
+            let direct_memo = DirectMemo {
+                from,
+                to: message.to,
+                content: message.content,
+            };
+            debug!("Received direct message {:?}", direct_memo);
+            // to every session of the recipient, and every other one of the sender to keep up
+            let mut delivered = false;
+            for (_, recipient) in registry.user_sessions(&direct_memo.to).await {
+                delivered |= recipient
+                    .send(ChatResponse::Direct(direct_memo.clone()))
+                    .await
+                    // the recipient's own connection cleans up after itself
+                    .inspect_err(|e| debug!("Failed to deliver direct message: {:?}", e))
+                    .is_ok();
+            }
+            if delivered && direct_memo.to != direct_memo.from {
+                for (session, sender) in registry.user_sessions(&direct_memo.from).await {
+                    if session != connection.session_id() {
+                        let _ = sender.send(ChatResponse::Direct(direct_memo.clone())).await;
+                    }
+                }
+            }
+            if !delivered {
+                return Ok(Handled::Failed(Failure {
+                    code: ErrorCode::UserOffline,
+                    message: format!("{} is not online", direct_memo.to),
+                    response: Box::new(ChatResponse::Undelivered(direct_memo)),
+                }));
+            }
+        }
+        ChatCommand::Send(message) => {
+            let room = message.room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+            let username = match verify_identity(connection, message.username, &room) {
+                Ok(username) => username,
+                Err(failure) => return Ok(Handled::Failed(failure)),
+            };
+            let Some(room_state) = registry
+                .room(&room)
+                .await
+                .filter(|_| connection.in_room(&room))
+            else {
+                return Ok(Handled::Failed(not_in_room(&room, username)));
+            };
+            debug!(
+                "Received message from {} for room {}: {:?}",
+                username, room, message.content
+            );
+            let chat_response = ChatResponse::Broadcast(ChatMemo {
+                room,
+                username,
+                content: message.content,
+                stamp: None,
+            });
+            debug!(
+                "Going to Broadcast for others the Received message {:?}",
+                chat_response
+            );
+            let broadcast = room_state
+                .broadcast_from(Some(connection.session_id()), chat_response)
+                .await;
+            if let Err(e) = broadcast {
+                return Ok(Handled::Failed(Failure::internal(format!(
+                    "Message could not be broadcast: {}",
+                    e
+                ))));
+            }
+        }
+        ChatCommand::Leave(claimed) => {
+            if let Err(failure) = verify_identity(connection, claimed, DEFAULT_ROOM) {
+                return Ok(Handled::Failed(failure));
+            }
+            if let Some(username) = connection.leave().await {
+                debug!("completed User {} leave handling", username);
+            }
+        }
+        ChatCommand::Request(_) => unreachable!("requests are unwrapped before handling"),
+        ChatCommand::Ping(ping) => {
+            outbound.send(ChatResponse::Pong(ping)).await?;
+        }
+        ChatCommand::Pong(pong) => {
+            if let Some(round_trip) = connection.pong(pong) {
+                debug!("Round trip to {} took {:?}", addr, round_trip);
+            }
+        }
+    }
+    Ok(Handled::Done)
+}
+
+/// Joins the default room after signing in, offering to hold the session for the client to resume.
+async fn enter_chat(
+    username: &str,
+    outbound: &Outbound,
+    registry: &RoomRegistry,
+    connection: &mut ConnectionState,
+) -> Result<Handled, RoomError> {
+    let handled = join_room(
+        DEFAULT_ROOM,
+        username,
+        outbound.clone(),
+        registry,
+        connection,
+    )
+    .await?;
+    if let Handled::Done = handled {
+        offer_resume(outbound, registry, connection).await?;
+    }
+    Ok(handled)
+}
+
+/// Hands a client that can resume a new ticket for its session, if the server holds sessions.
+async fn offer_resume(
+    outbound: &Outbound,
+    registry: &RoomRegistry,
+    connection: &mut ConnectionState,
+) -> Result<(), RoomError> {
+    let Some(grace) = registry.resume_grace() else {
+        return Ok(());
+    };
+    if !connection.supports(Capability::Resume) {
+        return Ok(());
+    }
+    let ticket = connection.issue_ticket().await;
+    let resumable = ResumeTicket {
+        ticket,
+        grace_ms: grace.as_millis().try_into().unwrap_or(u64::MAX),
+    };
+    outbound.send(ChatResponse::Resumable(resumable)).await
+}
+
+/// Adds the joined user of the connection to the room, tells the room about it
+/// and welcomes the user with the room's recent history.
+async fn join_room(
+    room: &str,
+    username: &str,
+    outbound: Outbound,
+    registry: &RoomRegistry,
+    connection: &mut ConnectionState,
+) -> Result<Handled, RoomError> {
+    // live broadcasts wait in the room's channel for the welcome and history to be queued first
+    let (ready, wait_for_ready) = oneshot::channel::<()>();
+    let send_outbound = outbound.clone();
+    let send_username = username.to_string();
+    let session = connection.session_id();
+    let echo = connection.supports(Capability::Echo);
+    let lag_backfill = registry.lag_backfill();
+    let joined = registry
+        .join(room, username, session, |room_state, rx| {
+            // weak so the room can still go away once empty
+            let backfill = lag_backfill.then(|| Arc::downgrade(room_state));
+            tokio::spawn(async move {
+                if wait_for_ready.await.is_err() {
+                    return Ok(());
+                }
+                send_from_broadcast_channel(
+                    send_outbound,
+                    rx,
+                    send_username,
+                    session,
+                    echo,
+                    backfill,
+                )
+                .await
+            })
+        })
+        .await;
+    let Some((room_state, history, arrived)) = joined else {
+        return Ok(Handled::Failed(Failure::rejected(
+            ErrorCode::AlreadyJoined,
+            room,
+            username.to_string(),
+            format!("Already in room {}", room),
+        )));
+    };
+    connection.join_room(room);
+    // the room already knows the user from other sessions
+    if arrived {
+        let broadcast = send_to_broadcast_channel(
+            ChatResponse::Broadcast(ChatMemo {
+                room: room.to_string(),
+                username: username.to_string(),
+                content: "Joined".to_string(),
+                stamp: None,
+            }),
+            room_state,
+        )
+        .await;
+        if let Err(e) = broadcast {
+            // the user is in the room all the same, only the others were not told
+            debug!(
+                "Failed to broadcast that {} joined {}: {}",
+                username, room, e
+            );
+        }
+    }
+    let joined = ChatResponse::Joined(ChatMemo {
+        room: room.to_string(),
+        username: username.to_string(),
+        content: "Warm Welcome".to_string(),
+        stamp: None,
+    });
+    let mut welcome = vec![joined];
+    if !history.memos.is_empty() && connection.supports(Capability::History) {
+        welcome.push(ChatResponse::History(history));
+    }
+    outbound.send_all(welcome).await?;
+    let _ = ready.send(());
+    Ok(Handled::Done)
+}
+
+/// Takes the session of the user out of the room, the room goes away once empty.
+async fn part_room(room: &str, username: &str, session: SessionId, registry: &RoomRegistry) {
+    if let Some(room_state) = registry.room(room).await {
+        leave_room(username.to_string(), session, room_state).await;
+    }
+    registry.remove_if_empty(room).await;
+}
+
+fn not_in_room(room: &str, username: String) -> Failure {
+    Failure::rejected(
+        ErrorCode::NotInRoom,
+        room,
+        username,
+        format!("Not in room {}", room),
+    )
+}
+
+/// Why the connection cannot join the chat as the username, if it cannot: it already joined,
+/// or its client certificate is for another user, or it has none for a username certificates stand for.
+fn check_sign_in(
+    connection: &ConnectionState,
+    registry: &RoomRegistry,
+    username: &str,
+) -> Option<Failure> {
+    if let Some(joined) = connection.username() {
+        return Some(Failure::rejected(
+            ErrorCode::AlreadyJoined,
+            DEFAULT_ROOM,
+            username.to_string(),
+            format!("Already joined as {} on this connection", joined),
+        ));
+    }
+    match connection.certified() {
+        Some(certified) if certified != username => Some(Failure::rejected(
+            ErrorCode::WrongIdentity,
+            DEFAULT_ROOM,
+            username.to_string(),
+            format!("Certificate is for {}", certified),
+        )),
+        None if registry.is_certified_username(username) => Some(Failure::rejected(
+            ErrorCode::WrongIdentity,
+            DEFAULT_ROOM,
+            username.to_string(),
+            format!("Username {} needs a certificate for it", username),
+        )),
+        _ => None,
+    }
+}
+
+fn username_taken(username: String) -> Failure {
+    Failure {
+        code: ErrorCode::UsernameTaken,
+        message: format!("Username {} is already taken", username),
+        response: Box::new(ChatResponse::Duplicate(ChatMemo {
+            room: DEFAULT_ROOM.to_string(),
+            username,
+            content: "Sorry".to_string(),
+            stamp: None,
+        })),
+    }
+}
+
+/// For a command of an optional feature the client left out of its `Hello`.
+fn not_negotiated(capability: Capability, username: String) -> Failure {
+    Failure::rejected(
+        ErrorCode::Unsupported,
+        DEFAULT_ROOM,
+        username,
+        format!("{:?} was not negotiated in the handshake", capability),
+    )
+}
+
+fn tokens_unsupported(username: String) -> Failure {
+    Failure::rejected(
+        ErrorCode::Unsupported,
+        DEFAULT_ROOM,
+        username,
+        "Session tokens are not enabled on this server".to_string(),
+    )
+}
+
+fn accounts_unsupported(username: String) -> Failure {
+    Failure::rejected(
+        ErrorCode::Unsupported,
+        DEFAULT_ROOM,
+        username,
+        "Accounts are not enabled on this server".to_string(),
+    )
+}
+
+/// Runs slow password hashing and file writes on the blocking pool, off the workers serving connections.
+async fn blocking<T, F>(work: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// The username joined on this connection is the only identity commands can act as,
+/// so a claimed username that does not match it is rejected.
+fn verify_identity(
+    connection: &ConnectionState,
+    claimed: Option<String>,
+    room: &str,
+) -> Result<String, Failure> {
+    match (connection.username(), claimed) {
+        (None, claimed) => Err(Failure::rejected(
+            ErrorCode::NotJoined,
+            room,
+            claimed.unwrap_or_default(),
+            "Join before sending commands".to_string(),
+        )),
+        (Some(joined), Some(claimed)) if joined != claimed => Err(Failure::rejected(
+            ErrorCode::WrongIdentity,
+            room,
+            claimed,
+            format!("Joined as {} on this connection", joined),
+        )),
+        (Some(joined), _) => Ok(joined.to_string()),
+    }
+}
+
+/// Removes the session of the user from the room and, if it was the user's last one in the room,
+/// lets everyone else in it know the user has left.
+pub async fn leave_room(username: String, session: SessionId, room_state: Arc<RoomState>) {
+    if !remove_username(username.clone(), session, room_state.clone()).await {
+        return;
+    }
+    debug!(
+        "User {} has left room {} so sending broadcast message",
+        username, room_state.name
+    );
+    let left = ChatResponse::Broadcast(ChatMemo {
+        room: room_state.name.clone(),
+        username: username.clone(),
+        content: "Left".to_string(),
+        stamp: None,
+    });
+    if let Err(e) = send_to_broadcast_channel(left, room_state).await {
+        debug!("No one left to tell that {} has left: {}", username, e);
+    }
+}
+
+/// Leaves every room the session of the user is in and frees the username for others,
+/// once the user has no session left.
+pub async fn leave_chat(
+    username: String,
+    session: SessionId,
+    rooms: Vec<String>,
+    registry: Arc<RoomRegistry>,
+) {
+    for room in rooms {
+        if let Some(room_state) = registry.room(&room).await {
+            leave_room(username.clone(), session, room_state).await;
+        }
+        registry.remove_if_empty(&room).await;
+    }
+    registry.release_username(&username, session).await;
+}
+
+/// Stops the send task of the session, returning whether the user has no session left in the room.
+pub async fn remove_username(
+    username: String,
+    session: SessionId,
+    room_state: Arc<RoomState>,
+) -> bool {
     let mut lookup = room_state.task_handles.lock().await;
-    if let Some(handle) = lookup.remove(&username) {
+    let Some(tasks) = lookup.get_mut(&username) else {
+        return false;
+    };
+    if let Some(handle) = tasks.remove(&session) {
         info!("Aborting background task for user: {}", username);
         handle.abort();
     }
-    info!("User {} removed from room", username);
+    if !tasks.is_empty() {
+        return false;
+    }
+    lookup.remove(&username);
+    info!("User {} removed from room {}", username, room_state.name);
     // list connected users
     let users: Vec<String> = lookup.keys().cloned().collect();
-    info!("Users in room after removal: {:?}", users);
+    info!(
+        "Users in room {} after removal: {:?}",
+        room_state.name, users
+    );
+    true
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashMap;
-    use tokio::sync::broadcast;
     use tokio::task::JoinHandle;
 
     #[tokio::test]
     async fn test_remove_username() {
-        let mut lookup_initial = HashMap::new();
+        let room_state = Arc::new(RoomState::new(DEFAULT_ROOM, 100, 100));
+        let mut lookup_initial = room_state.task_handles.lock().await;
         let dummy_task: JoinHandle<Result<(), RoomError>> = tokio::spawn(async { Ok(()) });
-        lookup_initial.insert("test_user".to_string(), dummy_task);
         let dummy_task2: JoinHandle<Result<(), RoomError>> = tokio::spawn(async { Ok(()) });
-        lookup_initial.insert("other_user".to_string(), dummy_task2);
+        lookup_initial.insert(
+            "test_user".to_string(),
+            HashMap::from([(1, dummy_task), (2, dummy_task2)]),
+        );
+        let dummy_task3: JoinHandle<Result<(), RoomError>> = tokio::spawn(async { Ok(()) });
+        lookup_initial.insert("other_user".to_string(), HashMap::from([(3, dummy_task3)]));
+        drop(lookup_initial);
 
-        let (tx, _) = broadcast::channel(100);
-        let room_state = Arc::new(RoomState {
-            tx,
-            task_handles: Mutex::new(lookup_initial),
-        });
+        // still in the room with another session
+        assert!(!remove_username("test_user".to_string(), 1, room_state.clone()).await);
+        assert!(room_state
+            .task_handles
+            .lock()
+            .await
+            .contains_key("test_user"));
 
         // Execute removal
-        remove_username("test_user".to_string(), room_state.clone()).await;
+        assert!(remove_username("test_user".to_string(), 2, room_state.clone()).await);
 
         // Verify user was removed
         let lookup = room_state.task_handles.lock().await;
@@ -157,4 +1208,41 @@ mod tests {
         assert!(lookup.contains_key("other_user"));
         assert_eq!(lookup.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_verify_identity() {
+        let registry = Arc::new(RoomRegistry::new(100, 100));
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let mut connection = ConnectionState::new(addr, registry);
+
+        // nothing joined yet
+        assert!(matches!(
+            verify_identity(&connection, None, DEFAULT_ROOM),
+            Err(Failure {
+                code: ErrorCode::NotJoined,
+                ..
+            })
+        ));
+
+        connection.claim("carl".to_string());
+        assert_eq!(
+            verify_identity(&connection, None, DEFAULT_ROOM).unwrap(),
+            "carl"
+        );
+        assert_eq!(
+            verify_identity(&connection, Some("carl".to_string()), DEFAULT_ROOM).unwrap(),
+            "carl"
+        );
+        let Err(failure) = verify_identity(&connection, Some("david".to_string()), "rust") else {
+            panic!("expected rejection when claiming another username");
+        };
+        assert_eq!(failure.code, ErrorCode::WrongIdentity);
+        let ChatResponse::Rejected(memo) = failure.into_response(None) else {
+            panic!("expected rejected without a request id");
+        };
+        assert_eq!(memo.room, "rust");
+        assert_eq!(memo.username, "david");
+        assert!(memo.content.contains("carl"));
+        connection.leave().await;
+    }
 }
@@ -0,0 +1,459 @@
+use crate::listen::command::leave_chat;
+use crate::listen::limit::{Action, Limiter, Verdict};
+use crate::listen::outbound::Outbound;
+use crate::listen::registry::{RoomRegistry, SessionId};
+use crate::listen::token::{resume_ticket, Claims};
+use chatty_types::command::BearerToken;
+use chatty_types::protocol::{Capability, Heartbeat};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// How often quiet connections are checked on, and how long they may stay quiet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// Connections with the heartbeat capability quiet for this long are pinged.
+    pub interval: Duration,
+    /// Connections quiet for this long are disconnected, never when None.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+/// Session of a dropped connection, held for the client to resume on another one.
+pub struct ParkedSession {
+    pub id: SessionId,
+    pub username: String,
+    pub logged_in: bool,
+    pub roles: Vec<String>,
+    pub session: Option<Claims>,
+    pub rooms: HashSet<String>,
+    /// Queue of the dropped connection, holding what was sent to the user since,
+    /// which the room send tasks and direct messages still go to.
+    pub outbound: Outbound,
+}
+
+/// The username joined through a single client connection, which is the identity
+/// used for every later command on it, the rooms it is in, the negotiated capabilities and
+/// its rate limiter. Users logged in to their account may be joined through several connections,
+/// each a session of their own.
+/// If still joined when the connection goes away (EOF, IO or parse error,
+/// or the connection task being aborted) the user leaves every room with a "Left" broadcast,
+/// unless the session is parked for the client to resume it.
+pub struct ConnectionState {
+    addr: SocketAddr,
+    certified: Option<String>,
+    session_id: SessionId,
+    username: Option<String>,
+    logged_in: bool,
+    roles: Vec<String>,
+    session: Option<Claims>,
+    rooms: HashSet<String>,
+    ticket: Option<BearerToken>,
+    takeover: Arc<Notify>,
+    greeted: bool,
+    capabilities: Vec<Capability>,
+    last_heard: Instant,
+    ping_sent: Option<(Heartbeat, Instant)>,
+    limiter: Limiter,
+    malformed: u32,
+    registry: Arc<RoomRegistry>,
+}
+
+impl ConnectionState {
+    pub fn new(addr: SocketAddr, registry: Arc<RoomRegistry>) -> Self {
+        Self {
+            addr,
+            certified: None,
+            session_id: registry.new_session(),
+            username: None,
+            logged_in: false,
+            roles: Vec::new(),
+            session: None,
+            rooms: HashSet::new(),
+            ticket: None,
+            takeover: Arc::new(Notify::new()),
+            greeted: false,
+            capabilities: Capability::IMPLICIT.to_vec(),
+            last_heard: Instant::now(),
+            ping_sent: None,
+            limiter: Limiter::new(registry.rate_limits()),
+            malformed: 0,
+            registry,
+        }
+    }
+
+    /// Binds the connection to the username of the client's certificate.
+    pub fn with_certified(mut self, certified: Option<String>) -> Self {
+        self.certified = certified;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Id of the session of the user joined on this connection, telling it apart from other sessions
+    /// of the same user.
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// The only username the client may join as, any free one when None.
+    pub fn certified(&self) -> Option<&str> {
+        self.certified.as_deref()
+    }
+
+    /// Records the capabilities agreed on in the handshake.
+    pub fn greet(&mut self, capabilities: Vec<Capability>) {
+        self.greeted = true;
+        self.capabilities = capabilities;
+    }
+
+    pub fn greeted(&self) -> bool {
+        self.greeted
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Anything read from the client shows it is still there.
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    pub fn quiet_for(&self) -> Duration {
+        self.last_heard.elapsed()
+    }
+
+    /// Next ping to send, remembered to time the round trip of its pong.
+    pub fn ping(&mut self) -> Heartbeat {
+        let nonce = self.ping_sent.map_or(1, |(ping, _)| ping.nonce + 1);
+        let ping = Heartbeat { nonce };
+        self.ping_sent = Some((ping, Instant::now()));
+        ping
+    }
+
+    /// Round trip of the latest ping, None if the pong is for another one.
+    pub fn pong(&mut self, pong: Heartbeat) -> Option<Duration> {
+        match self.ping_sent {
+            Some((ping, sent)) if ping == pong => Some(sent.elapsed()),
+            _ => None,
+        }
+    }
+
+    /// Counts a command that could not be decoded, returning how many there were in a row.
+    pub fn malformed(&mut self) -> u32 {
+        self.malformed += 1;
+        self.malformed
+    }
+
+    pub fn well_formed(&mut self) {
+        self.malformed = 0;
+    }
+
+    /// Checks a command against the limits of this connection and of the user joined on it,
+    /// so neither many commands on one connection nor on many connections get through.
+    pub async fn check_limit(&mut self, action: Action) -> Verdict {
+        let verdict = self.limiter.check(action, Instant::now());
+        match &self.username {
+            Some(username) => verdict.or(self.registry.check_user_limit(username, action).await),
+            None => verdict,
+        }
+    }
+
+    pub fn claim(&mut self, username: String) {
+        self.username = Some(username);
+    }
+
+    /// Claims the username of an account with its roles, after its password was checked.
+    pub fn log_in(&mut self, username: String, roles: Vec<String>) {
+        self.username = Some(username);
+        self.logged_in = true;
+        self.roles = roles;
+    }
+
+    /// Claims the username of a session token with the roles it carries.
+    pub fn log_in_with_token(&mut self, claims: Claims) {
+        self.log_in(claims.sub.clone(), claims.roles.clone());
+        self.session = Some(claims);
+    }
+
+    /// Whether the joined username is that of an account rather than a guest's.
+    pub fn logged_in(&self) -> bool {
+        self.logged_in
+    }
+
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Claims of the session token logged in with, None after logging in with the password.
+    pub fn session(&self) -> Option<&Claims> {
+        self.session.as_ref()
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn join_room(&mut self, room: &str) {
+        self.rooms.insert(room.to_string());
+    }
+
+    pub fn part_room(&mut self, room: &str) -> bool {
+        self.rooms.remove(room)
+    }
+
+    pub fn in_room(&self, room: &str) -> bool {
+        self.rooms.contains(room)
+    }
+
+    /// Hands out a new ticket to resume the joined session with, in place of any earlier one.
+    pub async fn issue_ticket(&mut self) -> BearerToken {
+        if let Some(ticket) = self.ticket.take() {
+            self.registry.forget_session(&ticket).await;
+        }
+        let ticket = resume_ticket();
+        self.registry
+            .hold_session(ticket.clone(), self.takeover.clone())
+            .await;
+        self.ticket = Some(ticket.clone());
+        ticket
+    }
+
+    /// Notified when the session is resumed on another connection before this one is noticed to be gone.
+    pub fn takeover(&self) -> Arc<Notify> {
+        self.takeover.clone()
+    }
+
+    /// Holds the joined session for the client to resume instead of leaving the chat,
+    /// false if it was never offered a ticket or the queue of the connection was closed.
+    pub async fn park(&mut self, outbound: &Outbound) -> bool {
+        if self.ticket.is_none() || !outbound.detach() {
+            return false;
+        }
+        let (Some(ticket), Some(username)) = (self.ticket.take(), self.username.take()) else {
+            return false;
+        };
+        info!(
+            "Connection {} dropped, parking session of {}",
+            self.addr, username
+        );
+        let parked = ParkedSession {
+            id: self.session_id,
+            username,
+            logged_in: std::mem::take(&mut self.logged_in),
+            roles: std::mem::take(&mut self.roles),
+            session: self.session.take(),
+            rooms: std::mem::take(&mut self.rooms),
+            outbound: outbound.clone(),
+        };
+        self.registry.park(ticket, parked).await;
+        true
+    }
+
+    /// Takes over a parked session, returning the queue that held what was sent to it.
+    pub fn resume(&mut self, parked: ParkedSession) -> Outbound {
+        self.session_id = parked.id;
+        self.username = Some(parked.username);
+        self.logged_in = parked.logged_in;
+        self.roles = parked.roles;
+        self.session = parked.session;
+        self.rooms = parked.rooms;
+        parked.outbound
+    }
+
+    pub fn rooms(&self) -> &HashSet<String> {
+        &self.rooms
+    }
+
+    /// Leaves every room and gives up the username, returning it if the connection was joined.
+    pub async fn leave(&mut self) -> Option<String> {
+        let username = self.username.take()?;
+        if let Some(ticket) = self.ticket.take() {
+            self.registry.forget_session(&ticket).await;
+        }
+        self.logged_in = false;
+        self.roles.clear();
+        self.session = None;
+        let rooms = self.rooms.drain().collect();
+        leave_chat(
+            username.clone(),
+            self.session_id,
+            rooms,
+            self.registry.clone(),
+        )
+        .await;
+        Some(username)
+    }
+
+    /// Leaves the chat if the connection is still joined.
+    pub async fn release_on_disconnect(&mut self) {
+        if let Some(username) = self.leave().await {
+            info!(
+                "Connection {} dropped without leave, cleaned up user {}",
+                self.addr, username
+            );
+        }
+    }
+}
+
+impl Drop for ConnectionState {
+    fn drop(&mut self) {
+        let Some(username) = self.username.take() else {
+            return;
+        };
+        // only reached when the connection task is aborted before release_on_disconnect could run
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            debug!("No runtime available to clean up after {}", self.addr);
+            return;
+        };
+        let rooms = self.rooms.drain().collect();
+        let ticket = self.ticket.take();
+        let session_id = self.session_id;
+        let registry = self.registry.clone();
+        let addr = self.addr;
+        runtime.spawn(async move {
+            info!("Connection {} aborted, cleaning up user {}", addr, username);
+            if let Some(ticket) = ticket {
+                registry.forget_session(&ticket).await;
+            }
+            leave_chat(username, session_id, rooms, registry).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::WriteHalf;
+    use crate::listen::outbound::{Outbound, OutboundConfig};
+    use chatty_types::codec::{Framing, ServerCodec};
+    use chatty_types::command::DEFAULT_ROOM;
+    use chatty_types::response::{ChatMemo, ChatResponse};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_test::assert_ok;
+    use tokio_util::codec::FramedWrite;
+
+    /// Connections joined to the room as the users, in the same order.
+    async fn joined_connections(
+        usernames: &[&str],
+        room: &str,
+    ) -> (Arc<RoomRegistry>, Vec<ConnectionState>) {
+        let registry = Arc::new(RoomRegistry::new(100, 100));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        );
+        let mut connections = Vec::new();
+        for username in usernames {
+            let mut connection = ConnectionState::new(peer, registry.clone());
+            let session = connection.session_id();
+            assert!(
+                registry
+                    .reserve_username(username, session, outbound.clone(), false)
+                    .await
+            );
+            registry
+                .join(room, username, session, |_room_state, _rx| {
+                    tokio::spawn(async { Ok(()) })
+                })
+                .await
+                .unwrap();
+            connection.claim(username.to_string());
+            connection.join_room(room);
+            connections.push(connection);
+        }
+        (registry, connections)
+    }
+
+    #[tokio::test]
+    async fn test_release_on_disconnect_removes_and_broadcasts_left() {
+        let (registry, mut connections) =
+            joined_connections(&["carl", "david"], DEFAULT_ROOM).await;
+        let room_state = registry.room(DEFAULT_ROOM).await.unwrap();
+        let mut rx = room_state.tx.subscribe();
+
+        let connection = &mut connections[0];
+        connection.release_on_disconnect().await;
+
+        assert_eq!(connection.username(), None);
+        assert!(!connection.in_room(DEFAULT_ROOM));
+        let lookup = room_state.task_handles.lock().await;
+        assert!(!lookup.contains_key("carl"));
+        assert!(lookup.contains_key("david"));
+        assert!(registry.user_sessions("carl").await.is_empty());
+
+        let ChatResponse::Broadcast(ChatMemo {
+            room,
+            username,
+            content,
+            ..
+        }) = assert_ok!(rx.recv().await).response().clone()
+        else {
+            panic!("expected broadcast");
+        };
+        assert_eq!(room, DEFAULT_ROOM);
+        assert_eq!(username, "carl");
+        assert_eq!(content, "Left");
+    }
+
+    #[tokio::test]
+    async fn test_drop_cleans_up_joined_rooms() {
+        let (registry, connections) = joined_connections(&["carl"], "rust").await;
+        let mut rx = registry.room("rust").await.unwrap().tx.subscribe();
+        drop(connections);
+
+        assert_ok!(rx.recv().await);
+        // carl was the only one in the room
+        let removed = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while registry.room("rust").await.is_some() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await;
+        assert_ok!(removed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pong_times_latest_ping() {
+        let registry = Arc::new(RoomRegistry::new(100, 100));
+        let mut connection = ConnectionState::new("127.0.0.1:9000".parse().unwrap(), registry);
+
+        let first = connection.ping();
+        let second = connection.ping();
+        assert_ne!(first, second);
+        tokio::time::advance(Duration::from_millis(40)).await;
+        assert_eq!(connection.pong(first), None);
+        assert_eq!(connection.pong(second), Some(Duration::from_millis(40)));
+
+        assert_eq!(connection.quiet_for(), Duration::from_millis(40));
+        connection.heard();
+        assert_eq!(connection.quiet_for(), Duration::ZERO);
+    }
+}
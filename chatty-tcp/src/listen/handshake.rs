@@ -0,0 +1,106 @@
+use chatty_types::codec::Framing;
+use chatty_types::command::Hello;
+use chatty_types::protocol::{Capability, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use chatty_types::response::{Incompatible, Welcome};
+
+pub fn server_software() -> String {
+    format!("chatty-tcp-server/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Accepts the client's protocol version if the server still speaks it,
+/// agreeing on the capabilities both sides listed and the encoding the client asked for.
+pub fn negotiate(hello: &Hello, framing: Framing) -> Result<Welcome, Incompatible> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version) {
+        return Err(Incompatible {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            reason: format!(
+                "Protocol version {} of {} is not supported, use a version from {} to {}",
+                hello.protocol_version, hello.software, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        });
+    }
+    if hello.encoding.is_binary() && framing == Framing::Lines {
+        return Err(Incompatible {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            reason: format!(
+                "Encoding {:?} needs length-prefixed framing, the server uses newline-delimited frames",
+                hello.encoding
+            ),
+        });
+    }
+    let capabilities = Capability::ALL
+        .into_iter()
+        .filter(|capability| hello.capabilities.contains(capability))
+        .collect();
+    Ok(Welcome {
+        protocol_version: hello.protocol_version,
+        software: server_software(),
+        capabilities,
+        encoding: hello.encoding,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatty_types::codec::Encoding;
+
+    fn hello(protocol_version: u32, capabilities: Vec<Capability>) -> Hello {
+        Hello {
+            protocol_version,
+            software: "test-client/1.0".to_string(),
+            capabilities,
+            encoding: Encoding::Json,
+        }
+    }
+
+    #[test]
+    fn test_negotiate_common_capabilities() {
+        let welcome = negotiate(
+            &hello(
+                PROTOCOL_VERSION,
+                vec![Capability::Unknown, Capability::History],
+            ),
+            Framing::Lines,
+        )
+        .unwrap();
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities, vec![Capability::History]);
+        assert!(welcome.software.starts_with("chatty-tcp-server/"));
+    }
+
+    #[test]
+    fn test_negotiate_refuses_unsupported_versions() {
+        let incompatible =
+            negotiate(&hello(PROTOCOL_VERSION + 1, vec![]), Framing::Lines).unwrap_err();
+        assert_eq!(incompatible.max_version, PROTOCOL_VERSION);
+        assert!(incompatible.reason.contains("test-client/1.0"));
+        assert!(negotiate(&hello(0, vec![]), Framing::Lines).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_binary_encoding_needs_length_prefix() {
+        let hello = Hello {
+            encoding: Encoding::MessagePack,
+            ..hello(PROTOCOL_VERSION, vec![])
+        };
+        let incompatible = negotiate(&hello, Framing::Lines).unwrap_err();
+        assert!(incompatible.reason.contains("MessagePack"));
+        let welcome = negotiate(&hello, Framing::LengthPrefixed).unwrap();
+        assert_eq!(welcome.encoding, Encoding::MessagePack);
+    }
+
+    #[test]
+    fn test_unknown_capabilities_deserialize() {
+        let hello: Hello = serde_json::from_str(
+            r#"{"protocol_version":1,"software":"newer","capabilities":["History","Compression"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            hello.capabilities,
+            vec![Capability::History, Capability::Unknown]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use chatty_types::command::ChatCommand;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Sustained rate and burst of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+/// Limits on what a connection, and a user across connections, may do,
+/// and how repeatedly going over them is dealt with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+    /// Broadcasts and direct messages, unlimited when None.
+    pub messages: Option<Rate>,
+    /// Joins of the chat and of rooms, unlimited when None.
+    pub joins: Option<Rate>,
+    /// Commands refused in a row before the offender is muted.
+    pub mute_after: u32,
+    pub mute_for: Duration,
+    /// Mutes before the offender is disconnected instead, never when zero.
+    /// Forgotten once nothing was refused for as long as a mute lasts after the last one ended.
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages: Some(Rate {
+                per_sec: 10.0,
+                burst: 20,
+            }),
+            joins: Some(Rate {
+                per_sec: 1.0,
+                burst: 5,
+            }),
+            mute_after: 10,
+            mute_for: Duration::from_secs(30),
+            disconnect_after: 3,
+        }
+    }
+}
+
+impl RateLimits {
+    /// No limits at all, for trusted clients such as tests and benchmarks.
+    pub fn unlimited() -> Self {
+        Self {
+            messages: None,
+            joins: None,
+            ..Self::default()
+        }
+    }
+}
+
+/// Commands the rate limits apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Message,
+    Join,
+}
+
+impl Action {
+    pub fn of(command: &ChatCommand) -> Option<Self> {
+        match command {
+            ChatCommand::Send(_) | ChatCommand::Direct(_) => Some(Self::Message),
+            // password and token guesses count as joins too
+            ChatCommand::Join(_)
+            | ChatCommand::JoinRoom(_)
+            | ChatCommand::Register(_)
+            | ChatCommand::Login(_)
+            | ChatCommand::ChangePassword(_)
+            | ChatCommand::TokenLogin(_)
+            | ChatCommand::Resume(_) => Some(Self::Join),
+            _ => None,
+        }
+    }
+}
+
+/// What the limiter makes of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Throttled(Duration),
+    Muted(Duration),
+    Disconnect,
+}
+
+impl Verdict {
+    /// The stricter of two verdicts, for a command checked against several limiters.
+    pub fn or(self, other: Verdict) -> Verdict {
+        match (self, other) {
+            (Verdict::Disconnect, _) | (_, Verdict::Disconnect) => Verdict::Disconnect,
+            (Verdict::Muted(a), Verdict::Muted(b)) => Verdict::Muted(a.max(b)),
+            (Verdict::Muted(a), _) | (_, Verdict::Muted(a)) => Verdict::Muted(a),
+            (Verdict::Throttled(a), Verdict::Throttled(b)) => Verdict::Throttled(a.max(b)),
+            (Verdict::Throttled(a), _) | (_, Verdict::Throttled(a)) => Verdict::Throttled(a),
+            (Verdict::Allowed, Verdict::Allowed) => Verdict::Allowed,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    /// Takes a token, or tells how long until there is one.
+    fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate.per_sec))
+    }
+
+    fn is_full(&mut self, rate: Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= f64::from(rate.burst)
+    }
+}
+
+/// Token buckets of one connection or one user, and how often they ran dry.
+#[derive(Debug)]
+pub struct Limiter {
+    limits: RateLimits,
+    messages: Option<Bucket>,
+    joins: Option<Bucket>,
+    strikes: u32,
+    mutes: u32,
+    muted_until: Option<Instant>,
+    /// When the mutes so far are forgotten, unless refused again before.
+    mutes_forgiven_at: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            messages: limits.messages.map(|rate| Bucket::full(rate, now)),
+            joins: limits.joins.map(|rate| Bucket::full(rate, now)),
+            strikes: 0,
+            mutes: 0,
+            muted_until: None,
+            mutes_forgiven_at: None,
+        }
+    }
+
+    pub fn check(&mut self, action: Action, now: Instant) -> Verdict {
+        self.forgive(now);
+        if let Some(until) = self.muted_until {
+            if until > now {
+                return Verdict::Muted(until - now);
+            }
+            self.muted_until = None;
+        }
+        let (bucket, rate) = match action {
+            Action::Message => (&mut self.messages, self.limits.messages),
+            Action::Join => (&mut self.joins, self.limits.joins),
+        };
+        let (Some(bucket), Some(rate)) = (bucket, rate) else {
+            return Verdict::Allowed;
+        };
+        let retry_after = match bucket.take(rate, now) {
+            Ok(()) => {
+                self.strikes = 0;
+                return Verdict::Allowed;
+            }
+            Err(retry_after) => retry_after,
+        };
+        self.strikes += 1;
+        if self.mutes > 0 {
+            let forgiven_at = now + self.limits.mute_for;
+            self.mutes_forgiven_at = self.mutes_forgiven_at.max(Some(forgiven_at));
+        }
+        if self.limits.mute_after == 0 || self.strikes < self.limits.mute_after {
+            return Verdict::Throttled(retry_after);
+        }
+        self.strikes = 0;
+        self.mutes += 1;
+        if self.limits.disconnect_after > 0 && self.mutes >= self.limits.disconnect_after {
+            return Verdict::Disconnect;
+        }
+        self.muted_until = Some(now + self.limits.mute_for);
+        self.mutes_forgiven_at = Some(now + 2 * self.limits.mute_for);
+        Verdict::Muted(self.limits.mute_for)
+    }
+
+    fn forgive(&mut self, now: Instant) {
+        if self.mutes_forgiven_at.is_some_and(|at| at <= now) {
+            self.mutes = 0;
+            self.mutes_forgiven_at = None;
+        }
+    }
+
+    /// Nothing to remember: buckets are full again, no mute is running and earlier ones are forgiven.
+    pub fn is_at_rest(&mut self, now: Instant) -> bool {
+        self.forgive(now);
+        let limits = self.limits;
+        let full = |bucket: &mut Option<Bucket>, rate: Option<Rate>| match (bucket, rate) {
+            (Some(bucket), Some(rate)) => bucket.is_full(rate, now),
+            _ => true,
+        };
+        full(&mut self.messages, limits.messages)
+            && full(&mut self.joins, limits.joins)
+            && self.muted_until.is_none_or(|until| until <= now)
+            && self.mutes == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            messages: Some(Rate {
+                per_sec: 2.0,
+                burst: 3,
+            }),
+            joins: None,
+            mute_after: 3,
+            mute_for: Duration::from_secs(10),
+            disconnect_after: 2,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket_allows_burst_then_refills() {
+        let mut limiter = Limiter::new(limits());
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check(Action::Message, now), Verdict::Allowed);
+        }
+        assert_eq!(
+            limiter.check(Action::Message, now),
+            Verdict::Throttled(Duration::from_millis(500))
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check(Action::Message, later), Verdict::Allowed);
+        assert!(!limiter.is_at_rest(later));
+        assert!(limiter.is_at_rest(later + Duration::from_secs(2)));
+        // unlimited
+        assert_eq!(limiter.check(Action::Join, now), Verdict::Allowed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeat_offender_is_muted_then_disconnected() {
+        let mut limiter = Limiter::new(limits());
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.check(Action::Message, now);
+        }
+        limiter.check(Action::Message, now);
+        limiter.check(Action::Message, now);
+        assert_eq!(
+            limiter.check(Action::Message, now),
+            Verdict::Muted(Duration::from_secs(10))
+        );
+        let muted = now + Duration::from_secs(4);
+        assert_eq!(
+            limiter.check(Action::Message, muted),
+            Verdict::Muted(Duration::from_secs(6))
+        );
+        assert!(!limiter.is_at_rest(muted));
+
+        let unmuted = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(Action::Message, unmuted), Verdict::Allowed);
+        }
+        limiter.check(Action::Message, unmuted);
+        limiter.check(Action::Message, unmuted);
+        assert_eq!(limiter.check(Action::Message, unmuted), Verdict::Disconnect);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mutes_are_forgiven_after_a_clean_period() {
+        let mut limiter = Limiter::new(limits());
+        let now = Instant::now();
+        for _ in 0..5 {
+            limiter.check(Action::Message, now);
+        }
+        assert_eq!(
+            limiter.check(Action::Message, now),
+            Verdict::Muted(Duration::from_secs(10))
+        );
+        // still remembered for a while after the mute ended
+        let unmuted = now + Duration::from_secs(15);
+        assert!(!limiter.is_at_rest(unmuted));
+
+        let clean = now + Duration::from_secs(20);
+        assert!(limiter.is_at_rest(clean));
+        for _ in 0..5 {
+            limiter.check(Action::Message, clean);
+        }
+        // muted again as a first offender, not disconnected
+        assert_eq!(
+            limiter.check(Action::Message, clean),
+            Verdict::Muted(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_stricter_verdict_wins() {
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            Verdict::Allowed.or(Verdict::Throttled(second)),
+            Verdict::Throttled(second)
+        );
+        assert_eq!(
+            Verdict::Muted(second).or(Verdict::Throttled(2 * second)),
+            Verdict::Muted(second)
+        );
+        assert_eq!(
+            Verdict::Muted(second).or(Verdict::Disconnect),
+            Verdict::Disconnect
+        );
+    }
+}
@@ -0,0 +1,646 @@
+use crate::listen::command::RoomError;
+use crate::listen::response::{ResponseWriter, SharedResponse};
+use chatty_types::codec::{CodecError, Encoding, Frame};
+use chatty_types::response::ChatResponse;
+use futures::SinkExt;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, warn};
+
+/// Responses queued for a connection before its policy for slow consumers applies.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// What happens to a response for a connection whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    /// The oldest queued broadcast makes room, which the client sees as a gap in the room's sequence.
+    /// The client is disconnected once there are only other responses left to make room.
+    DropOldest,
+    /// The client is disconnected.
+    Disconnect,
+    /// Whoever sends waits for room, so broadcasts pile up in the room's channel until the member lags.
+    #[default]
+    Backpressure,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            "backpressure" => Ok(Self::Backpressure),
+            other => Err(format!("Unknown slow consumer policy {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundConfig {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+/// Depth of a connection's queue, to tell which users are slow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Responses waiting for the writer task right now.
+    pub depth: usize,
+    /// Deepest the queue has been.
+    pub high_water: usize,
+    /// Broadcasts dropped to make room.
+    pub dropped: u64,
+}
+
+enum Outgoing {
+    Response(ChatResponse),
+    /// Broadcast of a room, encoded once for all its members.
+    Shared(SharedResponse),
+    /// Switches the encoding of every response after it.
+    SetEncoding(Encoding),
+}
+
+struct Queue {
+    items: VecDeque<Outgoing>,
+    closed: bool,
+    /// No writer task takes responses off the queue as the connection is gone,
+    /// what is sent is held for the session to be resumed.
+    detached: bool,
+    /// Queue of the connection that resumed the session, which everything goes to from then on.
+    redirected: Option<Arc<Shared>>,
+    stats: QueueStats,
+}
+
+struct Shared {
+    config: OutboundConfig,
+    /// Peer address for logging.
+    peer: String,
+    queue: Mutex<Queue>,
+    readable: Notify,
+    writable: Notify,
+    closed: Notify,
+    writer_task: OnceLock<AbortHandle>,
+}
+
+/// Queue of responses for one connection, written to the client by the connection's own writer task.
+/// Cloned for everyone sending to the connection, the command loop, room send tasks and direct messages.
+/// Once redirected to the queue of a connection resuming the session, every clone sends there.
+#[derive(Clone)]
+pub struct Outbound {
+    target: Arc<Mutex<Arc<Shared>>>,
+}
+
+impl Outbound {
+    fn new(config: OutboundConfig, peer: String) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            peer,
+            queue: Mutex::new(Queue {
+                items: VecDeque::with_capacity(config.capacity),
+                closed: false,
+                detached: false,
+                redirected: None,
+                stats: QueueStats::default(),
+            }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: Notify::new(),
+            writer_task: OnceLock::new(),
+        });
+        Self {
+            target: Arc::new(Mutex::new(shared)),
+        }
+    }
+
+    /// Starts the writer task of the connection, which runs until the queue is closed.
+    pub fn spawn(writer: ResponseWriter, peer: SocketAddr, config: OutboundConfig) -> Self {
+        let outbound = Self::new(config, peer.to_string());
+        let shared = outbound.shared();
+        let writer_task = tokio::spawn(write_queued(shared.clone(), writer));
+        let _ = shared.writer_task.set(writer_task.abort_handle());
+        outbound
+    }
+
+    /// Queue responses go to, following redirects to where the session lives on now.
+    fn shared(&self) -> Arc<Shared> {
+        let mut target = self.target.lock().expect("outbound target lock poisoned");
+        loop {
+            let redirected = target.lock().redirected.clone();
+            match redirected {
+                Some(next) => *target = next,
+                None => return target.clone(),
+            }
+        }
+    }
+
+    pub async fn send(&self, response: ChatResponse) -> Result<(), RoomError> {
+        self.push(vec![Outgoing::Response(response)]).await
+    }
+
+    pub async fn send_shared(&self, response: SharedResponse) -> Result<(), RoomError> {
+        self.push(vec![Outgoing::Shared(response)]).await
+    }
+
+    /// Queues the responses right after each other, with nothing sent by others in between.
+    pub async fn send_all(
+        &self,
+        responses: impl IntoIterator<Item = ChatResponse>,
+    ) -> Result<(), RoomError> {
+        self.push(responses.into_iter().map(Outgoing::Response).collect())
+            .await
+    }
+
+    /// Queues the response, after which everything is written in the encoding.
+    pub async fn send_then_encode(
+        &self,
+        response: ChatResponse,
+        encoding: Encoding,
+    ) -> Result<(), RoomError> {
+        self.push(vec![
+            Outgoing::Response(response),
+            Outgoing::SetEncoding(encoding),
+        ])
+        .await
+    }
+
+    async fn push(&self, items: Vec<Outgoing>) -> Result<(), RoomError> {
+        let mut waited = false;
+        loop {
+            let shared = self.shared();
+            let OutboundConfig { capacity, policy } = shared.config;
+            // created before looking at the queue so a wakeup in between is not missed
+            let writable = shared.writable.notified();
+            {
+                let mut queue = shared.lock();
+                if queue.redirected.is_some() {
+                    continue;
+                }
+                if queue.closed {
+                    return Err(RoomError::ConnectionClosed);
+                }
+                let full = queue.items.len() >= capacity;
+                // nothing frees room in a detached queue, the oldest broadcasts held give way
+                if !full || policy == SlowConsumerPolicy::DropOldest || queue.detached {
+                    for item in items {
+                        // with no broadcast left to give way the queue stays bounded all the same
+                        if queue.items.len() >= capacity && !queue.drop_oldest_broadcast() {
+                            return Err(self.disconnect(&shared, queue));
+                        }
+                        queue.items.push_back(item);
+                    }
+                    let depth = queue.items.len();
+                    queue.stats.depth = depth;
+                    if depth > queue.stats.high_water {
+                        queue.stats.high_water = depth;
+                        if depth == capacity {
+                            warn!("Outbound queue of {} is full", shared.peer);
+                        }
+                    }
+                    drop(queue);
+                    shared.readable.notify_one();
+                    return Ok(());
+                }
+                if policy == SlowConsumerPolicy::Disconnect {
+                    return Err(self.disconnect(&shared, queue));
+                }
+            }
+            if !waited {
+                debug!("Waiting for room in outbound queue of {}", shared.peer);
+                waited = true;
+            }
+            writable.await;
+        }
+    }
+
+    /// Gives up on the consumer for being too slow, dropping everything queued for it.
+    fn disconnect(
+        &self,
+        shared: &Shared,
+        mut queue: std::sync::MutexGuard<'_, Queue>,
+    ) -> RoomError {
+        let queued = queue.items.len();
+        warn!(
+            "Disconnecting {} with {} responses queued",
+            shared.peer, queued
+        );
+        queue.items.clear();
+        drop(queue);
+        // the writer may be stuck on the socket of the client that does not read
+        if let Some(writer_task) = shared.writer_task.get() {
+            writer_task.abort();
+        }
+        self.close();
+        RoomError::SlowConsumer(queued)
+    }
+
+    /// Stops taking responses, the writer task writes what is queued and ends the connection.
+    pub fn close(&self) {
+        let shared = self.shared();
+        shared.lock().closed = true;
+        shared.readable.notify_one();
+        shared.writable.notify_waiters();
+        shared.closed.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared().lock().closed
+    }
+
+    /// Resolves once the queue is closed, by the connection or for being too slow,
+    /// or the connection is gone.
+    pub async fn closed(&self) {
+        loop {
+            let shared = self.shared();
+            let closed = shared.closed.notified();
+            {
+                let queue = shared.lock();
+                if queue.redirected.is_some() {
+                    continue;
+                }
+                if queue.closed || queue.detached {
+                    return;
+                }
+            }
+            closed.await;
+        }
+    }
+
+    /// Stops writing to the connection, which is gone, while still taking responses
+    /// to hold for the session until the queue is closed or redirected. False if already closed.
+    pub fn detach(&self) -> bool {
+        let shared = self.shared();
+        {
+            let mut queue = shared.lock();
+            if queue.closed {
+                return false;
+            }
+            queue.detached = true;
+        }
+        if let Some(writer_task) = shared.writer_task.get() {
+            writer_task.abort();
+        }
+        shared.writable.notify_waiters();
+        shared.closed.notify_waiters();
+        true
+    }
+
+    /// Moves the responses held in this queue to the end of the other one, led by the response
+    /// made from their number, and has everything sent to any clone of this queue go there from now on.
+    pub fn redirect(&self, to: &Outbound, lead: impl FnOnce(usize) -> ChatResponse) {
+        let from = self.shared();
+        let target = to.shared();
+        assert!(
+            !Arc::ptr_eq(&from, &target),
+            "outbound queue redirected to itself"
+        );
+        let mut queue = from.lock();
+        // the encoding is that of the connection written to
+        let held: Vec<Outgoing> = std::mem::take(&mut queue.items)
+            .into_iter()
+            .filter(|item| !matches!(item, Outgoing::SetEncoding(_)))
+            .collect();
+        {
+            let mut target_queue = target.lock();
+            target_queue
+                .items
+                .push_back(Outgoing::Response(lead(held.len())));
+            target_queue.items.extend(held);
+            target_queue.stats.depth = target_queue.items.len();
+        }
+        queue.stats.depth = 0;
+        queue.redirected = Some(target.clone());
+        drop(queue);
+        target.readable.notify_one();
+        from.writable.notify_waiters();
+        from.closed.notify_waiters();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared().lock().stats
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().expect("outbound queue lock poisoned")
+    }
+}
+
+impl Queue {
+    /// Whether there was a broadcast to drop.
+    fn drop_oldest_broadcast(&mut self) -> bool {
+        let oldest = self.items.iter().position(|item| match item {
+            Outgoing::Response(response) => matches!(response, ChatResponse::Broadcast(_)),
+            Outgoing::Shared(shared) => matches!(shared.response(), ChatResponse::Broadcast(_)),
+            Outgoing::SetEncoding(_) => false,
+        });
+        let Some(oldest) = oldest else {
+            return false;
+        };
+        self.items.remove(oldest);
+        self.stats.dropped += 1;
+        true
+    }
+}
+
+/// Writes everything queued in one go and flushes, until the queue is closed and empty.
+async fn write_queued(shared: Arc<Shared>, mut writer: ResponseWriter) {
+    loop {
+        let readable = shared.readable.notified();
+        let (batch, closed) = {
+            let mut queue = shared.lock();
+            queue.stats.depth = 0;
+            (std::mem::take(&mut queue.items), queue.closed)
+        };
+        if batch.is_empty() {
+            if closed {
+                break;
+            }
+            readable.await;
+            continue;
+        }
+        shared.writable.notify_waiters();
+        if let Err(e) = write_batch(&mut writer, batch, &shared.peer).await {
+            info!("Failed to write to {}: {}", shared.peer, e);
+            // held for resuming the session until the connection's end closes the queue
+            shared.lock().detached = true;
+            shared.writable.notify_waiters();
+            shared.closed.notify_waiters();
+            return;
+        }
+    }
+    // shuts down the write half so the client sees the connection end
+    let _ = SinkExt::<Frame>::close(&mut writer).await;
+}
+
+async fn write_batch(
+    writer: &mut ResponseWriter,
+    batch: VecDeque<Outgoing>,
+    peer: &str,
+) -> Result<(), RoomError> {
+    for item in batch {
+        let written = match item {
+            Outgoing::Response(response) => writer.feed(&response).await,
+            Outgoing::Shared(shared) => match shared.frame(writer.encoder_mut()) {
+                Ok(frame) => writer.feed(frame).await,
+                Err(e) => Err(e),
+            },
+            Outgoing::SetEncoding(encoding) => {
+                writer.encoder_mut().set_encoding(encoding);
+                Ok(())
+            }
+        };
+        match written {
+            Ok(()) => {}
+            Err(CodecError::Io(e)) => return Err(CodecError::Io(e).into()),
+            // nothing was written for it, so the responses after it still reach the client
+            Err(e) => warn!(
+                "Skipped a response to {} that cannot be encoded: {}",
+                peer, e
+            ),
+        }
+    }
+    // the same for every item type the codec writes
+    SinkExt::<Frame>::flush(writer).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::WriteHalf;
+    use chatty_types::codec::{ClientCodec, Framing, ServerCodec};
+    use chatty_types::command::DEFAULT_ROOM;
+    use chatty_types::response::{Ack, ChatMemo, DirectMemo};
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_test::{assert_err, assert_ok};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn broadcast(content: &str) -> ChatResponse {
+        ChatResponse::Broadcast(ChatMemo {
+            room: DEFAULT_ROOM.to_string(),
+            username: "carl".to_string(),
+            content: content.to_string(),
+            stamp: None,
+        })
+    }
+
+    /// Queue without a writer task, so nothing is taken off it.
+    fn stalled(capacity: usize, policy: SlowConsumerPolicy) -> Outbound {
+        Outbound::new(OutboundConfig { capacity, policy }, "test".to_string())
+    }
+
+    fn queued(outbound: &Outbound) -> Vec<ChatResponse> {
+        outbound
+            .shared()
+            .lock()
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Outgoing::Response(response) => Some(response.clone()),
+                Outgoing::Shared(shared) => Some(shared.response().clone()),
+                Outgoing::SetEncoding(_) => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_other_responses() {
+        let outbound = stalled(2, SlowConsumerPolicy::DropOldest);
+        assert_ok!(outbound.send(ChatResponse::Ack(Ack { id: 1 })).await);
+        assert_ok!(outbound.send(broadcast("first")).await);
+        assert_ok!(outbound.send(broadcast("second")).await);
+        assert_ok!(outbound.send(broadcast("third")).await);
+
+        assert_eq!(
+            queued(&outbound),
+            vec![ChatResponse::Ack(Ack { id: 1 }), broadcast("third"),]
+        );
+        let stats = outbound.stats();
+        assert_eq!((stats.depth, stats.high_water, stats.dropped), (2, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_queue_without_broadcasts_to_drop_stays_bounded() {
+        let direct = |i: usize| {
+            ChatResponse::Direct(DirectMemo {
+                from: "carl".to_string(),
+                to: "david".to_string(),
+                content: format!("psst {}", i),
+            })
+        };
+        let outbound = stalled(4, SlowConsumerPolicy::DropOldest);
+        let mut flooded = Ok(());
+        for i in 0..10 {
+            flooded = outbound.send(direct(i)).await;
+            assert!(outbound.stats().depth <= 4);
+            if flooded.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(flooded, Err(RoomError::SlowConsumer(4))));
+        assert!(outbound.is_closed());
+
+        // nothing is written from a detached queue either, whatever the policy
+        let parked = stalled(4, SlowConsumerPolicy::Backpressure);
+        assert!(parked.detach());
+        for i in 0..4 {
+            assert_ok!(parked.send(direct(i)).await);
+        }
+        assert!(matches!(
+            parked.send(direct(4)).await,
+            Err(RoomError::SlowConsumer(4))
+        ));
+        assert!(parked.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_closes_full_queue() {
+        let outbound = stalled(1, SlowConsumerPolicy::Disconnect);
+        assert_ok!(outbound.send(broadcast("first")).await);
+        assert!(matches!(
+            outbound.send(broadcast("second")).await,
+            Err(RoomError::SlowConsumer(1))
+        ));
+        tokio::time::timeout(Duration::from_millis(100), outbound.closed())
+            .await
+            .unwrap();
+        assert!(matches!(
+            outbound.send(broadcast("third")).await,
+            Err(RoomError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_waits_for_room() {
+        let outbound = stalled(1, SlowConsumerPolicy::Backpressure);
+        assert_ok!(outbound.send(broadcast("first")).await);
+        let waiting = outbound.clone();
+        let mut second = tokio::spawn(async move { waiting.send(broadcast("second")).await });
+        assert_err!(tokio::time::timeout(Duration::from_millis(50), &mut second).await);
+
+        // what a writer task does on taking the queued responses
+        let shared = outbound.shared();
+        shared.lock().items.clear();
+        shared.writable.notify_waiters();
+        assert_ok!(assert_ok!(second.await));
+        assert_eq!(queued(&outbound), vec![broadcast("second")]);
+    }
+
+    #[tokio::test]
+    async fn test_detached_queue_holds_until_redirected() {
+        let parked = stalled(2, SlowConsumerPolicy::Backpressure);
+        let room_send_task = parked.clone();
+        assert_ok!(parked.send(broadcast("first")).await);
+        assert!(parked.detach());
+        tokio::time::timeout(Duration::from_millis(100), parked.closed())
+            .await
+            .unwrap();
+        // never waits for room no one makes
+        assert_ok!(room_send_task.send(broadcast("second")).await);
+        assert_ok!(room_send_task.send(broadcast("third")).await);
+
+        let resumed = stalled(8, SlowConsumerPolicy::Backpressure);
+        parked.redirect(&resumed, |held| ChatResponse::Ack(Ack { id: held as u64 }));
+        assert_ok!(room_send_task.send(broadcast("fourth")).await);
+        let expected = vec![
+            ChatResponse::Ack(Ack { id: 2 }),
+            broadcast("second"),
+            broadcast("third"),
+            broadcast("fourth"),
+        ];
+        assert_eq!(queued(&resumed), expected);
+        // every clone follows the redirect
+        assert_eq!(queued(&room_send_task), expected);
+        assert_err!(tokio::time::timeout(Duration::from_millis(50), room_send_task.closed()).await);
+    }
+
+    #[tokio::test]
+    async fn test_writer_task_writes_in_order_and_switches_encoding() {
+        let listener = assert_ok!(TcpListener::bind("127.0.0.1:0").await);
+        let client = assert_ok!(TcpStream::connect(listener.local_addr().unwrap()).await);
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let writer = FramedWrite::new(writer_half, ServerCodec::new(Framing::LengthPrefixed));
+        let outbound = Outbound::spawn(writer, peer, OutboundConfig::default());
+        let (stream, _) = assert_ok!(listener.accept().await);
+        let mut reader = FramedRead::new(stream, ClientCodec::new(Framing::LengthPrefixed));
+
+        assert_ok!(
+            outbound
+                .send_then_encode(broadcast("json"), Encoding::MessagePack)
+                .await
+        );
+        assert_ok!(
+            outbound
+                .send_all([broadcast("msgpack"), ChatResponse::Ack(Ack { id: 2 })])
+                .await
+        );
+        outbound.close();
+
+        assert_eq!(assert_ok!(reader.next().await.unwrap()), broadcast("json"));
+        reader.decoder_mut().set_encoding(Encoding::MessagePack);
+        assert_eq!(
+            assert_ok!(reader.next().await.unwrap()),
+            broadcast("msgpack")
+        );
+        assert_eq!(
+            assert_ok!(reader.next().await.unwrap()),
+            ChatResponse::Ack(Ack { id: 2 })
+        );
+        // closing the queue ends the connection
+        assert!(reader.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_writer_task_skips_responses_too_large_to_encode() {
+        let listener = assert_ok!(TcpListener::bind("127.0.0.1:0").await);
+        let client = assert_ok!(TcpStream::connect(listener.local_addr().unwrap()).await);
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let codec = ServerCodec::new(Framing::LengthPrefixed).with_max_frame_bytes(256);
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, codec),
+            peer,
+            OutboundConfig::default(),
+        );
+        let (stream, _) = assert_ok!(listener.accept().await);
+        let mut reader = FramedRead::new(stream, ClientCodec::new(Framing::LengthPrefixed));
+
+        assert_ok!(
+            outbound
+                .send_all([
+                    broadcast(&"x".repeat(512)),
+                    broadcast("small"),
+                    ChatResponse::Ack(Ack { id: 3 }),
+                ])
+                .await
+        );
+        outbound.close();
+
+        assert_eq!(assert_ok!(reader.next().await.unwrap()), broadcast("small"));
+        assert_eq!(
+            assert_ok!(reader.next().await.unwrap()),
+            ChatResponse::Ack(Ack { id: 3 })
+        );
+        assert!(reader.next().await.is_none());
+    }
+}
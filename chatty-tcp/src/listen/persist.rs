@@ -0,0 +1,431 @@
+use chatty_types::response::ChatMemo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info};
+
+/// Where broadcast memos are kept so room history survives a server restart.
+pub trait MessageLog: Send + Sync {
+    /// Durably records one broadcast memo, on disk by the time it returns.
+    fn append(&self, memo: &ChatMemo) -> io::Result<()>;
+
+    /// Durably records the memos in order, which a log may do with fewer writes to disk.
+    fn append_all(&self, memos: &[ChatMemo]) -> io::Result<()> {
+        memos.iter().try_for_each(|memo| self.append(memo))
+    }
+
+    /// Every memo still within retention, oldest first.
+    fn load(&self) -> io::Result<Vec<ChatMemo>>;
+}
+
+pub struct FileLogConfig {
+    pub dir: PathBuf,
+    /// Size after which the active segment is sealed and a new one started.
+    pub segment_bytes: u64,
+    /// How long memos are kept, whole segments are removed once everything in them is older.
+    pub retention: Duration,
+}
+
+/// Append-only log of segment files named by an increasing id.
+/// Each record is its length and CRC32 followed by the JSON of the record,
+/// so a torn record from a crash mid-write is detected and truncated on open.
+pub struct FileMessageLog {
+    config: FileLogConfig,
+    active: Mutex<ActiveSegment>,
+}
+
+struct ActiveSegment {
+    id: u64,
+    file: File,
+    len: u64,
+    /// When the last memo of the segment was appended, zero while it has none.
+    newest: u64,
+    /// When the last memo of each sealed segment was appended, by segment id,
+    /// so expiry needs no reading of them.
+    sealed: BTreeMap<u64, u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    /// Seconds since the unix epoch the memo was appended at.
+    at: u64,
+    memo: ChatMemo,
+}
+
+const RECORD_HEADER_BYTES: u64 = 8;
+
+impl FileMessageLog {
+    /// Opens the log in the configured directory, recovering the last segment
+    /// and removing segments past retention.
+    pub fn open(config: FileLogConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut ids = segment_ids(&config.dir)?;
+        let id = ids.pop().unwrap_or(0);
+        let mut sealed = BTreeMap::new();
+        for id in ids {
+            let (records, _) = read_records(&mut File::open(segment_path(&config.dir, id))?)?;
+            sealed.insert(id, newest_at(&records));
+        }
+        let path = segment_path(&config.dir, id);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .truncate(false)
+            .open(&path)?;
+        let (records, valid_len) = read_records(&mut file)?;
+        let len = file.metadata()?.len();
+        if valid_len < len {
+            info!(
+                "Truncating torn record at {} in {}, {} bytes dropped",
+                valid_len,
+                path.display(),
+                len - valid_len
+            );
+            file.set_len(valid_len)?;
+        }
+        let log = Self {
+            config,
+            active: Mutex::new(ActiveSegment {
+                id,
+                file,
+                len: valid_len,
+                newest: newest_at(&records),
+                sealed,
+            }),
+        };
+        log.remove_expired(&mut log.active.lock().expect("message log lock poisoned"))?;
+        Ok(log)
+    }
+
+    fn append_at(&self, at: u64, memos: &[ChatMemo]) -> io::Result<()> {
+        let mut active = self.active.lock().expect("message log lock poisoned");
+        for memo in memos {
+            let payload = serde_json::to_vec(&LogRecord {
+                at,
+                memo: memo.clone(),
+            })?;
+            let mut record = Vec::with_capacity(RECORD_HEADER_BYTES as usize + payload.len());
+            record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            record.extend_from_slice(&payload);
+
+            if active.len > 0 && active.len + record.len() as u64 > self.config.segment_bytes {
+                let id = active.id + 1;
+                debug!("Rotating message log to segment {}", id);
+                // sealed for good, so on disk before anything goes to the next one
+                active.file.sync_data()?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(segment_path(&self.config.dir, id))?;
+                let (sealed_id, newest) = (active.id, active.newest);
+                active.sealed.insert(sealed_id, newest);
+                active.id = id;
+                active.file = file;
+                active.len = 0;
+                active.newest = 0;
+                self.remove_expired(&mut active)?;
+            }
+            // one write per record so a crash can only leave the last record torn
+            active.file.write_all(&record)?;
+            active.len += record.len() as u64;
+            active.newest = at;
+        }
+        // one sync for all of them, what returns is on disk
+        active.file.sync_data()
+    }
+
+    /// Removes sealed segments, everything before the active one, whose newest memo is past retention.
+    fn remove_expired(&self, active: &mut ActiveSegment) -> io::Result<()> {
+        let cutoff = now_secs().saturating_sub(self.config.retention.as_secs());
+        let expired: Vec<u64> = active
+            .sealed
+            .iter()
+            .filter(|(_, &newest)| newest < cutoff)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let path = segment_path(&self.config.dir, id);
+            info!(
+                "Removing message log segment {} past retention",
+                path.display()
+            );
+            fs::remove_file(&path)?;
+            active.sealed.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+impl MessageLog for FileMessageLog {
+    fn append(&self, memo: &ChatMemo) -> io::Result<()> {
+        self.append_at(now_secs(), std::slice::from_ref(memo))
+    }
+
+    fn append_all(&self, memos: &[ChatMemo]) -> io::Result<()> {
+        self.append_at(now_secs(), memos)
+    }
+
+    fn load(&self) -> io::Result<Vec<ChatMemo>> {
+        // keeps appends out while reading so the active segment is never read half written
+        let _active = self.active.lock().expect("message log lock poisoned");
+        let cutoff = now_secs().saturating_sub(self.config.retention.as_secs());
+        let mut memos = Vec::new();
+        for id in segment_ids(&self.config.dir)? {
+            let (records, _) = read_records(&mut File::open(segment_path(&self.config.dir, id))?)?;
+            memos.extend(
+                records
+                    .into_iter()
+                    .filter(|record| record.at >= cutoff)
+                    .map(|record| record.memo),
+            );
+        }
+        Ok(memos)
+    }
+}
+
+/// Appends memos to the log on a thread of its own, in the order they are queued, so broadcasting
+/// never waits on the disk. Memos queued together are written with one sync, and those still queued
+/// when the last clone is dropped are written before the drop returns.
+#[derive(Clone)]
+pub struct LogWriter(Arc<WriterThread>);
+
+struct WriterThread {
+    tx: Option<mpsc::Sender<ChatMemo>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LogWriter {
+    pub fn spawn(message_log: Arc<dyn MessageLog>) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel::<ChatMemo>();
+        let thread = thread::Builder::new()
+            .name("message-log".to_string())
+            .spawn(move || {
+                while let Ok(memo) = rx.recv() {
+                    let mut memos = vec![memo];
+                    memos.extend(rx.try_iter());
+                    if let Err(e) = message_log.append_all(&memos) {
+                        // the rooms carry on in memory, only durability is lost
+                        error!(
+                            "Failed to append {} memos to message log: {}",
+                            memos.len(),
+                            e
+                        );
+                    }
+                }
+            })?;
+        Ok(Self(Arc::new(WriterThread {
+            tx: Some(tx),
+            thread: Some(thread),
+        })))
+    }
+
+    pub fn append(&self, memo: ChatMemo) {
+        let tx = self.0.tx.as_ref().expect("log writer is running");
+        if tx.send(memo).is_err() {
+            error!("Message log writer stopped, memo not persisted");
+        }
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        // the thread finishes what is queued once the channel is closed
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn newest_at(records: &[LogRecord]) -> u64 {
+    records.last().map_or(0, |record| record.at)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", id))
+}
+
+fn segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "log") {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Reads records from the start of the file up to the first incomplete or corrupt one,
+/// returning them with the length of the valid prefix.
+fn read_records(file: &mut File) -> io::Result<(Vec<LogRecord>, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0usize;
+    while let Some(header) = bytes.get(offset..offset + RECORD_HEADER_BYTES as usize) {
+        let len = u32::from_le_bytes(header[0..4].try_into().expect("4 byte length")) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().expect("4 byte crc"));
+        let start = offset + RECORD_HEADER_BYTES as usize;
+        let Some(payload) = bytes.get(start..start + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let Ok(record) = serde_json::from_slice::<LogRecord>(payload) else {
+            break;
+        };
+        records.push(record);
+        offset = start + len;
+    }
+    Ok((records, offset as u64))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memo(room: &str, content: &str) -> ChatMemo {
+        ChatMemo {
+            room: room.to_string(),
+            username: "carl".to_string(),
+            content: content.to_string(),
+            stamp: None,
+        }
+    }
+
+    fn config(dir: &Path) -> FileLogConfig {
+        FileLogConfig {
+            dir: dir.to_path_buf(),
+            segment_bytes: 1024 * 1024,
+            retention: Duration::from_secs(60 * 60),
+        }
+    }
+
+    fn contents(memos: &[ChatMemo]) -> Vec<&str> {
+        memos.iter().map(|memo| memo.content.as_str()).collect()
+    }
+
+    #[test]
+    fn test_append_and_load_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = FileMessageLog::open(config(dir.path())).unwrap();
+        log.append(&memo("lobby", "one")).unwrap();
+        log.append(&memo("rust", "two")).unwrap();
+        drop(log);
+
+        let log = FileMessageLog::open(config(dir.path())).unwrap();
+        log.append(&memo("lobby", "three")).unwrap();
+        let memos = log.load().unwrap();
+        assert_eq!(contents(&memos), vec!["one", "two", "three"]);
+        assert_eq!(memos[1].room, "rust");
+    }
+
+    #[test]
+    fn test_segments_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = FileMessageLog::open(FileLogConfig {
+            segment_bytes: 128,
+            ..config(dir.path())
+        })
+        .unwrap();
+        for i in 0..10 {
+            log.append(&memo("lobby", &format!("message {}", i)))
+                .unwrap();
+        }
+        assert!(segment_ids(dir.path()).unwrap().len() > 1);
+        let memos = log.load().unwrap();
+        assert_eq!(memos.len(), 10);
+        assert_eq!(memos[9].content, "message 9");
+    }
+
+    #[test]
+    fn test_torn_record_truncated_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = FileMessageLog::open(config(dir.path())).unwrap();
+        log.append(&memo("lobby", "kept")).unwrap();
+        log.append(&memo("lobby", "torn")).unwrap();
+        drop(log);
+
+        // crash in the middle of writing the last record
+        let path = segment_path(dir.path(), 0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let log = FileMessageLog::open(config(dir.path())).unwrap();
+        assert_eq!(contents(&log.load().unwrap()), vec!["kept"]);
+        // appends continue right after the last good record
+        log.append(&memo("lobby", "after crash")).unwrap();
+        drop(log);
+        let log = FileMessageLog::open(config(dir.path())).unwrap();
+        assert_eq!(contents(&log.load().unwrap()), vec!["kept", "after crash"]);
+    }
+
+    #[test]
+    fn test_retention_removes_old_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileLogConfig {
+            segment_bytes: 128,
+            ..config(dir.path())
+        };
+        let log = FileMessageLog::open(config).unwrap();
+        let two_hours_ago = now_secs() - 2 * 60 * 60;
+        for i in 0..5 {
+            log.append_at(two_hours_ago, &[memo("lobby", &format!("old {}", i))])
+                .unwrap();
+        }
+        log.append(&memo("lobby", "new")).unwrap();
+        // the new memo made the log rotate, sealing segments with only old memos
+        log.append(&memo("lobby", "newer")).unwrap();
+
+        let memos = log.load().unwrap();
+        assert_eq!(contents(&memos), vec!["new", "newer"]);
+        for id in segment_ids(dir.path()).unwrap() {
+            let (records, _) =
+                read_records(&mut File::open(segment_path(dir.path(), id)).unwrap()).unwrap();
+            assert!(records.iter().all(|record| record.at > two_hours_ago));
+        }
+    }
+
+    #[test]
+    fn test_log_writer_appends_in_order_before_drop_returns() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(FileMessageLog::open(config(dir.path())).unwrap());
+        let writer = LogWriter::spawn(log.clone()).unwrap();
+        let contents_sent: Vec<String> = (0..50).map(|i| format!("message {}", i)).collect();
+        for content in &contents_sent {
+            writer.clone().append(memo("lobby", content));
+        }
+        drop(writer);
+        assert_eq!(contents(&log.load().unwrap()), contents_sent);
+    }
+}
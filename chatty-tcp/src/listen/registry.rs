@@ -0,0 +1,702 @@
+use crate::listen::account::{AccountStore, GuestNames};
+use crate::listen::command::{leave_chat, RoomError};
+use crate::listen::connection::{HeartbeatConfig, ParkedSession};
+use crate::listen::limit::{Action, Limiter, RateLimits, Verdict};
+use crate::listen::outbound::{Outbound, OutboundConfig, QueueStats};
+use crate::listen::persist::{LogWriter, MessageLog};
+use crate::listen::response::SharedResponse;
+use crate::listen::state::RoomState;
+use crate::listen::token::TokenSigner;
+use chatty_types::command::{BearerToken, DEFAULT_ROOM};
+use chatty_types::limits::SizeLimits;
+use chatty_types::response::{ChatMemo, HistoryPage, RoomSummary};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Malformed commands in a row a connection gets away with unless configured otherwise.
+pub const DEFAULT_MALFORMED_STRIKES: u32 = 3;
+
+/// How long the session of a dropped connection is held unless configured otherwise.
+pub const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// How long resuming waits for a connection the server has not noticed is gone to hand its session over.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Id of one session of a user, which stays the same when resumed on another connection.
+pub type SessionId = u64;
+
+/// Sessions a username is online with, several only for users logged in to their account.
+struct Online {
+    shared: bool,
+    sessions: HashMap<SessionId, Outbound>,
+}
+
+/// Session a resume ticket was handed out for.
+enum Resumable {
+    /// Its connection is still up, and hands the session over once notified.
+    Live(Arc<Notify>),
+    /// Its connection was told to hand the session over to whoever is resuming it.
+    HandingOver(oneshot::Sender<ParkedSession>),
+    /// Its connection is gone, the session is held until resumed or the grace period is over.
+    Parked(ParkedSession),
+}
+
+/// All rooms on the server, created on demand when someone joins them,
+/// and the usernames currently online across all rooms with the queue of each of their sessions.
+/// Rate limiters of users outlive their connections until there is nothing left to remember.
+/// History of rooms removed once empty is archived until the room is created again.
+/// Sessions of dropped connections are held for a grace period, to be resumed with their ticket.
+pub struct RoomRegistry {
+    capacity: usize,
+    history_limit: usize,
+    rooms: Mutex<HashMap<String, Arc<RoomState>>>,
+    users: Mutex<HashMap<String, Online>>,
+    next_session: AtomicU64,
+    user_limiters: Mutex<HashMap<String, Limiter>>,
+    sessions: Mutex<HashMap<BearerToken, Resumable>>,
+    archive: Mutex<HashMap<String, Vec<ChatMemo>>>,
+    message_log: Option<LogWriter>,
+    accounts: Option<Arc<dyn AccountStore>>,
+    tokens: Option<Arc<TokenSigner>>,
+    guest_names: GuestNames,
+    lag_backfill: bool,
+    outbound_queue: OutboundConfig,
+    heartbeat: HeartbeatConfig,
+    rate_limits: RateLimits,
+    size_limits: SizeLimits,
+    malformed_strikes: u32,
+    certified_usernames: HashSet<String>,
+    resume_grace: Option<Duration>,
+}
+
+impl RoomRegistry {
+    /// Creates the registry with the default room, `capacity` bounds each room's broadcast channel
+    /// and `history_limit` the number of broadcast memos each room keeps for replay.
+    pub fn new(capacity: usize, history_limit: usize) -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(
+            DEFAULT_ROOM.to_string(),
+            Arc::new(RoomState::new(DEFAULT_ROOM, capacity, history_limit)),
+        );
+        Self {
+            capacity,
+            history_limit,
+            rooms: Mutex::new(rooms),
+            users: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(1),
+            user_limiters: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            archive: Mutex::new(HashMap::new()),
+            message_log: None,
+            accounts: None,
+            tokens: None,
+            guest_names: GuestNames::default(),
+            lag_backfill: false,
+            outbound_queue: OutboundConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            rate_limits: RateLimits::default(),
+            size_limits: SizeLimits::default(),
+            malformed_strikes: DEFAULT_MALFORMED_STRIKES,
+            certified_usernames: HashSet::new(),
+            resume_grace: Some(DEFAULT_RESUME_GRACE),
+        }
+    }
+
+    /// Replays broadcasts a lagging connection missed from the room's history, as far as kept.
+    pub fn with_lag_backfill(mut self, lag_backfill: bool) -> Self {
+        self.lag_backfill = lag_backfill;
+        self
+    }
+
+    pub fn lag_backfill(&self) -> bool {
+        self.lag_backfill
+    }
+
+    /// Size of each connection's queue of responses and what happens once it is full.
+    pub fn with_outbound_queue(mut self, outbound_queue: OutboundConfig) -> Self {
+        self.outbound_queue = outbound_queue;
+        self
+    }
+
+    pub fn outbound_queue(&self) -> OutboundConfig {
+        self.outbound_queue
+    }
+
+    /// When quiet connections are pinged and when they are disconnected.
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        self.heartbeat
+    }
+
+    /// Rate limits of each connection and of each user across their connections.
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits
+    }
+
+    /// Largest frame, message content and username accepted from clients.
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.size_limits = size_limits;
+        self
+    }
+
+    pub fn size_limits(&self) -> SizeLimits {
+        self.size_limits
+    }
+
+    /// Malformed commands in a row after which a connection is closed, never when zero.
+    pub fn with_malformed_strikes(mut self, malformed_strikes: u32) -> Self {
+        self.malformed_strikes = malformed_strikes;
+        self
+    }
+
+    pub fn malformed_strikes(&self) -> u32 {
+        self.malformed_strikes
+    }
+
+    /// How long sessions of dropped connections are held for clients to resume, never when None.
+    pub fn with_resume_grace(mut self, resume_grace: Option<Duration>) -> Self {
+        self.resume_grace = resume_grace;
+        self
+    }
+
+    pub fn resume_grace(&self) -> Option<Duration> {
+        self.resume_grace
+    }
+
+    /// Usernames client certificates stand for, which only clients with such a certificate may join with.
+    pub fn with_certified_usernames(mut self, certified_usernames: HashSet<String>) -> Self {
+        self.certified_usernames = certified_usernames;
+        self
+    }
+
+    pub fn is_certified_username(&self, username: &str) -> bool {
+        self.certified_usernames.contains(username)
+    }
+
+    /// Registered accounts, whose usernames only those logging in can join with.
+    pub fn with_accounts(mut self, accounts: Arc<dyn AccountStore>) -> Self {
+        self.accounts = Some(accounts);
+        self
+    }
+
+    pub fn accounts(&self) -> Option<&Arc<dyn AccountStore>> {
+        self.accounts.as_ref()
+    }
+
+    /// Signs session tokens for accounts to log in with later, and checks them.
+    pub fn with_tokens(mut self, tokens: Arc<TokenSigner>) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    pub fn tokens(&self) -> Option<&Arc<TokenSigner>> {
+        self.tokens.as_ref()
+    }
+
+    /// Usernames users joining without an account may take.
+    pub fn with_guest_names(mut self, guest_names: GuestNames) -> Self {
+        self.guest_names = guest_names;
+        self
+    }
+
+    pub fn guest_names(&self) -> &GuestNames {
+        &self.guest_names
+    }
+
+    /// Checks the command against the user's rate limits, kept across reconnects.
+    pub async fn check_user_limit(&self, username: &str, action: Action) -> Verdict {
+        let mut limiters = self.user_limiters.lock().await;
+        limiters
+            .entry(username.to_string())
+            .or_insert_with(|| Limiter::new(self.rate_limits))
+            .check(action, Instant::now())
+    }
+
+    /// Persists every broadcast memo to the log, restoring the history kept in it.
+    pub fn with_message_log(mut self, message_log: Arc<dyn MessageLog>) -> io::Result<Self> {
+        let mut archive: HashMap<String, Vec<ChatMemo>> = HashMap::new();
+        for memo in message_log.load()? {
+            archive.entry(memo.room.clone()).or_default().push(memo);
+        }
+        for memos in archive.values_mut() {
+            let excess = memos.len().saturating_sub(self.history_limit);
+            memos.drain(..excess);
+        }
+        info!(
+            "Restored history of {} rooms from message log",
+            archive.len()
+        );
+        self.message_log = Some(LogWriter::spawn(message_log)?);
+        let default_room = self.create_room(
+            DEFAULT_ROOM,
+            archive.remove(DEFAULT_ROOM).unwrap_or_default(),
+        );
+        self.rooms
+            .get_mut()
+            .insert(DEFAULT_ROOM.to_string(), Arc::new(default_room));
+        *self.archive.get_mut() = archive;
+        Ok(self)
+    }
+
+    fn create_room(&self, name: &str, archived: Vec<ChatMemo>) -> RoomState {
+        let room_state =
+            RoomState::new(name, self.capacity, self.history_limit).with_history(archived);
+        match &self.message_log {
+            Some(message_log) => room_state.with_message_log(message_log.clone()),
+            None => room_state,
+        }
+    }
+
+    /// Id for a new session, unique for the lifetime of the server.
+    pub fn new_session(&self) -> SessionId {
+        self.next_session.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Reserves the username for the whole server for the session, false if someone already has it.
+    /// Shared reservations, of users logged in to their account, are held by all their sessions at once.
+    /// The outbound queue is where direct messages for the user go.
+    pub async fn reserve_username(
+        &self,
+        username: &str,
+        session: SessionId,
+        outbound: Outbound,
+        shared: bool,
+    ) -> bool {
+        let mut users = self.users.lock().await;
+        match users.get_mut(username) {
+            Some(online) if online.shared && shared => {
+                online.sessions.insert(session, outbound);
+            }
+            Some(_) => return false,
+            None => {
+                users.insert(
+                    username.to_string(),
+                    Online {
+                        shared,
+                        sessions: HashMap::from([(session, outbound)]),
+                    },
+                );
+            }
+        }
+        true
+    }
+
+    /// Gives up the username for the session, the user is offline once no session holds it.
+    pub async fn release_username(&self, username: &str, session: SessionId) {
+        let mut users = self.users.lock().await;
+        if let Some(online) = users.get_mut(username) {
+            online.sessions.remove(&session);
+            if online.sessions.is_empty() {
+                users.remove(username);
+            }
+        }
+        // forget limiters of users offline once they are back to full buckets
+        let now = Instant::now();
+        self.user_limiters
+            .lock()
+            .await
+            .retain(|username, limiter| users.contains_key(username) || !limiter.is_at_rest(now));
+    }
+
+    /// Outbound queue of each session the user is online with, none if the user is offline.
+    pub async fn user_sessions(&self, username: &str) -> Vec<(SessionId, Outbound)> {
+        self.users
+            .lock()
+            .await
+            .get(username)
+            .map_or_else(Vec::new, |online| {
+                online
+                    .sessions
+                    .iter()
+                    .map(|(&session, outbound)| (session, outbound.clone()))
+                    .collect()
+            })
+    }
+
+    /// Depth of the outbound queue of every session online, deepest first.
+    pub async fn queue_stats(&self) -> Vec<(String, QueueStats)> {
+        let mut stats: Vec<_> = self
+            .users
+            .lock()
+            .await
+            .iter()
+            .flat_map(|(username, online)| {
+                online
+                    .sessions
+                    .values()
+                    .map(|outbound| (username.clone(), outbound.stats()))
+            })
+            .collect();
+        stats.sort_by(|(_, a), (_, b)| b.depth.cmp(&a.depth).then(b.high_water.cmp(&a.high_water)));
+        stats
+    }
+
+    /// Lets the session of a live connection be resumed with the ticket,
+    /// the connection hands it over once `takeover` is notified.
+    pub async fn hold_session(&self, ticket: BearerToken, takeover: Arc<Notify>) {
+        self.sessions
+            .lock()
+            .await
+            .insert(ticket, Resumable::Live(takeover));
+    }
+
+    /// Forgets the ticket of a session that left the chat.
+    pub async fn forget_session(&self, ticket: &BearerToken) {
+        self.sessions.lock().await.remove(ticket);
+    }
+
+    /// Holds the session of a dropped connection, handing it over right away if someone is resuming it,
+    /// and has it leave the chat unless resumed within the grace period.
+    pub async fn park(self: &Arc<Self>, ticket: BearerToken, parked: ParkedSession) {
+        let mut sessions = self.sessions.lock().await;
+        let parked = match sessions.remove(&ticket) {
+            Some(Resumable::HandingOver(hand_over)) => match hand_over.send(parked) {
+                Ok(()) => return,
+                // whoever was resuming gave up waiting
+                Err(parked) => parked,
+            },
+            _ => parked,
+        };
+        let grace = self.resume_grace.unwrap_or_default();
+        info!(
+            "Holding session of {} for {:?} to be resumed",
+            parked.username, grace
+        );
+        sessions.insert(ticket.clone(), Resumable::Parked(parked));
+        drop(sessions);
+        let registry = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let mut sessions = registry.sessions.lock().await;
+            let parked = match sessions.remove(&ticket) {
+                Some(Resumable::Parked(parked)) => parked,
+                Some(other) => {
+                    sessions.insert(ticket, other);
+                    return;
+                }
+                None => return,
+            };
+            drop(sessions);
+            info!("Session of {} was not resumed in time", parked.username);
+            parked.outbound.close();
+            let rooms = parked.rooms.into_iter().collect();
+            leave_chat(parked.username, parked.id, rooms, registry).await;
+        });
+    }
+
+    /// Whether the session of the ticket is held since its connection dropped.
+    pub async fn is_parked(&self, ticket: &BearerToken) -> bool {
+        matches!(
+            self.sessions.lock().await.get(ticket),
+            Some(Resumable::Parked(_))
+        )
+    }
+
+    /// Takes the session of the ticket over, held since its connection dropped, or handed over
+    /// by its connection that is not noticed to be gone yet. None for an unknown ticket.
+    pub async fn resume(&self, ticket: &BearerToken) -> Option<ParkedSession> {
+        let handed_over = {
+            let mut sessions = self.sessions.lock().await;
+            match sessions.remove(ticket)? {
+                // given up on for being too slow while held, left to the end of the grace period
+                Resumable::Parked(parked) if parked.outbound.is_closed() => {
+                    sessions.insert(ticket.clone(), Resumable::Parked(parked));
+                    return None;
+                }
+                Resumable::Parked(parked) => return Some(parked),
+                Resumable::Live(takeover) => {
+                    let (hand_over, handed_over) = oneshot::channel();
+                    sessions.insert(ticket.clone(), Resumable::HandingOver(hand_over));
+                    takeover.notify_one();
+                    handed_over
+                }
+                handing_over @ Resumable::HandingOver(_) => {
+                    // resumed by someone else right now
+                    sessions.insert(ticket.clone(), handing_over);
+                    return None;
+                }
+            }
+        };
+        tokio::time::timeout(HANDOVER_TIMEOUT, handed_over)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    pub async fn room(&self, name: &str) -> Option<Arc<RoomState>> {
+        self.rooms.lock().await.get(name).cloned()
+    }
+
+    /// Adds the session of the user to the room, creating the room if needed, with the send task
+    /// spawned from a fresh subscription to the room's broadcast channel.
+    /// Returns the latest history from before the subscription and whether the user just arrived
+    /// in the room with no other session in it, None if the session is already in the room.
+    pub async fn join<F>(
+        &self,
+        name: &str,
+        username: &str,
+        session: SessionId,
+        spawn_send_task: F,
+    ) -> Option<(Arc<RoomState>, HistoryPage, bool)>
+    where
+        F: FnOnce(
+            &Arc<RoomState>,
+            broadcast::Receiver<SharedResponse>,
+        ) -> JoinHandle<Result<(), RoomError>>,
+    {
+        // holding the rooms lock so the room cannot be removed as empty while joining it
+        let mut rooms = self.rooms.lock().await;
+        if !rooms.contains_key(name) {
+            info!("Creating room {}", name);
+            let archived = self.archive.lock().await.remove(name).unwrap_or_default();
+            rooms.insert(name.to_string(), Arc::new(self.create_room(name, archived)));
+        }
+        let room_state = rooms[name].clone();
+        let mut lookup = room_state.task_handles.lock().await;
+        let arrived = !lookup.contains_key(username);
+        let tasks = lookup.entry(username.to_string()).or_default();
+        if tasks.contains_key(&session) {
+            return None;
+        }
+        let (rx, history) = room_state.subscribe(self.size_limits.max_frame_bytes).await;
+        tasks.insert(session, spawn_send_task(&room_state, rx));
+        info!("Users in room {} after addition: {:?}", name, lookup.keys());
+        drop(lookup);
+        Some((room_state, history, arrived))
+    }
+
+    /// Drops the room once the last member is gone, the default room always stays.
+    pub async fn remove_if_empty(&self, name: &str) {
+        if name == DEFAULT_ROOM {
+            return;
+        }
+        let mut rooms = self.rooms.lock().await;
+        let Some(room_state) = rooms.get(name) else {
+            return;
+        };
+        if room_state.task_handles.lock().await.is_empty() {
+            info!("Removing empty room {}", name);
+            let memos = room_state.history_memos().await;
+            self.archive.lock().await.insert(name.to_string(), memos);
+            rooms.remove(name);
+        }
+    }
+
+    /// Rooms with their member counts, sorted by name.
+    pub async fn summaries(&self) -> Vec<RoomSummary> {
+        let rooms = self.rooms.lock().await;
+        let mut summaries = Vec::with_capacity(rooms.len());
+        for (name, room_state) in rooms.iter() {
+            summaries.push(RoomSummary {
+                name: name.clone(),
+                members: room_state.task_handles.lock().await.len(),
+            });
+        }
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    /// Aborts the background send tasks in every room, used on server shutdown.
+    pub async fn abort_all(&self) {
+        let rooms = self.rooms.lock().await;
+        for (name, room_state) in rooms.iter() {
+            let mut handles = room_state.task_handles.lock().await;
+            for (username, tasks) in handles.iter() {
+                info!(
+                    "Aborting background send tasks for user: {} in room {}",
+                    username, name
+                );
+                for handle in tasks.values() {
+                    handle.abort();
+                }
+            }
+            handles.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::WriteHalf;
+    use chatty_types::codec::{Framing, ServerCodec};
+    use chatty_types::response::ChatResponse;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_test::assert_ok;
+    use tokio_util::codec::FramedWrite;
+
+    fn dummy_send_task(
+        _room_state: &Arc<RoomState>,
+        _rx: broadcast::Receiver<SharedResponse>,
+    ) -> JoinHandle<Result<(), RoomError>> {
+        tokio::spawn(async { Ok(()) })
+    }
+
+    #[tokio::test]
+    async fn test_rooms_created_on_demand_and_removed_when_empty() {
+        let registry = RoomRegistry::new(100, 100);
+        assert!(registry.room("rust").await.is_none());
+
+        let (room_state, _, arrived) = registry
+            .join("rust", "carl", 1, dummy_send_task)
+            .await
+            .unwrap();
+        assert_eq!(room_state.name, "rust");
+        assert!(arrived);
+        assert!(registry
+            .join("rust", "carl", 1, dummy_send_task)
+            .await
+            .is_none());
+        // another session of carl, who is in the room already
+        let (_, _, arrived) = registry
+            .join("rust", "carl", 2, dummy_send_task)
+            .await
+            .unwrap();
+        assert!(!arrived);
+        assert!(registry
+            .join("rust", "david", 3, dummy_send_task)
+            .await
+            .is_some());
+        assert!(registry
+            .join(DEFAULT_ROOM, "carl", 1, dummy_send_task)
+            .await
+            .is_some());
+
+        assert_eq!(
+            registry.summaries().await,
+            vec![
+                RoomSummary {
+                    name: DEFAULT_ROOM.to_string(),
+                    members: 1
+                },
+                RoomSummary {
+                    name: "rust".to_string(),
+                    members: 2
+                },
+            ]
+        );
+
+        room_state.task_handles.lock().await.clear();
+        let _rx = room_state.tx.subscribe();
+        assert_ok!(
+            room_state
+                .broadcast(ChatResponse::Broadcast(ChatMemo {
+                    room: "rust".to_string(),
+                    username: "carl".to_string(),
+                    content: "Anyone?".to_string(),
+                    stamp: None,
+                }))
+                .await
+        );
+        registry.remove_if_empty("rust").await;
+        assert!(registry.room("rust").await.is_none());
+
+        // history is back when the room is created again
+        let (_, history, _) = registry
+            .join("rust", "david", 3, dummy_send_task)
+            .await
+            .unwrap();
+        assert_eq!(history.memos.len(), 1);
+        assert_eq!(history.memos[0].content, "Anyone?");
+
+        // the default room stays even when empty
+        registry
+            .room(DEFAULT_ROOM)
+            .await
+            .unwrap()
+            .task_handles
+            .lock()
+            .await
+            .clear();
+        registry.remove_if_empty(DEFAULT_ROOM).await;
+        assert!(registry.room(DEFAULT_ROOM).await.is_some());
+    }
+
+    async fn test_outbound() -> Outbound {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_usernames_reserved_across_rooms() {
+        let registry = RoomRegistry::new(100, 100);
+        let outbound = test_outbound().await;
+        assert!(registry.user_sessions("carl").await.is_empty());
+        assert!(
+            registry
+                .reserve_username("carl", 1, outbound.clone(), false)
+                .await
+        );
+        assert!(
+            !registry
+                .reserve_username("carl", 2, outbound.clone(), false)
+                .await
+        );
+        assert_eq!(registry.user_sessions("carl").await.len(), 1);
+        assert_eq!(
+            registry.queue_stats().await,
+            vec![("carl".to_string(), QueueStats::default())]
+        );
+        registry.release_username("carl", 1).await;
+        assert!(registry.user_sessions("carl").await.is_empty());
+        assert!(registry.reserve_username("carl", 2, outbound, false).await);
+    }
+
+    #[tokio::test]
+    async fn test_account_usernames_shared_by_sessions() {
+        let registry = RoomRegistry::new(100, 100);
+        let outbound = test_outbound().await;
+        assert!(
+            registry
+                .reserve_username("carl", 1, outbound.clone(), true)
+                .await
+        );
+        assert!(
+            registry
+                .reserve_username("carl", 2, outbound.clone(), true)
+                .await
+        );
+        // a guest never gets a username an account is online with
+        assert!(
+            !registry
+                .reserve_username("carl", 3, outbound.clone(), false)
+                .await
+        );
+        assert_eq!(registry.user_sessions("carl").await.len(), 2);
+
+        // online while any session is
+        registry.release_username("carl", 1).await;
+        let sessions = registry.user_sessions("carl").await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].0, 2);
+        registry.release_username("carl", 2).await;
+        assert!(registry.user_sessions("carl").await.is_empty());
+        assert_ne!(registry.new_session(), registry.new_session());
+    }
+}
@@ -1,51 +1,143 @@
+use crate::handler::WriteHalf;
 use crate::listen::command::RoomError;
+use crate::listen::outbound::Outbound;
+use crate::listen::registry::SessionId;
 use crate::listen::state::RoomState;
 use anyhow::Result;
 use broadcast::error::RecvError;
-use chatty_types::response::ChatResponse;
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::{broadcast, Mutex};
+use chatty_types::codec::{CodecError, Encoding, Frame, Framing, ServerCodec};
+use chatty_types::response::{ChatMemo, ChatResponse, Lagged};
+use std::sync::{Arc, OnceLock, Weak};
+use tokio::sync::broadcast;
+use tokio_util::codec::FramedWrite;
 use tracing::{debug, info};
 
+/// Write half of a client connection, framing and serializing the responses sent on it.
+pub type ResponseWriter = FramedWrite<WriteHalf, ServerCodec>;
+
+/// Response going out to many connections, encoded at most once for each framing and encoding
+/// with the frame shared by every connection using them.
+#[derive(Debug, Clone)]
+pub struct SharedResponse(Arc<Frames>);
+
+#[derive(Debug)]
+struct Frames {
+    response: ChatResponse,
+    origin: Option<SessionId>,
+    encoded: [OnceLock<Frame>; 6],
+}
+
+impl SharedResponse {
+    pub fn new(response: ChatResponse) -> Self {
+        Self(Arc::new(Frames {
+            response,
+            origin: None,
+            encoded: Default::default(),
+        }))
+    }
+
+    /// Response to what the session sent.
+    pub fn from_session(response: ChatResponse, origin: SessionId) -> Self {
+        Self(Arc::new(Frames {
+            response,
+            origin: Some(origin),
+            encoded: Default::default(),
+        }))
+    }
+
+    pub fn response(&self) -> &ChatResponse {
+        &self.0.response
+    }
+
+    /// Session the response is to, None for what the server says on its own.
+    pub fn origin(&self) -> Option<SessionId> {
+        self.0.origin
+    }
+
+    /// Frame in the framing and encoding of the codec, encoded by the first connection needing it.
+    pub fn frame(&self, codec: &mut ServerCodec) -> Result<Frame, CodecError> {
+        let framing = match codec.framing() {
+            Framing::Lines => 0,
+            Framing::LengthPrefixed => 1,
+        };
+        let encoding = match codec.encoding() {
+            Encoding::Json => 0,
+            Encoding::MessagePack => 1,
+            Encoding::Cbor => 2,
+        };
+        let slot = &self.0.encoded[framing * 3 + encoding];
+        if let Some(frame) = slot.get() {
+            return Ok(frame.clone());
+        }
+        // connections racing here both encode, only one frame is kept
+        let frame = codec.frame(&self.0.response)?;
+        Ok(slot.get_or_init(|| frame).clone())
+    }
+}
+
 pub async fn send_to_broadcast_channel(
     chat_response: ChatResponse,
     room_state: Arc<RoomState>,
 ) -> Result<(), RoomError> {
     // send the chat_response to the broadcast channel
-    let _ = room_state.tx.send(chat_response)?;
+    let _ = room_state.broadcast(chat_response).await?;
 
     Ok(())
 }
 
+/// Writes the room's broadcasts to the connection of one session of the user,
+/// those sent from the session itself only if the connection asked for them to be echoed.
+/// What the user sent from other sessions is written like anyone else's.
+/// Falling behind is reported with a `Lagged`, followed by the missed broadcasts still in the
+/// history of the room when given one to backfill from.
 pub async fn send_from_broadcast_channel(
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    mut rx: broadcast::Receiver<ChatResponse>,
+    outbound: Outbound,
+    mut rx: broadcast::Receiver<SharedResponse>,
     username: String,
+    session: SessionId,
+    echo: bool,
+    backfill: Option<Weak<RoomState>>,
 ) -> Result<(), RoomError> {
+    let mut lagged = 0;
     loop {
         match rx.recv().await {
             Ok(recv_chat_response) => {
                 debug!(
                     "send_task received from broadcast::Receiver: recv_chat_response  is {:?}",
-                    recv_chat_response
+                    recv_chat_response.response()
                 );
-                let ChatResponse::Broadcast(recv_memo) = recv_chat_response.clone() else {
+                let ChatResponse::Broadcast(recv_memo) = recv_chat_response.response() else {
                     return Err(RoomError::BroadcastReceive(
                         "Failed to get memo from received chat response".to_string(),
                     ));
                 };
+                if lagged > 0 {
+                    let missed = std::mem::take(&mut lagged);
+                    let report = report_lag(
+                        recv_memo,
+                        missed,
+                        &username,
+                        session,
+                        echo,
+                        backfill.as_ref(),
+                        &outbound,
+                    );
+                    if let Err(e) = report.await {
+                        debug!("Failed to report lag: {:?}", e);
+                        break;
+                    }
+                }
                 let recv_username = recv_memo.username.clone();
                 debug!("recv_username in send_task is {:?}", recv_username);
                 debug!("username in send_task is {:?}", username);
 
-                if !recv_username.eq(&username) {
+                let own = is_own(recv_memo, recv_chat_response.origin(), &username, session);
+                if echo || !own {
                     debug!(
                         "Sending to -> {} chat response for received username -> {}",
                         username, recv_username
                     );
-                    if let Err(e) = send_response(recv_chat_response, writer.clone()).await {
+                    if let Err(e) = outbound.send_shared(recv_chat_response.clone()).await {
                         debug!("Failed to send response: {:?}", e);
                         break;
                     }
@@ -54,7 +146,9 @@ pub async fn send_from_broadcast_channel(
             Err(RecvError::Lagged(missed)) => {
                 // This condition is very unlikely in normal chat usage with human users.
                 // Only expected during extreme message bursts or potential DoS (Denial of Service) attack
+                // reported once the next broadcast shows where the skipped ones ended
                 info!("Receiver lagged, skipped {} messages", missed);
+                lagged += missed;
                 continue;
             }
             Err(RecvError::Closed) => {
@@ -66,26 +160,65 @@ pub async fn send_from_broadcast_channel(
     Ok(())
 }
 
-pub async fn send_response(
-    chat_response: ChatResponse,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-) -> Result<(), RoomError> {
-    let serialized = serde_json::to_string(&chat_response)?;
-    let mut writer = writer.lock().await;
-    writer.write_all(serialized.as_bytes()).await?;
+/// Whether the memo was sent from the session, which the server's own broadcasts about the user,
+/// such as joining, count as.
+fn is_own(memo: &ChatMemo, origin: Option<SessionId>, username: &str, session: SessionId) -> bool {
+    match origin {
+        Some(origin) => origin == session,
+        None => memo.username == username,
+    }
+}
 
-    writer.write_all(b"\n").await?;
-    Ok(())
+/// Tells the connection which broadcasts it skipped right before `next`, the first one
+/// received after them, and replays those of them still kept in the room's history.
+async fn report_lag(
+    next: &ChatMemo,
+    missed: u64,
+    username: &str,
+    session: SessionId,
+    echo: bool,
+    backfill: Option<&Weak<RoomState>>,
+    outbound: &Outbound,
+) -> Result<(), RoomError> {
+    // broadcasts are stamped before they are sent, so this is only for safety
+    let Some(stamp) = next.stamp else {
+        return Ok(());
+    };
+    let last_seq = stamp.seq.saturating_sub(1);
+    let first_seq = stamp.seq.saturating_sub(missed);
+    let recovered = match backfill.and_then(Weak::upgrade) {
+        Some(room_state) => room_state.memos_between(first_seq, last_seq).await,
+        None => Vec::new(),
+    };
+    let lag = ChatResponse::Lagged(Lagged {
+        room: next.room.clone(),
+        missed,
+        first_seq,
+        last_seq,
+        recovered: recovered.len() as u64,
+    });
+    let replayed = recovered
+        .into_iter()
+        .filter(|(memo, origin)| echo || !is_own(memo, *origin, username, session))
+        .map(|(memo, _)| ChatResponse::Broadcast(memo));
+    outbound
+        .send_all(std::iter::once(lag).chain(replayed))
+        .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::listen::outbound::OutboundConfig;
+    use chatty_types::codec::{ClientCodec, Framing};
+    use chatty_types::command::DEFAULT_ROOM;
     use chatty_types::response::{ChatMemo, ChatResponse};
+    use futures::StreamExt;
     use tokio::io::AsyncReadExt;
     use tokio::net::TcpListener;
     use tokio::net::TcpStream;
     use tokio_test::{assert_err, assert_ok};
+    use tokio_util::codec::FramedRead;
 
     #[tokio::test]
     async fn test_send_task_different_username() {
@@ -95,19 +228,32 @@ mod tests {
         let addr = assert_ok!(listener.local_addr());
 
         let client = assert_ok!(TcpStream::connect(addr).await);
+        let peer = client.peer_addr().unwrap();
         let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
 
-        let writer = Arc::new(Mutex::new(writer_half));
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        );
         let _handle = tokio::spawn(async move {
-            assert_ok!(send_from_broadcast_channel(writer, rx, "alice".to_string()).await);
+            assert_ok!(
+                send_from_broadcast_channel(outbound, rx, "alice".to_string(), 1, false, None)
+                    .await
+            );
         });
 
         let (mut stream, _) = assert_ok!(listener.accept().await);
 
-        assert_ok!(tx.send(ChatResponse::Broadcast(ChatMemo {
-            username: "carl".to_string(),
-            content: "hello, I love tokio".to_string(),
-        })));
+        assert_ok!(
+            tx.send(SharedResponse::new(ChatResponse::Broadcast(ChatMemo {
+                room: DEFAULT_ROOM.to_string(),
+                username: "carl".to_string(),
+                content: "hello, I love tokio".to_string(),
+                stamp: None,
+            })))
+        );
 
         let mut buf = vec![0; 1024];
         let n = assert_ok!(stream.read(&mut buf).await);
@@ -126,18 +272,31 @@ mod tests {
         let addr = assert_ok!(listener.local_addr());
 
         let client = assert_ok!(TcpStream::connect(addr).await);
+        let peer = client.peer_addr().unwrap();
         let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
 
-        let writer = Arc::new(Mutex::new(writer_half));
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        );
         let _handle = tokio::spawn(async move {
-            assert_ok!(send_from_broadcast_channel(writer, rx, "alice".to_string()).await);
+            assert_ok!(
+                send_from_broadcast_channel(outbound, rx, "alice".to_string(), 1, false, None)
+                    .await
+            );
         });
 
         let (mut stream, _) = assert_ok!(listener.accept().await);
-        assert_ok!(tx.send(ChatResponse::Broadcast(ChatMemo {
-            username: "alice".to_string(),
-            content: "hello, I love tokio".to_string(),
-        })));
+        assert_ok!(
+            tx.send(SharedResponse::new(ChatResponse::Broadcast(ChatMemo {
+                room: DEFAULT_ROOM.to_string(),
+                username: "alice".to_string(),
+                content: "hello, I love tokio".to_string(),
+                stamp: None,
+            })))
+        );
 
         // Verify no data was sent
         let mut buf = vec![0; 1024];
@@ -147,4 +306,178 @@ mod tests {
 
         assert_err!(result);
     }
+
+    #[tokio::test]
+    async fn test_send_task_same_username_echoed() {
+        let (tx, rx) = broadcast::channel(100);
+
+        let listener = assert_ok!(TcpListener::bind("127.0.0.1:0").await);
+        let addr = assert_ok!(listener.local_addr());
+
+        let client = assert_ok!(TcpStream::connect(addr).await);
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        );
+        let _handle = tokio::spawn(async move {
+            assert_ok!(
+                send_from_broadcast_channel(outbound, rx, "alice".to_string(), 1, true, None).await
+            );
+        });
+
+        let (mut stream, _) = assert_ok!(listener.accept().await);
+        assert_ok!(
+            tx.send(SharedResponse::new(ChatResponse::Broadcast(ChatMemo {
+                room: DEFAULT_ROOM.to_string(),
+                username: "alice".to_string(),
+                content: "hello, I love tokio".to_string(),
+                stamp: None,
+            })))
+        );
+
+        let mut buf = vec![0; 1024];
+        let n = assert_ok!(stream.read(&mut buf).await);
+        let received = String::from_utf8_lossy(&buf[..n]);
+
+        assert!(received.contains("alice"));
+        assert!(received.contains("hello, I love tokio"));
+    }
+
+    const FROM_CARL: [(&str, Option<SessionId>); 5] = [("carl", None); 5];
+
+    /// Room with a buffer of two per member that broadcast five memos from the senders
+    /// before the send task of alice's session 1 got to read any.
+    async fn lagging_member(
+        backfill: bool,
+        senders: [(&str, Option<SessionId>); 5],
+    ) -> (Arc<RoomState>, FramedRead<TcpStream, ClientCodec>) {
+        let room = Arc::new(RoomState::new(DEFAULT_ROOM, 2, 10));
+        let rx = room.tx.subscribe();
+        for (i, (username, origin)) in (1..).zip(senders) {
+            assert_ok!(
+                room.broadcast_from(
+                    origin,
+                    ChatResponse::Broadcast(ChatMemo {
+                        room: DEFAULT_ROOM.to_string(),
+                        username: username.to_string(),
+                        content: format!("message {}", i),
+                        stamp: None,
+                    })
+                )
+                .await
+            );
+        }
+
+        let listener = assert_ok!(TcpListener::bind("127.0.0.1:0").await);
+        let addr = assert_ok!(listener.local_addr());
+        let client = assert_ok!(TcpStream::connect(addr).await);
+        let peer = client.peer_addr().unwrap();
+        let (_, writer_half) = client.into_split();
+        let writer_half: WriteHalf = Box::new(writer_half);
+        let outbound = Outbound::spawn(
+            FramedWrite::new(writer_half, ServerCodec::new(Framing::Lines)),
+            peer,
+            OutboundConfig::default(),
+        );
+        let backfill = backfill.then(|| Arc::downgrade(&room));
+        tokio::spawn(send_from_broadcast_channel(
+            outbound,
+            rx,
+            "alice".to_string(),
+            1,
+            false,
+            backfill,
+        ));
+        let (stream, _) = assert_ok!(listener.accept().await);
+        (
+            room,
+            FramedRead::new(stream, ClientCodec::new(Framing::Lines)),
+        )
+    }
+
+    async fn next_content(reader: &mut FramedRead<TcpStream, ClientCodec>) -> String {
+        match assert_ok!(reader.next().await.unwrap()) {
+            ChatResponse::Broadcast(memo) => memo.content,
+            other => panic!("expected broadcast, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_task_reports_lag() {
+        let (_room, mut reader) = lagging_member(false, FROM_CARL).await;
+        let lagged = assert_ok!(reader.next().await.unwrap());
+        assert_eq!(
+            lagged,
+            ChatResponse::Lagged(Lagged {
+                room: DEFAULT_ROOM.to_string(),
+                missed: 3,
+                first_seq: 1,
+                last_seq: 3,
+                recovered: 0,
+            })
+        );
+        assert_eq!(next_content(&mut reader).await, "message 4");
+        assert_eq!(next_content(&mut reader).await, "message 5");
+    }
+
+    #[tokio::test]
+    async fn test_send_task_backfills_lag_from_history() {
+        let (_room, mut reader) = lagging_member(true, FROM_CARL).await;
+        let lagged = assert_ok!(reader.next().await.unwrap());
+        let ChatResponse::Lagged(lagged) = lagged else {
+            panic!("expected lagged, got {:?}", lagged);
+        };
+        assert_eq!((lagged.missed, lagged.recovered), (3, 3));
+        for i in 1..=5 {
+            assert_eq!(next_content(&mut reader).await, format!("message {}", i));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_task_backfills_what_other_devices_of_the_user_sent() {
+        let senders = [
+            ("alice", Some(2)),
+            ("alice", Some(1)),
+            ("alice", Some(2)),
+            ("alice", Some(1)),
+            ("alice", Some(2)),
+        ];
+        let (_room, mut reader) = lagging_member(true, senders).await;
+        let lagged = assert_ok!(reader.next().await.unwrap());
+        let ChatResponse::Lagged(lagged) = lagged else {
+            panic!("expected lagged, got {:?}", lagged);
+        };
+        assert_eq!((lagged.missed, lagged.recovered), (3, 3));
+        // only what session 1 sent itself is left out, as it is not echoed
+        assert_eq!(next_content(&mut reader).await, "message 1");
+        assert_eq!(next_content(&mut reader).await, "message 3");
+        assert_eq!(next_content(&mut reader).await, "message 5");
+    }
+
+    #[test]
+    fn test_shared_response_encoded_once_per_encoding() {
+        let shared = SharedResponse::new(ChatResponse::Broadcast(ChatMemo {
+            room: DEFAULT_ROOM.to_string(),
+            username: "carl".to_string(),
+            content: "hello, I love tokio".to_string(),
+            stamp: None,
+        }));
+        let mut json = ServerCodec::new(Framing::LengthPrefixed);
+        let mut cbor = ServerCodec::new(Framing::LengthPrefixed).with_encoding(Encoding::Cbor);
+
+        let first = assert_ok!(shared.frame(&mut json));
+        let again = assert_ok!(shared
+            .clone()
+            .frame(&mut ServerCodec::new(Framing::LengthPrefixed)));
+        // the very same bytes, not just equal ones
+        assert_eq!(first.0.as_ptr(), again.0.as_ptr());
+        let other = assert_ok!(shared.frame(&mut cbor));
+        assert_ne!(first, other);
+        assert_eq!(other, assert_ok!(cbor.frame(shared.response())));
+    }
 }
@@ -1,16 +1,27 @@
 use crate::handler::ChatHandler;
 use crate::listen::command::{process_command, RoomError};
-use crate::listen::state::RoomState;
+use crate::listen::registry::RoomRegistry;
 use anyhow::Result;
 use std::sync::Arc;
 
-pub async fn serve(handler: ChatHandler, room_state: Arc<RoomState>) -> Result<(), RoomError> {
+pub async fn serve(handler: ChatHandler, registry: Arc<RoomRegistry>) -> Result<(), RoomError> {
     let ChatHandler {
         writer_half,
         reader_half,
+        framing,
+        peer_addr,
+        certified,
     } = handler;
 
-    process_command(writer_half, reader_half, room_state).await?;
+    process_command(
+        writer_half,
+        reader_half,
+        peer_addr,
+        certified,
+        framing,
+        registry,
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,13 +1,389 @@
 use crate::listen::command::RoomError;
-use chatty_types::response::ChatResponse;
-use std::collections::HashMap;
+use crate::listen::persist::LogWriter;
+use crate::listen::registry::SessionId;
+use crate::listen::response::SharedResponse;
+use chatty_types::response::{ChatMemo, ChatResponse, HistoryPage, Stamp};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
+use uuid::Uuid;
 
-type TaskHandleMap = Mutex<HashMap<String, JoinHandle<Result<(), RoomError>>>>;
+/// Send task of each session a member is in the room with.
+pub type SessionTasks = HashMap<SessionId, JoinHandle<Result<(), RoomError>>>;
+
+/// Members of the room by username, who are in it as long as any of their sessions is.
+type TaskHandleMap = Mutex<HashMap<String, SessionTasks>>;
+
+/// Number of memos in one page of history, on join and when scrolling back.
+pub const HISTORY_PAGE_SIZE: usize = 20;
 
 pub struct RoomState {
-    pub tx: broadcast::Sender<ChatResponse>,
+    pub name: String,
+    pub tx: broadcast::Sender<SharedResponse>,
     pub task_handles: TaskHandleMap,
+    history: Mutex<History>,
+    message_log: Option<LogWriter>,
+}
+
+impl RoomState {
+    pub fn new(name: impl Into<String>, capacity: usize, history_limit: usize) -> Self {
+        let (tx, _rx) = broadcast::channel(capacity);
+        Self {
+            name: name.into(),
+            tx,
+            task_handles: Mutex::new(HashMap::new()),
+            history: Mutex::new(History::new(history_limit)),
+            message_log: None,
+        }
+    }
+
+    /// Starts the history off with memos from before the room was last created.
+    pub fn with_history(mut self, memos: Vec<ChatMemo>) -> Self {
+        let history = self.history.get_mut();
+        for memo in memos {
+            history.record(memo, None);
+        }
+        self
+    }
+
+    /// Every broadcast memo is also queued to be appended to the log.
+    pub fn with_message_log(mut self, message_log: LogWriter) -> Self {
+        self.message_log = Some(message_log);
+        self
+    }
+
+    /// Sends to everyone subscribed, stamping broadcast memos with the next sequence number
+    /// of the room and keeping them in the room's history.
+    pub async fn broadcast(&self, chat_response: ChatResponse) -> Result<usize, RoomError> {
+        self.broadcast_from(None, chat_response).await
+    }
+
+    /// Same as `broadcast`, for what one session of a member said, which every other session
+    /// of the member gets too.
+    pub async fn broadcast_from(
+        &self,
+        origin: Option<SessionId>,
+        mut chat_response: ChatResponse,
+    ) -> Result<usize, RoomError> {
+        // history stays locked while sending so subscribe sees each memo either in history or live
+        // and every subscriber gets the memos in sequence order
+        let mut history = self.history.lock().await;
+        if let ChatResponse::Broadcast(memo) = &mut chat_response {
+            memo.stamp = Some(Stamp {
+                id: Uuid::now_v7(),
+                timestamp_ms: now_millis(),
+                seq: history.recorded + 1,
+            });
+            if let Some(message_log) = &self.message_log {
+                // queued in sequence order, as the history is still locked
+                message_log.append(memo.clone());
+            }
+            history.record(memo.clone(), origin);
+        }
+        let shared = match origin {
+            Some(origin) => SharedResponse::from_session(chat_response, origin),
+            None => SharedResponse::new(chat_response),
+        };
+        Ok(self.tx.send(shared)?)
+    }
+
+    /// Subscribes to the room along with the latest page of everything said before,
+    /// of no more than `max_page_bytes` encoded.
+    pub async fn subscribe(
+        &self,
+        max_page_bytes: usize,
+    ) -> (broadcast::Receiver<SharedResponse>, HistoryPage) {
+        let history = self.history.lock().await;
+        let rx = self.tx.subscribe();
+        (rx, history.page(&self.name, None, max_page_bytes))
+    }
+
+    pub async fn history_page(&self, before: Option<u64>, max_page_bytes: usize) -> HistoryPage {
+        self.history
+            .lock()
+            .await
+            .page(&self.name, before, max_page_bytes)
+    }
+
+    /// Memos with sequence numbers in the range that are still kept in history, oldest first.
+    /// Each with the session it was sent from, if one did.
+    pub async fn memos_between(
+        &self,
+        first_seq: u64,
+        last_seq: u64,
+    ) -> Vec<(ChatMemo, Option<SessionId>)> {
+        self.history.lock().await.between(first_seq, last_seq)
+    }
+
+    /// All memos kept in history, oldest first.
+    pub async fn history_memos(&self) -> Vec<ChatMemo> {
+        self.history.lock().await.memos.iter().cloned().collect()
+    }
+}
+
+/// Bounded backlog of broadcast memos, each at the position it was recorded at,
+/// which is one less than its sequence number.
+struct History {
+    limit: usize,
+    /// Sequence number of the last memo recorded.
+    recorded: u64,
+    memos: VecDeque<ChatMemo>,
+    /// Session each memo was sent from, in step with the memos and never sent to clients.
+    origins: VecDeque<Option<SessionId>>,
+}
+
+impl History {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            recorded: 0,
+            memos: VecDeque::with_capacity(limit),
+            origins: VecDeque::with_capacity(limit),
+        }
+    }
+
+    fn record(&mut self, memo: ChatMemo, origin: Option<SessionId>) {
+        // memos persisted before stamps carry on from the one before
+        let seq = memo.stamp.map_or(self.recorded + 1, |stamp| stamp.seq);
+        if seq != self.recorded + 1 {
+            // positions are only contiguous from here on
+            self.memos.clear();
+            self.origins.clear();
+        }
+        self.recorded = seq;
+        if self.limit == 0 {
+            return;
+        }
+        if self.memos.len() == self.limit {
+            self.memos.pop_front();
+            self.origins.pop_front();
+        }
+        self.memos.push_back(memo);
+        self.origins.push_back(origin);
+    }
+
+    fn between(&self, first_seq: u64, last_seq: u64) -> Vec<(ChatMemo, Option<SessionId>)> {
+        let oldest = self.recorded - self.memos.len() as u64;
+        let start = first_seq.saturating_sub(1).max(oldest);
+        let end = last_seq.min(self.recorded);
+        if start >= end {
+            return Vec::new();
+        }
+        let range = (start - oldest) as usize..(end - oldest) as usize;
+        self.memos
+            .range(range.clone())
+            .cloned()
+            .zip(self.origins.range(range).copied())
+            .collect()
+    }
+
+    /// Up to a page of memos recorded before the cursor position, as many as fit in `max_bytes`
+    /// of JSON, the largest of the encodings, though always at least one.
+    fn page(&self, room: &str, before: Option<u64>, max_bytes: usize) -> HistoryPage {
+        let oldest = self.recorded - self.memos.len() as u64;
+        let end = before.unwrap_or(self.recorded).clamp(oldest, self.recorded);
+        let mut page = HistoryPage {
+            room: room.to_string(),
+            memos: Vec::new(),
+            before: Some(end),
+        };
+        let mut bytes = encoded_len(&ChatResponse::History(page.clone()));
+        let mut start = end;
+        while start > oldest && end - start < HISTORY_PAGE_SIZE as u64 {
+            let memo = &self.memos[(start - 1 - oldest) as usize];
+            // and a comma
+            let memo_bytes = encoded_len(memo) + 1;
+            if start < end && bytes + memo_bytes > max_bytes {
+                break;
+            }
+            bytes += memo_bytes;
+            start -= 1;
+        }
+        page.memos = self
+            .memos
+            .range((start - oldest) as usize..(end - oldest) as usize)
+            .cloned()
+            .collect();
+        page.before = (start > oldest).then_some(start);
+        page
+    }
+}
+
+fn encoded_len<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map_or(0, |json| json.len())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memo(content: String) -> ChatMemo {
+        ChatMemo {
+            room: "rust".to_string(),
+            username: "carl".to_string(),
+            content,
+            stamp: None,
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut history = History::new(3);
+        for i in 0..5 {
+            history.record(memo(i.to_string()), None);
+        }
+        let page = history.page("rust", None, usize::MAX);
+        let contents: Vec<_> = page.memos.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["2", "3", "4"]);
+        assert_eq!(page.before, None);
+    }
+
+    #[test]
+    fn test_history_pages() {
+        let mut history = History::new(100);
+        for i in 0..(HISTORY_PAGE_SIZE * 2 + 5) {
+            history.record(memo(i.to_string()), None);
+        }
+
+        let latest = history.page("rust", None, usize::MAX);
+        assert_eq!(latest.memos.len(), HISTORY_PAGE_SIZE);
+        assert_eq!(latest.memos[0].content, "25");
+        assert_eq!(latest.before, Some(25));
+
+        let older = history.page("rust", latest.before, usize::MAX);
+        assert_eq!(older.memos.len(), HISTORY_PAGE_SIZE);
+        assert_eq!(older.memos[0].content, "5");
+        assert_eq!(older.memos[HISTORY_PAGE_SIZE - 1].content, "24");
+
+        let oldest = history.page("rust", older.before, usize::MAX);
+        assert_eq!(oldest.memos.len(), 5);
+        assert_eq!(oldest.memos[0].content, "0");
+        assert_eq!(oldest.before, None);
+    }
+
+    #[test]
+    fn test_history_pages_fit_in_bytes() {
+        let mut history = History::new(100);
+        for i in 0..10 {
+            history.record(memo(format!("{}{}", i, "x".repeat(99))), None);
+        }
+        let memo_bytes = encoded_len(&history.memos[0]) + 1;
+        let empty_bytes = encoded_len(&ChatResponse::History(HistoryPage {
+            room: "rust".to_string(),
+            memos: Vec::new(),
+            before: Some(10),
+        }));
+
+        let max_bytes = empty_bytes + 3 * memo_bytes;
+        let latest = history.page("rust", None, max_bytes);
+        assert_eq!(latest.memos.len(), 3);
+        assert!(encoded_len(&ChatResponse::History(latest.clone())) <= max_bytes);
+        assert!(latest.memos[0].content.starts_with('7'));
+        assert_eq!(latest.before, Some(7));
+        let older = history.page("rust", latest.before, max_bytes);
+        assert!(older.memos[2].content.starts_with('6'));
+
+        // one memo even if it does not fit, so paging goes on
+        let tiny = history.page("rust", None, 1);
+        assert_eq!(tiny.memos.len(), 1);
+        assert_eq!(tiny.before, Some(9));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_stamps_memos_in_sequence() {
+        let room = RoomState::new("rust", 10, 10);
+        let mut rx = room.tx.subscribe();
+        for i in 0..3 {
+            room.broadcast(ChatResponse::Broadcast(memo(i.to_string())))
+                .await
+                .unwrap();
+        }
+
+        let mut stamps = Vec::new();
+        for _ in 0..3 {
+            let ChatResponse::Broadcast(received) = rx.recv().await.unwrap().response().clone()
+            else {
+                panic!("expected a broadcast");
+            };
+            stamps.push(received.stamp.unwrap());
+        }
+        let seqs: Vec<_> = stamps.iter().map(|stamp| stamp.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert_ne!(stamps[0].id, stamps[1].id);
+        assert!(stamps[0].timestamp_ms <= stamps[2].timestamp_ms);
+        let kept = room.history_memos().await;
+        assert_eq!(kept[2].stamp, Some(stamps[2]));
+    }
+
+    #[tokio::test]
+    async fn test_restored_history_continues_sequence() {
+        let mut restored = memo("before restart".to_string());
+        restored.stamp = Some(Stamp {
+            id: Uuid::now_v7(),
+            timestamp_ms: 0,
+            seq: 41,
+        });
+        let room = RoomState::new("rust", 10, 10).with_history(vec![restored]);
+        let mut rx = room.tx.subscribe();
+        room.broadcast(ChatResponse::Broadcast(memo("after".to_string())))
+            .await
+            .unwrap();
+        let ChatResponse::Broadcast(received) = rx.recv().await.unwrap().response().clone() else {
+            panic!("expected a broadcast");
+        };
+        assert_eq!(received.stamp.unwrap().seq, 42);
+    }
+
+    #[test]
+    fn test_history_gap_starts_over() {
+        let mut history = History::new(10);
+        history.record(memo("1".to_string()), None);
+        let mut after_gap = memo("5".to_string());
+        after_gap.stamp = Some(Stamp {
+            id: Uuid::now_v7(),
+            timestamp_ms: 0,
+            seq: 5,
+        });
+        history.record(after_gap, None);
+        let page = history.page("rust", None, usize::MAX);
+        let contents: Vec<_> = page.memos.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["5"]);
+        assert_eq!(page.before, None);
+    }
+
+    #[test]
+    fn test_history_between_sequences() {
+        let mut history = History::new(5);
+        for i in 1..=8 {
+            history.record(memo(i.to_string()), None);
+        }
+        let contents = |memos: Vec<(ChatMemo, Option<SessionId>)>| -> Vec<String> {
+            memos.into_iter().map(|(m, _)| m.content).collect()
+        };
+        assert_eq!(contents(history.between(5, 6)), vec!["5", "6"]);
+        // 1 to 3 are no longer kept
+        assert_eq!(contents(history.between(2, 5)), vec!["4", "5"]);
+        assert_eq!(contents(history.between(7, 20)), vec!["7", "8"]);
+        assert!(history.between(1, 3).is_empty());
+        assert!(history.between(9, 10).is_empty());
+    }
+
+    #[test]
+    fn test_history_disabled() {
+        let mut history = History::new(0);
+        history.record(memo("lost".to_string()), None);
+        let page = history.page("rust", None, usize::MAX);
+        assert!(page.memos.is_empty());
+        assert_eq!(page.before, None);
+    }
 }
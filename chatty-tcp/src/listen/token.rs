@@ -0,0 +1,285 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chatty_types::command::BearerToken;
+use chatty_types::response::SessionToken;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+/// Shortest secret tokens are signed with, as many bytes as the HMAC-SHA256 output.
+pub const MIN_SECRET_BYTES: usize = 32;
+
+/// Role allowed to revoke the session tokens of other users.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// What a session token says about its holder, signed so clients cannot change it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Claims {
+    /// Username of the account the token was issued to.
+    pub sub: String,
+    pub roles: Vec<String>,
+    /// Seconds since the unix epoch when issued and when expiring.
+    pub iat: u64,
+    pub exp: u64,
+    /// Id of the token, which is what a revocation denies.
+    pub jti: Uuid,
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("Session token is malformed")]
+    Malformed,
+
+    #[error("Session token has an invalid signature")]
+    BadSignature,
+
+    #[error("Session token expired")]
+    Expired,
+
+    #[error("Session token was revoked")]
+    Revoked,
+}
+
+/// Issues and checks session tokens, the base64 of the claims in JSON and of their HMAC-SHA256
+/// joined by a dot. Nothing is kept of issued tokens, only the ids of revoked ones until they expire.
+pub struct TokenSigner {
+    key: hmac::Key,
+    ttl: Duration,
+    denylist: Denylist,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            ttl,
+            denylist: Denylist::default(),
+        }
+    }
+
+    /// Ids of revoked tokens, kept in a file so revocations survive restarts.
+    pub fn with_denylist(mut self, denylist: Denylist) -> Self {
+        self.denylist = denylist;
+        self
+    }
+
+    pub fn issue(&self, username: &str, roles: Vec<String>) -> SessionToken {
+        self.issue_at(username, roles, unix_secs())
+    }
+
+    fn issue_at(&self, username: &str, roles: Vec<String>, now: u64) -> SessionToken {
+        let claims = Claims {
+            sub: username.to_string(),
+            roles,
+            iat: now,
+            exp: now.saturating_add(self.ttl.as_secs()),
+            jti: Uuid::now_v7(),
+        };
+        let payload =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize to JSON"));
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, payload.as_bytes()));
+        SessionToken {
+            token: BearerToken(format!("{}.{}", payload, signature)),
+            username: claims.sub,
+            roles: claims.roles,
+            expires_at_ms: claims.exp.saturating_mul(1000),
+        }
+    }
+
+    /// Claims of a token signed with this server's secret that is neither expired nor revoked.
+    pub fn verify(&self, token: &BearerToken) -> Result<Claims, TokenError> {
+        self.verify_at(token, unix_secs())
+    }
+
+    fn verify_at(&self, token: &BearerToken, now: u64) -> Result<Claims, TokenError> {
+        let claims = self.signed_claims(token)?;
+        if claims.exp <= now {
+            return Err(TokenError::Expired);
+        }
+        if self.denylist.contains(&claims.jti) {
+            return Err(TokenError::Revoked);
+        }
+        Ok(claims)
+    }
+
+    /// Claims of a token signed with this server's secret, expired or revoked or not.
+    pub fn signed_claims(&self, token: &BearerToken) -> Result<Claims, TokenError> {
+        let (payload, signature) = token.0.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        // checked before the claims are even parsed, in constant time
+        hmac::verify(&self.key, payload.as_bytes(), &signature)
+            .map_err(|_| TokenError::BadSignature)?;
+        let claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| TokenError::Malformed)?;
+        serde_json::from_slice(&claims).map_err(|_| TokenError::Malformed)
+    }
+
+    /// Denies the token from now on, nothing to do for one already expired.
+    pub fn revoke(&self, claims: &Claims) -> io::Result<()> {
+        self.denylist.deny(claims.jti, claims.exp, unix_secs())
+    }
+}
+
+/// Ids of revoked tokens with when they expire, forgotten once they have,
+/// as the signer refuses expired tokens anyway.
+#[derive(Debug, Default)]
+pub struct Denylist {
+    path: Option<PathBuf>,
+    revoked: Mutex<HashMap<Uuid, u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Revoked {
+    jti: Uuid,
+    exp: u64,
+}
+
+impl Denylist {
+    /// Opens the denylist file, which is created on the first revocation if missing.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let revoked: Vec<Revoked> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        info!(
+            "Loaded {} revoked tokens from {}",
+            revoked.len(),
+            path.display()
+        );
+        let revoked = revoked.into_iter().map(|r| (r.jti, r.exp)).collect();
+        Ok(Self {
+            path: Some(path),
+            revoked: Mutex::new(revoked),
+        })
+    }
+
+    fn contains(&self, jti: &Uuid) -> bool {
+        let revoked = self.revoked.lock().expect("denylist lock poisoned");
+        revoked.contains_key(jti)
+    }
+
+    fn deny(&self, jti: Uuid, exp: u64, now: u64) -> io::Result<()> {
+        let mut revoked = self.revoked.lock().expect("denylist lock poisoned");
+        revoked.retain(|_, exp| *exp > now);
+        if exp <= now {
+            return Ok(());
+        }
+        revoked.insert(jti, exp);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut sorted: Vec<Revoked> = revoked
+            .iter()
+            .map(|(&jti, &exp)| Revoked { jti, exp })
+            .collect();
+        sorted.sort_by_key(|r| r.jti);
+        // same as the accounts file, a crash mid-write never leaves a torn file behind
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&sorted)?)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+/// Random ticket for resuming a session, which is only ever compared with those handed out.
+pub fn resume_ticket() -> BearerToken {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    BearerToken(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_tokens_are_signed_and_expire() {
+        let signer = TokenSigner::new(SECRET, DAY);
+        let issued = signer.issue_at("carl", vec![ADMIN_ROLE.to_string()], 1_000);
+        assert_eq!(issued.expires_at_ms, (1_000 + DAY.as_secs()) * 1000);
+
+        let claims = signer.verify_at(&issued.token, 1_001).unwrap();
+        assert_eq!(claims.sub, "carl");
+        assert!(claims.has_role(ADMIN_ROLE));
+        assert_eq!(
+            signer.verify_at(&issued.token, claims.exp),
+            Err(TokenError::Expired)
+        );
+
+        // signed with another secret
+        let other = TokenSigner::new(b"another secret of thirty-two bytes", DAY);
+        assert_eq!(
+            other.verify_at(&issued.token, 1_001),
+            Err(TokenError::BadSignature)
+        );
+
+        // claims changed by the client
+        let (_, signature) = issued.token.0.split_once('.').unwrap();
+        let forged = Claims {
+            sub: "david".to_string(),
+            ..claims
+        };
+        let forged = BearerToken(format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap()),
+            signature
+        ));
+        assert_eq!(
+            signer.verify_at(&forged, 1_001),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify_at(&BearerToken("garbage".to_string()), 1_001),
+            Err(TokenError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_revocations_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("revoked.json");
+        let signer =
+            TokenSigner::new(SECRET, DAY).with_denylist(Denylist::open(path.clone()).unwrap());
+        let revoked = signer.issue("carl", Vec::new());
+        let kept = signer.issue("carl", Vec::new());
+        let claims = signer.verify(&revoked.token).unwrap();
+        signer.revoke(&claims).unwrap();
+        assert_eq!(signer.verify(&revoked.token), Err(TokenError::Revoked));
+        drop(signer);
+
+        let signer = TokenSigner::new(SECRET, DAY).with_denylist(Denylist::open(path).unwrap());
+        assert_eq!(signer.verify(&revoked.token), Err(TokenError::Revoked));
+        assert!(signer.verify(&kept.token).is_ok());
+        // still a valid signature, only denied
+        assert_eq!(signer.signed_claims(&revoked.token), Ok(claims));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::handler::ChatHandler;
+use chatty_types::codec::Framing;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// Time a client gets for the TLS handshake, so connections that never start it do not linger.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key the server presents, both PEM files,
+/// and whether clients have to present one of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerTlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// Client certificates the server accepts, which name the user the client joins as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuthConfig {
+    /// PEM file of the certificate authorities issuing client certificates.
+    pub ca_path: PathBuf,
+    /// Whether clients without a certificate are refused, otherwise they join under any free username
+    /// but those in the usernames file.
+    pub mandatory: bool,
+    /// File of certificate names with the username each stands for, one pair per line.
+    pub usernames_path: Option<PathBuf>,
+}
+
+/// Certificate chain and private key a client presents to a server asking for one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Accepts TLS connections and tells the username of clients with a certificate.
+#[derive(Clone)]
+pub struct Acceptor {
+    acceptor: TlsAcceptor,
+    usernames: Arc<HashMap<String, String>>,
+}
+
+impl Acceptor {
+    /// Usernames the certificate names in the usernames file stand for.
+    pub fn usernames(&self) -> HashSet<String> {
+        self.usernames.values().cloned().collect()
+    }
+}
+
+/// How the client checks the server's certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ServerVerification {
+    /// Against the well known public certificate authorities.
+    #[default]
+    PublicRoots,
+    /// Against the certificate authorities in a PEM file, such as a self-signed certificate.
+    CustomCa(PathBuf),
+    /// Not at all, for development against a server with a throwaway certificate.
+    Insecure,
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("{}: no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(invalid_data)?;
+    }
+    Ok(roots)
+}
+
+/// Reads lines of a certificate name followed by the username it stands for,
+/// skipping empty lines and those starting with `#`.
+fn load_usernames(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut usernames = HashMap::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some(name), Some(username), None) => {
+                usernames.insert(name.to_string(), username.to_string());
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "{}:{}: expected a certificate name and a username",
+                    path.display(),
+                    number + 1
+                )))
+            }
+        }
+    }
+    Ok(usernames)
+}
+
+/// Names a certificate is for: the common names of its subject, then the DNS names
+/// and email addresses among its subject alternative names.
+fn certificate_names(cert: &CertificateDer<'_>) -> io::Result<Vec<String>> {
+    let (_, cert) = X509Certificate::from_der(cert).map_err(invalid_data)?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|name| name.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(alternatives)) = cert.subject_alternative_name() {
+        for name in &alternatives.value.general_names {
+            if let GeneralName::DNSName(name) | GeneralName::RFC822Name(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Username of the client with the certificate: what the first of its names listed
+/// in the usernames file stands for, otherwise its first name as it is.
+fn certified_username(
+    cert: &CertificateDer<'_>,
+    usernames: &HashMap<String, String>,
+) -> io::Result<String> {
+    let names = certificate_names(cert)?;
+    names
+        .iter()
+        .find_map(|name| usernames.get(name))
+        .or(names.first())
+        .cloned()
+        .ok_or_else(|| invalid_data("Client certificate names no user"))
+}
+
+/// Acceptor wrapping accepted connections in TLS with the configured certificate,
+/// asking clients for theirs if configured.
+pub fn acceptor(config: &ServerTlsConfig) -> io::Result<Acceptor> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let mut usernames = HashMap::new();
+    let builder = match &config.client_auth {
+        Some(client_auth) => {
+            let roots = Arc::new(load_roots(&client_auth.ca_path)?);
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider());
+            let verifier = if client_auth.mandatory {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            };
+            if let Some(usernames_path) = &client_auth.usernames_path {
+                usernames = load_usernames(usernames_path)?;
+            }
+            builder.with_client_cert_verifier(verifier.map_err(invalid_data)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(load_certs(&config.cert_path)?, load_key(&config.key_path)?)
+        .map_err(invalid_data)?;
+    Ok(Acceptor {
+        acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        usernames: Arc::new(usernames),
+    })
+}
+
+/// Connector wrapping the connection to the server in TLS, checking its certificate as configured
+/// and presenting the client's own certificate if there is one.
+pub fn connector(
+    verification: &ServerVerification,
+    certificate: Option<&ClientCertificate>,
+) -> io::Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let builder = match verification {
+        ServerVerification::PublicRoots => builder.with_root_certificates(
+            RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        ),
+        ServerVerification::CustomCa(ca_path) => {
+            builder.with_root_certificates(load_roots(ca_path)?)
+        }
+        ServerVerification::Insecure => {
+            warn!("Not verifying the certificate of the chat server");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider())))
+        }
+    };
+    let client_config = match certificate {
+        Some(certificate) => builder
+            .with_client_auth_cert(
+                load_certs(&certificate.cert_path)?,
+                load_key(&certificate.key_path)?,
+            )
+            .map_err(invalid_data)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Handler for an accepted connection once the TLS handshake with the client is done,
+/// bound to the username of the client's certificate if it presented one.
+pub async fn accept(
+    acceptor: &Acceptor,
+    stream: TcpStream,
+    framing: Framing,
+) -> io::Result<ChatHandler> {
+    let peer_addr = stream.peer_addr()?;
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    let certified = match stream.get_ref().1.peer_certificates() {
+        Some([cert, ..]) => Some(certified_username(cert, &acceptor.usernames)?),
+        _ => None,
+    };
+    if let Some(username) = &certified {
+        debug!("Client {} has a certificate for {}", peer_addr, username);
+    }
+    Ok(ChatHandler::from_stream(stream, peer_addr, framing).with_certified(certified))
+}
+
+/// Handler for the connection to the server once the TLS handshake with it is done,
+/// the server's certificate has to be for `server_name`.
+pub async fn connect(
+    connector: &TlsConnector,
+    server_name: &str,
+    stream: TcpStream,
+    framing: Framing,
+) -> io::Result<ChatHandler> {
+    let peer_addr = stream.peer_addr()?;
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid_data)?;
+    let stream = connector.connect(server_name, stream).await?;
+    Ok(ChatHandler::from_stream(stream, peer_addr, framing))
+}
+
+/// Takes whatever certificate the server presents, only still checking that the
+/// handshake was signed with its key.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    fn certificate(
+        common_name: Option<&str>,
+        alternatives: Vec<SanType>,
+    ) -> CertificateDer<'static> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(common_name) = common_name {
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+        }
+        params.subject_alt_names = alternatives;
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn test_username_from_certificate_names_and_overrides() {
+        let cert = certificate(
+            Some("carl"),
+            vec![
+                SanType::DnsName("carl.example.com".try_into().unwrap()),
+                SanType::Rfc822Name("carl@example.com".try_into().unwrap()),
+            ],
+        );
+        assert_eq!(
+            certificate_names(&cert).unwrap(),
+            ["carl", "carl.example.com", "carl@example.com"]
+        );
+        let mut usernames = HashMap::new();
+        assert_eq!(certified_username(&cert, &usernames).unwrap(), "carl");
+        usernames.insert("carl@example.com".to_string(), "carlos".to_string());
+        assert_eq!(certified_username(&cert, &usernames).unwrap(), "carlos");
+
+        // without a common name the alternative names are all there is
+        let cert = certificate(
+            None,
+            vec![SanType::DnsName("david.example.com".try_into().unwrap())],
+        );
+        assert_eq!(
+            certified_username(&cert, &HashMap::new()).unwrap(),
+            "david.example.com"
+        );
+        assert!(certified_username(&certificate(None, vec![]), &usernames).is_err());
+    }
+
+    #[test]
+    fn test_usernames_file_is_read_line_by_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usernames");
+        std::fs::write(
+            &path,
+            "# certificate name, username\n\ncarl@example.com carl\n  david.example.com   david \n",
+        )
+        .unwrap();
+        assert_eq!(
+            load_usernames(&path).unwrap(),
+            HashMap::from([
+                ("carl@example.com".to_string(), "carl".to_string()),
+                ("david.example.com".to_string(), "david".to_string()),
+            ])
+        );
+        std::fs::write(&path, "carl@example.com\n").unwrap();
+        let error = load_usernames(&path).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with(":1: expected a certificate name and a username"));
+    }
+}
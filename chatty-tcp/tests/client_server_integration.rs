@@ -1,20 +1,50 @@
+use chatty_tcp::connect::command::{Prompt, SignIn};
+use chatty_tcp::connect::prompt::run;
+use chatty_tcp::connect::reconnect::{ConnectionStatus, Connector, ReconnectConfig};
 use chatty_tcp::handler::ChatHandler;
+use chatty_tcp::listen::account::{
+    hash_password, Account, AccountStore, GuestNames, MemoryAccountStore,
+};
+use chatty_tcp::listen::connection::HeartbeatConfig;
+use chatty_tcp::listen::limit::{Rate, RateLimits};
+use chatty_tcp::listen::outbound::{OutboundConfig, SlowConsumerPolicy};
+use chatty_tcp::listen::persist::{FileLogConfig, FileMessageLog};
+use chatty_tcp::listen::registry::RoomRegistry;
 use chatty_tcp::listen::room::serve;
-use chatty_tcp::listen::state::RoomState;
+use chatty_tcp::listen::token::{TokenSigner, ADMIN_ROLE};
+use chatty_tcp::tls::{self, ServerTlsConfig, ServerVerification};
+use chatty_types::codec::{ClientCodec, Encoding, Framing, DEFAULT_MAX_FRAME_BYTES};
+use chatty_types::command::{BearerToken, ChatCommand, ChatMessage, Hello, Request, DEFAULT_ROOM};
 use chatty_types::config::setup_tracing;
 use chatty_types::config::Component::Server;
-use chatty_types::response::ChatResponse;
-use std::collections::HashMap;
+use chatty_types::limits::SizeLimits;
+use chatty_types::protocol::{Capability, PROTOCOL_VERSION};
+use chatty_types::response::{ChatMemo, ChatResponse, ErrorCode};
+use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
-use tokio::sync::{broadcast, Mutex};
 use tokio_test::{assert_err, assert_ok};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 use std::sync::Once;
 
 static INIT: Once = Once::new();
 
+/// Line of JSON responses without the stamps of broadcasts, which differ on every run.
+fn without_stamps(line: &str) -> String {
+    let mut rest = line;
+    let mut stripped = String::with_capacity(line.len());
+    while let Some(start) = rest.find(r#","stamp":{"#) {
+        stripped.push_str(&rest[..start]);
+        let end = rest[start..].find('}').expect("stamp object is closed");
+        rest = &rest[start + end + 1..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
 fn init_tracing_for_tests() {
     INIT.call_once(|| {
         setup_tracing(Server, "debug").unwrap();
@@ -24,23 +54,20 @@ fn init_tracing_for_tests() {
 #[tokio::test]
 async fn single_client() {
     init_tracing_for_tests();
-    // Set up room state
-    let (tx, _rx) = broadcast::channel::<ChatResponse>(100);
-    let task_handles = Mutex::new(HashMap::new());
-
-    let room_state = Arc::new(RoomState { tx, task_handles });
+    // Set up room registry
+    let registry = Arc::new(RoomRegistry::new(100, 100));
 
     // Start the server in a background task
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
     // Clone the Arc before moving it into the async block
-    let room_state_for_server = room_state.clone();
+    let registry_for_server = registry.clone();
     let server_handle = tokio::spawn(async move {
         loop {
             let (stream, _) = listener.accept().await.unwrap();
-            let state = room_state_for_server.clone();
+            let state = registry_for_server.clone();
             tokio::spawn(async move {
-                let handler = ChatHandler::new(stream);
+                let handler = ChatHandler::new(stream).unwrap();
                 serve(handler, state).await.unwrap();
             });
         }
@@ -77,8 +104,8 @@ async fn single_client() {
     assert_err!(result);
 
     // // Verify user is there
-    let room_state_for_removal = room_state.clone();
-    let lookup = room_state_for_removal.task_handles.lock().await;
+    let lobby = registry.room(DEFAULT_ROOM).await.unwrap();
+    let lookup = lobby.task_handles.lock().await;
     assert!(lookup.contains_key("alone"));
 
     // leave command
@@ -92,15 +119,11 @@ async fn single_client() {
 #[tokio::test]
 async fn multiple_clients() {
     init_tracing_for_tests();
-    let (tx, _rx) = broadcast::channel::<ChatResponse>(100);
+    // Set up room registry
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let state = registry.room(DEFAULT_ROOM).await.unwrap();
     // Create a separate subscriber for test verification
-    let test_tx = tx.clone();
-    let mut test_rx = test_tx.subscribe();
-    let task_handles = Mutex::new(HashMap::new());
-
-    // Set up room state
-    let room_state = Arc::new(RoomState { tx, task_handles });
-    let state = room_state.clone();
+    let mut test_rx = state.tx.subscribe();
 
     // Start the server in a background task
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -108,9 +131,9 @@ async fn multiple_clients() {
     let server_handle = tokio::spawn(async move {
         loop {
             let (stream, _) = listener.accept().await.unwrap();
-            let state = room_state.clone();
+            let state = registry.clone();
             tokio::spawn(async move {
-                let handler = ChatHandler::new(stream);
+                let handler = ChatHandler::new(stream).unwrap();
                 serve(handler, state).await.unwrap();
             });
         }
@@ -150,10 +173,16 @@ async fn multiple_clients() {
     let response2 = reader2.next_line().await.unwrap().unwrap();
     assert!(response2.contains("Warm Welcome"));
 
+    // The Second client gets what was said before joining
+    let history = reader2.next_line().await.unwrap().unwrap();
+    let expected_history = r#"{"History":{"room":"lobby","memos":[{"room":"lobby","username":"carl","content":"Joined"}],"before":null}}"#;
+    assert_eq!(without_stamps(&history), expected_history);
+
     // The First client reads the broadcast message
     let broadcast_message = reader1.next_line().await.unwrap().unwrap();
-    let expected_message = r#"{"Broadcast":{"username":"david","content":"Joined"}}"#;
-    assert_eq!(broadcast_message, expected_message);
+    let expected_message =
+        r#"{"Broadcast":{"room":"lobby","username":"david","content":"Joined"}}"#;
+    assert_eq!(without_stamps(&broadcast_message), expected_message);
 
     // First client sends a message
     let send_command = r#"{"Send":{"username":"carl","content":"Hello, world!"}}"#;
@@ -168,8 +197,9 @@ async fn multiple_clients() {
 
     // The Second client reads the broadcast message
     let broadcast_message = reader2.next_line().await.unwrap().unwrap();
-    let expected_message1 = r#"{"Broadcast":{"username":"carl","content":"Hello, world!"}}"#;
-    assert_eq!(broadcast_message, expected_message1);
+    let expected_message1 =
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Hello, world!"}}"#;
+    assert_eq!(without_stamps(&broadcast_message), expected_message1);
 
     // leave command from the first client
     let leave_command = r#"{"Leave":"carl"}"#;
@@ -181,8 +211,8 @@ async fn multiple_clients() {
 
     // The Second client reads the next broadcast message
     let broadcast_message = reader2.next_line().await.unwrap().unwrap();
-    let expected_message2 = r#"{"Broadcast":{"username":"carl","content":"Left"}}"#;
-    assert_eq!(broadcast_message, expected_message2);
+    let expected_message2 = r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#;
+    assert_eq!(without_stamps(&broadcast_message), expected_message2);
 
     let lookup = state.task_handles.lock().await;
     assert_eq!(lookup.len(), 1);
@@ -191,3 +221,2047 @@ async fn multiple_clients() {
     // Clean up
     server_handle.abort();
 }
+
+async fn start_server(registry: Arc<RoomRegistry>) -> std::net::SocketAddr {
+    start_server_with_framing(registry, Framing::Lines).await
+}
+
+async fn start_server_with_framing(
+    registry: Arc<RoomRegistry>,
+    framing: Framing,
+) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let state = registry.clone();
+            tokio::spawn(async move {
+                let handler = ChatHandler::with_framing(stream, framing).unwrap();
+                let _ = serve(handler, state).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn lobby_users(registry: &RoomRegistry) -> std::collections::HashSet<String> {
+    let lobby = registry.room(DEFAULT_ROOM).await.unwrap();
+    let lookup = lobby.task_handles.lock().await;
+    lookup.keys().cloned().collect()
+}
+
+async fn write_line(writer_half: &mut tokio::net::tcp::OwnedWriteHalf, line: &str) {
+    assert_ok!(writer_half.write_all(line.as_bytes()).await);
+    assert_ok!(writer_half.write_all(b"\n").await);
+}
+
+#[tokio::test]
+async fn dropped_connection_leaves_room_and_frees_username() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    let response1 = reader1.next_line().await.unwrap().unwrap();
+    assert!(response1.contains("Warm Welcome"));
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    let response2 = reader2.next_line().await.unwrap().unwrap();
+    assert!(response2.contains("Warm Welcome"));
+    assert_ok!(reader2.next_line().await);
+    let broadcast_message = reader1.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        without_stamps(&broadcast_message),
+        r#"{"Broadcast":{"room":"lobby","username":"david","content":"Joined"}}"#
+    );
+
+    // carl's laptop goes to sleep: socket is gone without any Leave command
+    drop(writer_half1);
+    drop(reader1);
+
+    let broadcast_message = reader2.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        without_stamps(&broadcast_message),
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#
+    );
+    assert!(!lobby_users(&registry).await.contains("carl"));
+
+    // the name can be used again
+    let (reader_half3, mut writer_half3) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader3 = BufReader::new(reader_half3).lines();
+    write_line(&mut writer_half3, r#"{"Join":"carl"}"#).await;
+    let response3 = reader3.next_line().await.unwrap().unwrap();
+    assert!(response3.contains("Warm Welcome"));
+}
+
+#[tokio::test]
+async fn malformed_commands_in_a_row_leave_room() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100).with_malformed_strikes(3));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    assert_ok!(reader2.next_line().await);
+
+    // a good command in between starts the count over
+    for line in [
+        "this is not json",
+        r#"{"Shout":"hi"}"#,
+        r#""ListRooms""#,
+        "this is not json",
+        "still not json",
+        "not json either",
+    ] {
+        write_line(&mut writer_half1, line).await;
+    }
+    let mut errors = 0;
+    while let Some(line) = reader1.next_line().await.unwrap() {
+        if line.starts_with(r#"{"Error":{"id":null,"code":"Malformed""#) {
+            errors += 1;
+        }
+    }
+    assert_eq!(errors, 5);
+
+    let broadcast_message = reader2.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        without_stamps(&broadcast_message),
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#
+    );
+    let lookup = lobby_users(&registry).await;
+    assert_eq!(lookup.len(), 1);
+    assert!(lookup.contains("david"));
+}
+
+#[tokio::test]
+async fn aborted_connection_task_leaves_room() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let lobby = registry.room(DEFAULT_ROOM).await.unwrap();
+    let mut test_rx = lobby.tx.subscribe();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = registry.clone();
+    let connection_handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let handler = ChatHandler::new(stream).unwrap();
+        let _ = serve(handler, state).await;
+    });
+
+    let (reader_half, mut writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = BufReader::new(reader_half).lines();
+    write_line(&mut writer_half, r#"{"Join":"carl"}"#).await;
+    assert!(reader
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    let joined = assert_ok!(test_rx.recv().await);
+    assert!(matches!(joined.response(), ChatResponse::Broadcast(memo) if memo.content == "Joined"));
+
+    connection_handle.abort();
+
+    let left = assert_ok!(test_rx.recv().await);
+    assert!(
+        matches!(left.response(), ChatResponse::Broadcast(memo) if memo.username == "carl" && memo.content == "Left")
+    );
+    assert!(lobby.task_handles.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn sender_identity_is_bound_to_connection() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    // sending before joining is not possible
+    write_line(&mut writer_half1, r#"{"Send":{"content":"Too early"}}"#).await;
+    let rejected = reader1.next_line().await.unwrap().unwrap();
+    assert!(rejected.starts_with(r#"{"Rejected":"#));
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    assert_ok!(reader2.next_line().await);
+    assert_ok!(reader1.next_line().await);
+
+    // carl pretending to be david is rejected
+    write_line(
+        &mut writer_half1,
+        r#"{"Send":{"username":"david","content":"I am david"}}"#,
+    )
+    .await;
+    let rejected = reader1.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        rejected,
+        r#"{"Rejected":{"room":"lobby","username":"david","content":"Joined as carl on this connection"}}"#
+    );
+
+    // carl cannot kick david out either
+    write_line(&mut writer_half1, r#"{"Leave":"david"}"#).await;
+    let rejected = reader1.next_line().await.unwrap().unwrap();
+    assert!(rejected.starts_with(r#"{"Rejected":"#));
+    assert!(lobby_users(&registry).await.contains("david"));
+
+    // a second join on the same connection is rejected
+    write_line(&mut writer_half1, r#"{"Join":"lucio"}"#).await;
+    let rejected = reader1.next_line().await.unwrap().unwrap();
+    assert!(rejected.starts_with(r#"{"Rejected":"#));
+    assert!(!lobby_users(&registry).await.contains("lucio"));
+
+    // without a username the message goes out as the joined user
+    write_line(&mut writer_half1, r#"{"Send":{"content":"Hello, david!"}}"#).await;
+    let broadcast_message = reader2.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        without_stamps(&broadcast_message),
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Hello, david!"}}"#
+    );
+
+    write_line(&mut writer_half1, r#"{"Leave":null}"#).await;
+    let broadcast_message = reader2.next_line().await.unwrap().unwrap();
+    assert_eq!(
+        without_stamps(&broadcast_message),
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#
+    );
+}
+
+#[tokio::test]
+async fn multiple_rooms() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert_eq!(
+        reader1.next_line().await.unwrap().unwrap(),
+        r#"{"Joined":{"room":"lobby","username":"carl","content":"Warm Welcome"}}"#
+    );
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    // welcome and history of the lobby
+    assert_ok!(reader2.next_line().await);
+    assert_ok!(reader2.next_line().await);
+    // david joining the lobby
+    assert_ok!(reader1.next_line().await);
+
+    // both join rust, carl also joins tokio
+    write_line(&mut writer_half1, r#"{"JoinRoom":"rust"}"#).await;
+    assert_eq!(
+        reader1.next_line().await.unwrap().unwrap(),
+        r#"{"Joined":{"room":"rust","username":"carl","content":"Warm Welcome"}}"#
+    );
+    write_line(&mut writer_half1, r#"{"JoinRoom":"tokio"}"#).await;
+    assert_ok!(reader1.next_line().await);
+    write_line(&mut writer_half2, r#"{"JoinRoom":"rust"}"#).await;
+    assert_ok!(reader2.next_line().await);
+    assert_eq!(
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+        r#"{"History":{"room":"rust","memos":[{"room":"rust","username":"carl","content":"Joined"}],"before":null}}"#
+    );
+    assert_eq!(
+        without_stamps(&reader1.next_line().await.unwrap().unwrap()),
+        r#"{"Broadcast":{"room":"rust","username":"david","content":"Joined"}}"#
+    );
+
+    write_line(&mut writer_half2, r#""ListRooms""#).await;
+    assert_eq!(
+        reader2.next_line().await.unwrap().unwrap(),
+        r#"{"Rooms":[{"name":"lobby","members":2},{"name":"rust","members":2},{"name":"tokio","members":1}]}"#
+    );
+
+    // david is not in tokio
+    write_line(
+        &mut writer_half2,
+        r#"{"Send":{"room":"tokio","content":"Anyone here?"}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader2.next_line().await.unwrap().unwrap(),
+        r#"{"Rejected":{"room":"tokio","username":"david","content":"Not in room tokio"}}"#
+    );
+
+    write_line(
+        &mut writer_half2,
+        r#"{"Send":{"room":"rust","content":"Hello, rustaceans!"}}"#,
+    )
+    .await;
+    assert_eq!(
+        without_stamps(&reader1.next_line().await.unwrap().unwrap()),
+        r#"{"Broadcast":{"room":"rust","username":"david","content":"Hello, rustaceans!"}}"#
+    );
+
+    // carl leaves tokio, the now empty room is gone
+    write_line(&mut writer_half1, r#"{"PartRoom":"tokio"}"#).await;
+    assert_eq!(
+        reader1.next_line().await.unwrap().unwrap(),
+        r#"{"Parted":{"room":"tokio","username":"carl","content":"Goodbye"}}"#
+    );
+    assert!(registry.room("tokio").await.is_none());
+
+    // carl dropping off leaves rust and the lobby
+    drop(writer_half1);
+    drop(reader1);
+    let mut left_rooms = vec![
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+    ];
+    left_rooms.sort();
+    assert_eq!(
+        left_rooms,
+        vec![
+            r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#,
+            r#"{"Broadcast":{"room":"rust","username":"carl","content":"Left"}}"#,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn direct_messages() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    for username in ["carl", "david", "lucio"] {
+        let (reader_half, mut writer_half) =
+            assert_ok!(TcpStream::connect(addr).await).into_split();
+        let mut reader = BufReader::new(reader_half).lines();
+        write_line(&mut writer_half, &format!(r#"{{"Join":"{}"}}"#, username)).await;
+        assert!(reader
+            .next_line()
+            .await
+            .unwrap()
+            .unwrap()
+            .contains("Warm Welcome"));
+        if username != "carl" {
+            // history of the earlier joins
+            assert_ok!(reader.next_line().await);
+        }
+        readers.push(reader);
+        writers.push(writer_half);
+    }
+    // joins of david and lucio in the lobby
+    assert_ok!(readers[0].next_line().await);
+    assert_ok!(readers[0].next_line().await);
+    assert_ok!(readers[1].next_line().await);
+
+    write_line(
+        &mut writers[0],
+        r#"{"Direct":{"to":"david","content":"Only for you"}}"#,
+    )
+    .await;
+    assert_eq!(
+        readers[1].next_line().await.unwrap().unwrap(),
+        r#"{"Direct":{"from":"carl","to":"david","content":"Only for you"}}"#
+    );
+    // lucio sees nothing
+    let result = tokio::time::timeout(
+        std::time::Duration::from_millis(100),
+        readers[2].next_line(),
+    )
+    .await;
+    assert_err!(result);
+
+    write_line(
+        &mut writers[0],
+        r#"{"Direct":{"to":"nobody","content":"Hello?"}}"#,
+    )
+    .await;
+    assert_eq!(
+        readers[0].next_line().await.unwrap().unwrap(),
+        r#"{"Undelivered":{"from":"carl","to":"nobody","content":"Hello?"}}"#
+    );
+}
+
+#[tokio::test]
+async fn history_replayed_on_join_and_paged() {
+    init_tracing_for_tests();
+    // more history than the rate limits let through quickly
+    let registry = Arc::new(RoomRegistry::new(100, 30).with_rate_limits(RateLimits::unlimited()));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    // nothing said yet so no history
+    for i in 0..35 {
+        write_line(
+            &mut writer_half1,
+            &format!(r#"{{"Send":{{"content":"message {}"}}}}"#, i),
+        )
+        .await;
+    }
+
+    let lobby = registry.room(DEFAULT_ROOM).await.unwrap();
+    let sent = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+        while lobby
+            .history_page(None, usize::MAX)
+            .await
+            .memos
+            .last()
+            .map(|m| m.content.as_str())
+            != Some("message 34")
+        {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await;
+    assert_ok!(sent);
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    // latest page right after the welcome
+    let history = reader2.next_line().await.unwrap().unwrap();
+    let ChatResponse::History(page) = assert_ok!(serde_json::from_str(&history)) else {
+        panic!("expected history after joining, got {}", history);
+    };
+    assert_eq!(page.memos.len(), 20);
+    assert_eq!(page.memos[0].content, "message 15");
+    assert_eq!(page.memos[19].content, "message 34");
+    let before = page.before.expect("older history to scroll back to");
+
+    // scrolling back to the oldest kept memos, only 30 are kept
+    // and david joining pushed out message 5 since the first page
+    write_line(
+        &mut writer_half2,
+        &format!(r#"{{"History":{{"room":"lobby","before":{}}}}}"#, before),
+    )
+    .await;
+    let history = reader2.next_line().await.unwrap().unwrap();
+    let ChatResponse::History(page) = assert_ok!(serde_json::from_str(&history)) else {
+        panic!("expected older history, got {}", history);
+    };
+    assert_eq!(page.memos.len(), 9);
+    assert_eq!(page.memos[0].content, "message 6");
+    assert_eq!(page.memos[8].content, "message 14");
+    assert_eq!(page.before, None);
+
+    // no history of rooms david is not in
+    write_line(&mut writer_half2, r#"{"History":{"room":"rust"}}"#).await;
+    let rejected = reader2.next_line().await.unwrap().unwrap();
+    assert!(rejected.starts_with(r#"{"Rejected":"#));
+}
+
+#[tokio::test]
+async fn history_of_full_size_memos_is_paged_to_fit_frames() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+    let max_content_chars = registry.size_limits().max_content_chars;
+    let lobby = registry.room(DEFAULT_ROOM).await.unwrap();
+    for i in 0..25 {
+        let content = format!("{:02}{}", i, "é".repeat(max_content_chars - 2));
+        // recorded though nobody is in the room to hear it yet
+        let _ = lobby
+            .broadcast(ChatResponse::Broadcast(ChatMemo {
+                room: DEFAULT_ROOM.to_string(),
+                username: "carl".to_string(),
+                content,
+                stamp: None,
+            }))
+            .await;
+    }
+
+    let (reader_half, mut writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = BufReader::new(reader_half).lines();
+    write_line(&mut writer_half, r#"{"Join":"david"}"#).await;
+    assert!(reader
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    // the latest page is cut to what fits in a frame
+    let history = reader.next_line().await.unwrap().unwrap();
+    assert!(history.len() <= DEFAULT_MAX_FRAME_BYTES);
+    let ChatResponse::History(page) = assert_ok!(serde_json::from_str(&history)) else {
+        panic!("expected history after joining, got {}", history);
+    };
+    assert!(!page.memos.is_empty() && page.memos.len() < 20);
+    assert!(page.memos.last().unwrap().content.starts_with("24"));
+
+    // the connection outlives the page, scrolling back gets every memo in pages fitting a frame
+    let mut received = page.memos.len();
+    let mut before = page.before;
+    while let Some(end) = before {
+        write_line(
+            &mut writer_half,
+            &format!(r#"{{"History":{{"room":"lobby","before":{}}}}}"#, end),
+        )
+        .await;
+        let history = reader.next_line().await.unwrap().unwrap();
+        assert!(history.len() <= DEFAULT_MAX_FRAME_BYTES);
+        let ChatResponse::History(page) = assert_ok!(serde_json::from_str(&history)) else {
+            panic!("expected older history, got {}", history);
+        };
+        received += page.memos.len();
+        before = page.before;
+    }
+    assert_eq!(received, 25);
+}
+
+fn persisted_registry(dir: &std::path::Path) -> Arc<RoomRegistry> {
+    let message_log = FileMessageLog::open(FileLogConfig {
+        dir: dir.to_path_buf(),
+        segment_bytes: 256,
+        retention: std::time::Duration::from_secs(60 * 60),
+    })
+    .unwrap();
+    Arc::new(
+        RoomRegistry::new(100, 100)
+            .with_message_log(Arc::new(message_log))
+            .unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn history_survives_server_restart() {
+    init_tracing_for_tests();
+    let dir = tempfile::tempdir().unwrap();
+
+    // first server run
+    let registry = persisted_registry(dir.path());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = registry.clone();
+    let server_handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let handler = ChatHandler::new(stream).unwrap();
+        let _ = serve(handler, state).await;
+    });
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert_ok!(reader1.next_line().await);
+    write_line(&mut writer_half1, r#"{"JoinRoom":"rust"}"#).await;
+    assert_ok!(reader1.next_line().await);
+    write_line(
+        &mut writer_half1,
+        r#"{"Send":{"content":"Before the restart"}}"#,
+    )
+    .await;
+    write_line(
+        &mut writer_half1,
+        r#"{"Send":{"room":"rust","content":"Rust talk"}}"#,
+    )
+    .await;
+    write_line(&mut writer_half1, r#"{"Leave":null}"#).await;
+    drop(writer_half1);
+    // connection is done once the server closes it after the leave
+    while let Ok(Some(_)) = reader1.next_line().await {}
+    assert_ok!(server_handle.await);
+    drop(registry);
+
+    // second server run on the same log
+    let registry = persisted_registry(dir.path());
+    let addr = start_server(registry.clone()).await;
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    assert_eq!(
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+        r#"{"History":{"room":"lobby","memos":[{"room":"lobby","username":"carl","content":"Joined"},{"room":"lobby","username":"carl","content":"Before the restart"},{"room":"lobby","username":"carl","content":"Left"}],"before":null}}"#
+    );
+
+    write_line(&mut writer_half2, r#"{"JoinRoom":"rust"}"#).await;
+    assert_ok!(reader2.next_line().await);
+    assert_eq!(
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+        r#"{"History":{"room":"rust","memos":[{"room":"rust","username":"carl","content":"Joined"},{"room":"rust","username":"carl","content":"Rust talk"},{"room":"rust","username":"carl","content":"Left"}],"before":null}}"#
+    );
+}
+
+#[tokio::test]
+async fn handshake_negotiates_capabilities() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    // a client from before the handshake gets everything, including history
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert_ok!(reader1.next_line().await);
+    write_line(
+        &mut writer_half1,
+        r#"{"Hello":{"protocol_version":1,"software":"late"}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader1.next_line().await.unwrap().unwrap(),
+        r#"{"Rejected":{"room":"lobby","username":"carl","content":"Hello is only accepted as the first command"}}"#
+    );
+
+    // without the History capability nothing is replayed on join
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(
+        &mut writer_half2,
+        r#"{"Hello":{"protocol_version":1,"software":"bot/0.1","capabilities":["Rooms","Compression"]}}"#,
+    )
+    .await;
+    let welcome = reader2.next_line().await.unwrap().unwrap();
+    let ChatResponse::Welcome(welcome) = assert_ok!(serde_json::from_str(&welcome)) else {
+        panic!("expected welcome, got {}", welcome);
+    };
+    assert_eq!(welcome.protocol_version, 1);
+    assert_eq!(
+        welcome.capabilities,
+        vec![chatty_types::protocol::Capability::Rooms]
+    );
+    write_line(&mut writer_half2, r#"{"Join":"bot"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    write_line(&mut writer_half1, r#"{"Send":{"content":"Hi bot"}}"#).await;
+    assert_eq!(
+        without_stamps(&reader2.next_line().await.unwrap().unwrap()),
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Hi bot"}}"#
+    );
+
+    // direct messages are only for clients that negotiated them, or never said Hello
+    write_line(
+        &mut writer_half2,
+        r#"{"Direct":{"to":"carl","content":"psst"}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader2.next_line().await.unwrap().unwrap(),
+        r#"{"Rejected":{"room":"lobby","username":"bot","content":"DirectMessages was not negotiated in the handshake"}}"#
+    );
+    write_line(
+        &mut writer_half1,
+        r#"{"Direct":{"to":"bot","content":"psst"}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader2.next_line().await.unwrap().unwrap(),
+        r#"{"Direct":{"from":"carl","to":"bot","content":"psst"}}"#
+    );
+
+    // an unknown protocol version is refused and the connection closed
+    let (reader_half3, mut writer_half3) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader3 = BufReader::new(reader_half3).lines();
+    write_line(
+        &mut writer_half3,
+        r#"{"Hello":{"protocol_version":99,"software":"future/9.0"}}"#,
+    )
+    .await;
+    let incompatible = reader3.next_line().await.unwrap().unwrap();
+    assert!(incompatible.starts_with(r#"{"Incompatible":{"min_version":1,"max_version":1,"#));
+    assert!(reader3.next_line().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn length_prefixed_framing() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server_with_framing(registry.clone(), Framing::LengthPrefixed).await;
+
+    let mut clients = Vec::new();
+    for username in ["carl", "david"] {
+        let (reader_half, writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+        let mut reader = FramedRead::new(reader_half, ClientCodec::new(Framing::LengthPrefixed));
+        let mut writer = FramedWrite::new(writer_half, ClientCodec::new(Framing::LengthPrefixed));
+        assert_ok!(writer.send(&ChatCommand::Join(username.to_string())).await);
+        let joined = assert_ok!(reader.next().await.unwrap());
+        assert!(matches!(joined, ChatResponse::Joined(_)));
+        clients.push((reader, writer));
+    }
+    let (mut reader2, _writer2) = clients.pop().unwrap();
+    let (_reader1, mut writer1) = clients.pop().unwrap();
+    // carl joining first broadcast to nobody, david was welcomed with carl's join in history
+    assert!(matches!(
+        assert_ok!(reader2.next().await.unwrap()),
+        ChatResponse::History(_)
+    ));
+
+    // newlines no longer delimit anything, so they can be part of the content
+    let send = ChatCommand::Send(ChatMessage {
+        username: None,
+        room: None,
+        content: "first line\nsecond line".to_string(),
+    });
+    assert_ok!(writer1.send(&send).await);
+    let ChatResponse::Broadcast(memo) = assert_ok!(reader2.next().await.unwrap()) else {
+        panic!("expected broadcast");
+    };
+    assert_eq!(memo.username, "carl");
+    assert_eq!(memo.content, "first line\nsecond line");
+
+    // a length prefix above the maximum ends the connection before the frame is read
+    let oversized = (DEFAULT_MAX_FRAME_BYTES as u32 + 1).to_be_bytes();
+    assert_ok!(writer1.get_mut().write_all(&oversized).await);
+    let ChatResponse::Broadcast(memo) = assert_ok!(reader2.next().await.unwrap()) else {
+        panic!("expected broadcast");
+    };
+    assert_eq!(memo.username, "carl");
+    assert_eq!(memo.content, "Left");
+    assert_eq!(lobby_users(&registry).await, ["david".to_string()].into());
+}
+
+type FramedClient = (
+    FramedRead<tokio::net::tcp::OwnedReadHalf, ClientCodec>,
+    FramedWrite<tokio::net::tcp::OwnedWriteHalf, ClientCodec>,
+);
+
+/// Connects with the handshake for the encoding and joins as the user,
+/// without history so the next response is whatever happens in the lobby.
+async fn join_with_encoding(
+    addr: std::net::SocketAddr,
+    encoding: Encoding,
+    username: &str,
+) -> FramedClient {
+    let (reader_half, writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = FramedRead::new(reader_half, ClientCodec::new(Framing::LengthPrefixed));
+    let mut writer = FramedWrite::new(writer_half, ClientCodec::new(Framing::LengthPrefixed));
+    let hello = ChatCommand::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        software: "test-client/1.0".to_string(),
+        capabilities: vec![Capability::Rooms],
+        encoding,
+    });
+    assert_ok!(writer.send(&hello).await);
+    writer.encoder_mut().set_encoding(encoding);
+    assert_ok!(writer.send(&ChatCommand::Join(username.to_string())).await);
+
+    let ChatResponse::Welcome(welcome) = assert_ok!(reader.next().await.unwrap()) else {
+        panic!("expected welcome");
+    };
+    assert_eq!(welcome.encoding, encoding);
+    reader.decoder_mut().set_encoding(encoding);
+    let joined = assert_ok!(reader.next().await.unwrap());
+    assert!(matches!(joined, ChatResponse::Joined(_)));
+    (reader, writer)
+}
+
+#[tokio::test]
+async fn mixed_encodings_in_one_room() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server_with_framing(registry.clone(), Framing::LengthPrefixed).await;
+
+    let (mut json_reader, mut json_writer) = join_with_encoding(addr, Encoding::Json, "carl").await;
+    let (mut msgpack_reader, mut msgpack_writer) =
+        join_with_encoding(addr, Encoding::MessagePack, "david").await;
+    let (mut cbor_reader, _cbor_writer) = join_with_encoding(addr, Encoding::Cbor, "erin").await;
+    // earlier joiners see the later ones arrive
+    for _ in 0..2 {
+        assert_ok!(json_reader.next().await.unwrap());
+    }
+    assert_ok!(msgpack_reader.next().await.unwrap());
+
+    let send = |content: &str| {
+        ChatCommand::Send(ChatMessage {
+            username: None,
+            room: None,
+            content: content.to_string(),
+        })
+    };
+    assert_ok!(json_writer.send(&send("from json")).await);
+    for reader in [&mut msgpack_reader, &mut cbor_reader] {
+        let ChatResponse::Broadcast(memo) = assert_ok!(reader.next().await.unwrap()) else {
+            panic!("expected broadcast");
+        };
+        assert_eq!(memo.username, "carl");
+        assert_eq!(memo.content, "from json");
+    }
+
+    assert_ok!(msgpack_writer.send(&send("from msgpack")).await);
+    for reader in [&mut json_reader, &mut cbor_reader] {
+        let ChatResponse::Broadcast(memo) = assert_ok!(reader.next().await.unwrap()) else {
+            panic!("expected broadcast");
+        };
+        assert_eq!(memo.username, "david");
+        assert_eq!(memo.content, "from msgpack");
+    }
+}
+
+#[tokio::test]
+async fn requests_are_acked_or_fail_with_error() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+    let (reader_half, mut writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = BufReader::new(reader_half).lines();
+
+    // before joining, as Rejected would be without the id
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":1,"command":{"Send":{"content":"too early"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Error":{"id":1,"code":"NotJoined","message":"Join before sending commands"}}"#
+    );
+
+    write_line(&mut writer_half, r#"{"Join":"carl"}"#).await;
+    assert!(reader
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    // the ack comes after the responses to the command
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":2,"command":"ListRooms"}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Rooms":[{"name":"lobby","members":1}]}"#
+    );
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Ack":{"id":2}}"#
+    );
+
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":3,"command":{"Direct":{"to":"nobody","content":"hello?"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Error":{"id":3,"code":"UserOffline","message":"nobody is not online"}}"#
+    );
+
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":4,"command":{"Send":{"room":"rust","content":"hi"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Error":{"id":4,"code":"NotInRoom","message":"Not in room rust"}}"#
+    );
+
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":5,"command":{"Send":{"content":"hi"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Ack":{"id":5}}"#
+    );
+
+    // a command that cannot be decoded, like one of a newer client, has no id to refer to
+    write_line(&mut writer_half, r#"{"Shout":"hi"}"#).await;
+    let error = reader.next_line().await.unwrap().unwrap();
+    let ChatResponse::Error(error) = assert_ok!(serde_json::from_str(&error)) else {
+        panic!("expected error, got {}", error);
+    };
+    assert_eq!(error.id, None);
+    assert_eq!(error.code, chatty_types::response::ErrorCode::Malformed);
+    assert!(error.message.contains("Shout"));
+
+    // and the connection goes on
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":6,"command":{"Send":{"content":"still here"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Ack":{"id":6}}"#
+    );
+}
+
+fn stamped_broadcast(line: &str) -> (String, String, u64) {
+    let ChatResponse::Broadcast(memo) = serde_json::from_str(line).unwrap() else {
+        panic!("expected broadcast, got {}", line);
+    };
+    (memo.username, memo.content, memo.stamp.unwrap().seq)
+}
+
+#[tokio::test]
+async fn broadcasts_are_stamped_in_sequence_and_echoed() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let addr = start_server(registry.clone()).await;
+
+    // carl asks for his own broadcasts back
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(
+        &mut writer_half1,
+        r#"{"Hello":{"protocol_version":1,"software":"test-client/1.0","capabilities":["Echo"]}}"#,
+    )
+    .await;
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    let welcome = reader1.next_line().await.unwrap().unwrap();
+    assert!(welcome.contains(r#""capabilities":["Echo"]"#));
+    let joined = reader1.next_line().await.unwrap().unwrap();
+    assert!(joined.contains("Warm Welcome"));
+    assert_eq!(
+        stamped_broadcast(&reader1.next_line().await.unwrap().unwrap()),
+        ("carl".to_string(), "Joined".to_string(), 1)
+    );
+
+    // david joins without a handshake, so without echo
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    let history = reader2.next_line().await.unwrap().unwrap();
+    assert!(history.contains(r#""seq":1"#));
+    assert_eq!(
+        stamped_broadcast(&reader1.next_line().await.unwrap().unwrap()),
+        ("david".to_string(), "Joined".to_string(), 2)
+    );
+
+    write_line(&mut writer_half1, r#"{"Send":{"content":"Hi david"}}"#).await;
+    write_line(&mut writer_half2, r#"{"Send":{"content":"Hi carl"}}"#).await;
+
+    // carl sees every seq of the room, his own message included
+    let mut seen_by_carl = [
+        stamped_broadcast(&reader1.next_line().await.unwrap().unwrap()),
+        stamped_broadcast(&reader1.next_line().await.unwrap().unwrap()),
+    ];
+    seen_by_carl.sort_by_key(|(_, _, seq)| *seq);
+    let seqs: Vec<_> = seen_by_carl.iter().map(|(_, _, seq)| *seq).collect();
+    assert_eq!(seqs, vec![3, 4]);
+    let mut said: Vec<_> = seen_by_carl
+        .iter()
+        .map(|(username, content, _)| (username.as_str(), content.as_str()))
+        .collect();
+    said.sort();
+    assert_eq!(said, vec![("carl", "Hi david"), ("david", "Hi carl")]);
+
+    // david only sees carl's message
+    let (username, content, seq) = stamped_broadcast(&reader2.next_line().await.unwrap().unwrap());
+    assert_eq!((username.as_str(), content.as_str()), ("carl", "Hi david"));
+    assert!(seq == 3 || seq == 4);
+    let nothing_more = reader2.next_line();
+    assert_err!(tokio::time::timeout(std::time::Duration::from_millis(100), nothing_more).await);
+}
+
+#[tokio::test]
+async fn slow_consumer_is_disconnected() {
+    init_tracing_for_tests();
+    let registry = Arc::new(
+        RoomRegistry::new(1000, 10)
+            .with_outbound_queue(OutboundConfig {
+                capacity: 4,
+                policy: SlowConsumerPolicy::Disconnect,
+            })
+            .with_rate_limits(RateLimits::unlimited())
+            .with_size_limits(SizeLimits {
+                max_content_chars: 64 * 1024,
+                ..SizeLimits::default()
+            }),
+    );
+    let addr = start_server(registry.clone()).await;
+
+    // david joins and then never reads
+    let (_reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    assert_eq!(
+        lobby_users(&registry).await,
+        ["carl".to_string(), "david".to_string()].into()
+    );
+
+    // carl talks until the socket buffers are full and david's queue overflows
+    let content = "x".repeat(32 * 1024);
+    let send = format!(r#"{{"Send":{{"content":"{}"}}}}"#, content);
+    let flood = tokio::spawn(async move {
+        for _ in 0..2000 {
+            if writer_half1.write_all(send.as_bytes()).await.is_err()
+                || writer_half1.write_all(b"\n").await.is_err()
+            {
+                break;
+            }
+        }
+        writer_half1
+    });
+
+    let disconnected = async {
+        while lobby_users(&registry).await.contains("david") {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    };
+    assert_ok!(tokio::time::timeout(std::time::Duration::from_secs(10), disconnected).await);
+    assert!(registry.user_sessions("david").await.is_empty());
+    assert!(lobby_users(&registry).await.contains("carl"));
+    flood.abort();
+}
+
+#[tokio::test]
+async fn quiet_connections_are_pinged_or_dropped() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 10).with_heartbeat(HeartbeatConfig {
+        interval: Duration::from_millis(50),
+        idle_timeout: Some(Duration::from_millis(300)),
+    }));
+    let addr = start_server(registry.clone()).await;
+
+    // carl's client answers pings
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(
+        &mut writer_half1,
+        r#"{"Hello":{"protocol_version":1,"software":"test","capabilities":["Heartbeat"]}}"#,
+    )
+    .await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .starts_with(r#"{"Welcome":"#));
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+    write_line(&mut writer_half1, r#"{"Ping":{"nonce":7}}"#).await;
+    assert_eq!(
+        reader1.next_line().await.unwrap().unwrap(),
+        r#"{"Pong":{"nonce":7}}"#
+    );
+
+    // david's is from before heartbeats and then goes silent, like a half-open connection
+    let (_reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+
+    let started = Instant::now();
+    let mut pings = 0;
+    let mut david_left = false;
+    while !david_left || started.elapsed() < Duration::from_millis(600) {
+        let line =
+            assert_ok!(tokio::time::timeout(Duration::from_secs(5), reader1.next_line()).await)
+                .unwrap()
+                .unwrap();
+        if let Some(ping) = line.strip_prefix(r#"{"Ping":"#) {
+            pings += 1;
+            write_line(&mut writer_half1, &format!(r#"{{"Pong":{}"#, ping)).await;
+        } else if without_stamps(&line)
+            == r#"{"Broadcast":{"room":"lobby","username":"david","content":"Left"}}"#
+        {
+            david_left = true;
+        }
+    }
+    assert!(pings >= 2);
+    assert_eq!(lobby_users(&registry).await, ["carl".to_string()].into());
+    assert!(registry.user_sessions("david").await.is_empty());
+}
+
+#[tokio::test]
+async fn flooding_client_is_throttled_without_lagging_others() {
+    init_tracing_for_tests();
+    // without limits the flood would overflow the broadcasts buffered for david
+    let registry = Arc::new(RoomRegistry::new(16, 10).with_rate_limits(RateLimits {
+        messages: Some(Rate {
+            per_sec: 20.0,
+            burst: 5,
+        }),
+        joins: None,
+        mute_after: 50,
+        mute_for: Duration::from_millis(200),
+        disconnect_after: 3,
+    }));
+    let addr = start_server(registry.clone()).await;
+
+    let (reader_half2, mut writer_half2) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader2 = BufReader::new(reader_half2).lines();
+    write_line(&mut writer_half2, r#"{"Join":"david"}"#).await;
+    assert!(reader2
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    let (reader_half1, mut writer_half1) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader1 = BufReader::new(reader_half1).lines();
+    write_line(&mut writer_half1, r#"{"Join":"carl"}"#).await;
+    assert!(reader1
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    // carl floods, reading everything he is sent so only the limiter holds him back
+    let carl = tokio::spawn(async move {
+        let flood = async {
+            for i in 0.. {
+                let send = format!(
+                    r#"{{"Request":{{"id":{},"command":{{"Send":{{"content":"spam {}"}}}}}}}}"#,
+                    i, i
+                );
+                if writer_half1.write_all(send.as_bytes()).await.is_err()
+                    || writer_half1.write_all(b"\n").await.is_err()
+                {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        let mut responses = Vec::new();
+        let read = async {
+            while let Ok(Some(line)) = reader1.next_line().await {
+                responses.push(line);
+            }
+        };
+        tokio::join!(flood, read);
+        responses
+    });
+
+    // david reads at a modest pace and sees carl go, without ever falling behind
+    let mut broadcasts = 0;
+    loop {
+        let line =
+            assert_ok!(tokio::time::timeout(Duration::from_secs(10), reader2.next_line()).await)
+                .unwrap()
+                .unwrap();
+        assert!(!line.starts_with(r#"{"Lagged":"#), "david lagged: {}", line);
+        if without_stamps(&line)
+            == r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#
+        {
+            break;
+        }
+        broadcasts += 1;
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    assert!(broadcasts >= 5);
+
+    let responses = assert_ok!(carl.await);
+    let throttled = responses
+        .iter()
+        .filter(|line| line.starts_with(r#"{"Throttled":"#))
+        .count();
+    assert!(throttled > broadcasts);
+    assert!(responses
+        .iter()
+        .any(|line| line.contains(r#""muted":true"#)));
+    assert!(responses.last().unwrap().contains(r#""code":"Flooding""#));
+    assert!(!lobby_users(&registry).await.contains("carl"));
+}
+
+#[tokio::test]
+async fn oversized_frames_and_content_are_refused() {
+    init_tracing_for_tests();
+    let registry = Arc::new(RoomRegistry::new(100, 10).with_size_limits(SizeLimits {
+        max_frame_bytes: 256,
+        max_content_chars: 10,
+        max_username_chars: 8,
+    }));
+    let addr = start_server(registry.clone()).await;
+    let (reader_half, mut writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = BufReader::new(reader_half).lines();
+
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":1,"command":{"Join":"carl_the_great"}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Error":{"id":1,"code":"TooLarge","message":"Username of 14 characters exceeds the maximum of 8"}}"#
+    );
+    write_line(&mut writer_half, r#"{"Join":"carl"}"#).await;
+    assert!(reader
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .contains("Warm Welcome"));
+
+    // too long content is refused, the connection stays usable
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":2,"command":{"Send":{"content":"hello world"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Error":{"id":2,"code":"TooLarge","message":"Message of 11 characters exceeds the maximum of 10"}}"#
+    );
+    write_line(
+        &mut writer_half,
+        r#"{"Request":{"id":3,"command":{"Send":{"content":"hello"}}}}"#,
+    )
+    .await;
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Ack":{"id":3}}"#
+    );
+
+    // a line that never ends is refused once over the frame size, closing the connection
+    assert_ok!(writer_half.write_all(&[b'x'; 300]).await);
+    assert!(reader
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .starts_with(r#"{"Error":{"id":null,"code":"TooLarge","message":"Frame of "#));
+    assert!(reader.next_line().await.unwrap().is_none());
+    assert!(lobby_users(&registry).await.is_empty());
+}
+
+async fn start_tls_server(
+    registry: Arc<RoomRegistry>,
+    acceptor: tls::Acceptor,
+) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let state = registry.clone();
+            tokio::spawn(async move {
+                if let Ok(handler) = tls::accept(&acceptor, stream, Framing::default()).await {
+                    let _ = serve(handler, state).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn tls_connections_chat_and_check_the_certificate() {
+    init_tracing_for_tests();
+    let dir = tempfile::tempdir().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let acceptor = assert_ok!(tls::acceptor(&ServerTlsConfig {
+        cert_path: cert_path.clone(),
+        key_path,
+        client_auth: None,
+    }));
+    let addr = start_tls_server(registry, acceptor).await;
+
+    let connect = |verification: ServerVerification| async move {
+        let connector = tls::connector(&verification, None).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        tls::connect(&connector, "localhost", stream, Framing::default()).await
+    };
+    let mut clients = Vec::new();
+    for (username, verification) in [
+        ("carl", ServerVerification::CustomCa(cert_path)),
+        ("david", ServerVerification::Insecure),
+    ] {
+        let handler = assert_ok!(connect(verification).await);
+        let mut reader = FramedRead::new(handler.reader_half, ClientCodec::new(handler.framing));
+        let mut writer = FramedWrite::new(handler.writer_half, ClientCodec::new(handler.framing));
+        assert_ok!(writer.send(&ChatCommand::Join(username.to_string())).await);
+        let joined = assert_ok!(reader.next().await.unwrap());
+        assert!(matches!(joined, ChatResponse::Joined(_)));
+        clients.push((reader, writer));
+    }
+    let (mut reader2, _writer2) = clients.pop().unwrap();
+    let (_reader1, mut writer1) = clients.pop().unwrap();
+    assert!(matches!(
+        assert_ok!(reader2.next().await.unwrap()),
+        ChatResponse::History(_)
+    ));
+    let send = ChatCommand::Send(ChatMessage {
+        username: None,
+        room: None,
+        content: "over tls".to_string(),
+    });
+    assert_ok!(writer1.send(&send).await);
+    let ChatResponse::Broadcast(memo) = assert_ok!(reader2.next().await.unwrap()) else {
+        panic!("expected broadcast");
+    };
+    assert_eq!(memo.username, "carl");
+    assert_eq!(memo.content, "over tls");
+
+    // a self-signed certificate is not trusted by the public authorities
+    assert!(connect(ServerVerification::PublicRoots).await.is_err());
+}
+
+/// Writes the certificate and its key as PEM files named after the certificate, returning their paths.
+fn write_certificate(
+    dir: &std::path::Path,
+    name: &str,
+    cert: &rcgen::Certificate,
+    key: &rcgen::KeyPair,
+) -> (std::path::PathBuf, std::path::PathBuf) {
+    let cert_path = dir.join(format!("{name}.pem"));
+    let key_path = dir.join(format!("{name}-key.pem"));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+/// Certificate issued by the CA for the subject common name and alternative names.
+fn issue_certificate(
+    common_name: &str,
+    alternatives: Vec<rcgen::SanType>,
+    usage: rcgen::ExtendedKeyUsagePurpose,
+    ca: &rcgen::Certificate,
+    ca_key: &rcgen::KeyPair,
+) -> (rcgen::Certificate, rcgen::KeyPair) {
+    let mut params = rcgen::CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, common_name);
+    params.subject_alt_names = alternatives;
+    params.extended_key_usages = vec![usage];
+    let key = rcgen::KeyPair::generate().unwrap();
+    (params.signed_by(&key, ca, ca_key).unwrap(), key)
+}
+
+#[tokio::test]
+async fn client_certificates_decide_the_username() {
+    use rcgen::{BasicConstraints, ExtendedKeyUsagePurpose, IsCa, SanType};
+    init_tracing_for_tests();
+    let dir = tempfile::tempdir().unwrap();
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::default();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let (ca_path, _) = write_certificate(dir.path(), "ca", &ca, &ca_key);
+
+    let (server, server_key) = issue_certificate(
+        "chat server",
+        vec![SanType::DnsName("localhost".try_into().unwrap())],
+        ExtendedKeyUsagePurpose::ServerAuth,
+        &ca,
+        &ca_key,
+    );
+    let (cert_path, key_path) = write_certificate(dir.path(), "server", &server, &server_key);
+    let mut clients = std::collections::HashMap::new();
+    for (name, common_name, alternatives) in [
+        ("carl", "carl", vec![]),
+        (
+            "david",
+            "David Doe",
+            vec![SanType::Rfc822Name("david@example.com".try_into().unwrap())],
+        ),
+    ] {
+        let (cert, key) = issue_certificate(
+            common_name,
+            alternatives,
+            ExtendedKeyUsagePurpose::ClientAuth,
+            &ca,
+            &ca_key,
+        );
+        let (cert_path, key_path) = write_certificate(dir.path(), name, &cert, &key);
+        clients.insert(
+            name,
+            tls::ClientCertificate {
+                cert_path,
+                key_path,
+            },
+        );
+    }
+    let usernames_path = dir.path().join("usernames");
+    std::fs::write(&usernames_path, "david@example.com david\n").unwrap();
+
+    let start = |mandatory: bool| {
+        let acceptor = tls::acceptor(&ServerTlsConfig {
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            client_auth: Some(tls::ClientAuthConfig {
+                ca_path: ca_path.clone(),
+                mandatory,
+                usernames_path: Some(usernames_path.clone()),
+            }),
+        })
+        .unwrap();
+        let registry = RoomRegistry::new(100, 100).with_certified_usernames(acceptor.usernames());
+        start_tls_server(Arc::new(registry), acceptor)
+    };
+    let join = |addr, certificate: Option<&tls::ClientCertificate>, username: &str| {
+        let connector =
+            tls::connector(&ServerVerification::CustomCa(ca_path.clone()), certificate).unwrap();
+        let join = ChatCommand::Request(Request {
+            id: 1,
+            command: Box::new(ChatCommand::Join(username.to_string())),
+        });
+        async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let handler = tls::connect(&connector, "localhost", stream, Framing::default()).await?;
+            let mut reader =
+                FramedRead::new(handler.reader_half, ClientCodec::new(handler.framing));
+            let mut writer =
+                FramedWrite::new(handler.writer_half, ClientCodec::new(handler.framing));
+            writer.send(&join).await?;
+            match reader.next().await {
+                Some(response) => Ok::<_, anyhow::Error>(response?),
+                None => Err(anyhow::anyhow!("connection closed")),
+            }
+        }
+    };
+    let error_code = |response: ChatResponse| match response {
+        ChatResponse::Error(error) => error.code,
+        other => panic!("expected error, got {:?}", other),
+    };
+
+    let addr = start(true).await;
+    // the certificate names the user, no one else
+    let refused = assert_ok!(join(addr, clients.get("carl"), "mallory").await);
+    assert_eq!(error_code(refused), ErrorCode::WrongIdentity);
+    let joined = assert_ok!(join(addr, clients.get("carl"), "carl").await);
+    assert!(matches!(joined, ChatResponse::Joined(_)));
+    // david's certificate is for his email address, mapped to his username
+    let refused = assert_ok!(join(addr, clients.get("david"), "David Doe").await);
+    assert_eq!(error_code(refused), ErrorCode::WrongIdentity);
+    let joined = assert_ok!(join(addr, clients.get("david"), "david").await);
+    assert!(matches!(joined, ChatResponse::Joined(_)));
+    // no certificate, no chat
+    assert!(join(addr, None, "erin").await.is_err());
+
+    let addr = start(false).await;
+    let joined = assert_ok!(join(addr, None, "erin").await);
+    assert!(matches!(joined, ChatResponse::Joined(_)));
+    let refused = assert_ok!(join(addr, clients.get("carl"), "mallory").await);
+    assert_eq!(error_code(refused), ErrorCode::WrongIdentity);
+    // david's username is kept for the certificate standing for it
+    let refused = assert_ok!(join(addr, None, "david").await);
+    assert_eq!(error_code(refused), ErrorCode::WrongIdentity);
+    let joined = assert_ok!(join(addr, clients.get("david"), "david").await);
+    assert!(matches!(joined, ChatResponse::Joined(_)));
+}
+
+type LineClient = (
+    tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    tokio::net::tcp::OwnedWriteHalf,
+);
+
+async fn connect_lines(addr: std::net::SocketAddr) -> LineClient {
+    let (reader_half, writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    (BufReader::new(reader_half).lines(), writer_half)
+}
+
+/// Sends the command as request 1 and reads the responses up to its Ack or Error, returning the last.
+async fn request(client: &mut LineClient, command: &str) -> String {
+    let (reader, writer) = client;
+    write_line(
+        writer,
+        &format!(r#"{{"Request":{{"id":1,"command":{}}}}}"#, command),
+    )
+    .await;
+    loop {
+        let line = reader.next_line().await.unwrap().unwrap();
+        if line.starts_with(r#"{"Ack":{"id":1"#) || line.starts_with(r#"{"Error":{"id":1"#) {
+            return line;
+        }
+    }
+}
+
+#[tokio::test]
+async fn registered_usernames_need_the_password() {
+    init_tracing_for_tests();
+    let registry = Arc::new(
+        RoomRegistry::new(100, 100)
+            .with_accounts(Arc::new(MemoryAccountStore::default()))
+            .with_guest_names(GuestNames::Prefixed("guest-".to_string())),
+    );
+    let addr = start_server(registry.clone()).await;
+    let error_code = |line: String| {
+        let response: ChatResponse = serde_json::from_str(&line).unwrap();
+        match response {
+            ChatResponse::Error(error) => Some(error.code),
+            _ => None,
+        }
+    };
+    let ok = r#"{"Ack":{"id":1}}"#;
+
+    let mut carl = connect_lines(addr).await;
+    let register =
+        |password: &str| format!(r#"{{"Register":{{"username":"carl","password":"{password}"}}}}"#);
+    assert_eq!(
+        error_code(request(&mut carl, &register("short")).await),
+        Some(ErrorCode::WeakPassword)
+    );
+    assert_eq!(
+        error_code(
+            request(
+                &mut carl,
+                r#"{"Register":{"username":"guest-carl","password":"long enough"}}"#
+            )
+            .await
+        ),
+        Some(ErrorCode::InvalidUsername)
+    );
+    assert_eq!(request(&mut carl, &register("long enough")).await, ok);
+    assert_eq!(lobby_users(&registry).await, ["carl".to_string()].into());
+
+    // guests can neither take the registered username nor one without the prefix
+    let mut guest = connect_lines(addr).await;
+    assert_eq!(
+        error_code(request(&mut guest, r#"{"Join":"carl"}"#).await),
+        Some(ErrorCode::Unauthorized)
+    );
+    assert_eq!(
+        error_code(request(&mut guest, r#"{"Join":"david"}"#).await),
+        Some(ErrorCode::InvalidUsername)
+    );
+    assert_eq!(request(&mut guest, r#"{"Join":"guest-david"}"#).await, ok);
+    assert_eq!(
+        error_code(
+            request(
+                &mut guest,
+                r#"{"ChangePassword":{"current":"","new":"long enough"}}"#
+            )
+            .await
+        ),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    let change = r#"{"ChangePassword":{"current":"long enough","new":"even longer"}}"#;
+    assert_eq!(request(&mut carl, change).await, ok);
+    assert_eq!(
+        error_code(request(&mut carl, change).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    let login =
+        |password: &str| format!(r#"{{"Login":{{"username":"carl","password":"{password}"}}}}"#);
+    let mut again = connect_lines(addr).await;
+    assert_eq!(
+        error_code(request(&mut again, &login("long enough")).await),
+        Some(ErrorCode::Unauthorized)
+    );
+    // online on the first connection too, from another device
+    assert_eq!(request(&mut again, &login("even longer")).await, ok);
+    drop(carl);
+
+    let mut late = connect_lines(addr).await;
+    assert_eq!(
+        error_code(request(&mut late, &register("long enough")).await),
+        Some(ErrorCode::UsernameTaken)
+    );
+}
+
+/// Asks for a session token as request 1, returning it as the JSON string sent to log in with it.
+async fn issue_token(client: &mut LineClient) -> String {
+    let (reader, writer) = client;
+    write_line(writer, r#"{"Request":{"id":1,"command":"IssueToken"}}"#).await;
+    let line = reader.next_line().await.unwrap().unwrap();
+    let response: ChatResponse = serde_json::from_str(&line).unwrap();
+    let ChatResponse::Token(token) = response else {
+        panic!("expected a token, got {}", line);
+    };
+    assert_eq!(
+        reader.next_line().await.unwrap().unwrap(),
+        r#"{"Ack":{"id":1}}"#
+    );
+    serde_json::to_string(&token.token).unwrap()
+}
+
+#[tokio::test]
+async fn session_tokens_log_in_until_revoked() {
+    init_tracing_for_tests();
+    let accounts = Arc::new(MemoryAccountStore::default());
+    assert!(assert_ok!(accounts.create(Account {
+        username: "david".to_string(),
+        password_hash: assert_ok!(hash_password("long enough")),
+        roles: vec![ADMIN_ROLE.to_string()],
+    })));
+    let signer = TokenSigner::new(b"0123456789abcdef0123456789abcdef", Duration::from_secs(60));
+    let registry = Arc::new(
+        RoomRegistry::new(100, 100)
+            .with_rate_limits(RateLimits::unlimited())
+            .with_accounts(accounts)
+            .with_tokens(Arc::new(signer)),
+    );
+    let addr = start_server(registry.clone()).await;
+    let error_code = |line: String| {
+        let response: ChatResponse = serde_json::from_str(&line).unwrap();
+        match response {
+            ChatResponse::Error(error) => Some(error.code),
+            _ => None,
+        }
+    };
+    let ok = r#"{"Ack":{"id":1}}"#;
+    let token_login = |token: &str| format!(r#"{{"TokenLogin":{}}}"#, token);
+    let revoke = |token: &str| format!(r#"{{"RevokeToken":{}}}"#, token);
+
+    let mut guest = connect_lines(addr).await;
+    assert_eq!(request(&mut guest, r#"{"Join":"erin"}"#).await, ok);
+    assert_eq!(
+        error_code(request(&mut guest, r#""IssueToken""#).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    let mut carl = connect_lines(addr).await;
+    assert_eq!(
+        request(
+            &mut carl,
+            r#"{"Register":{"username":"carl","password":"long enough"}}"#
+        )
+        .await,
+        ok
+    );
+    let token = issue_token(&mut carl).await;
+    let spare_token = issue_token(&mut carl).await;
+    drop(carl);
+    let mut left = false;
+    for _ in 0..50 {
+        if !lobby_users(&registry).await.contains("carl") {
+            left = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(left);
+
+    // back without the password
+    let mut again = connect_lines(addr).await;
+    assert_eq!(request(&mut again, &token_login(&token)).await, ok);
+    assert!(lobby_users(&registry).await.contains("carl"));
+    let mut forged = connect_lines(addr).await;
+    assert_eq!(
+        error_code(request(&mut forged, &token_login(r#""e30.c2lnbmF0dXJl""#)).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    // only admins revoke the tokens of others
+    let mut david = connect_lines(addr).await;
+    assert_eq!(
+        request(
+            &mut david,
+            r#"{"Login":{"username":"david","password":"long enough"}}"#
+        )
+        .await,
+        ok
+    );
+    let david_token = issue_token(&mut david).await;
+    assert_eq!(
+        error_code(request(&mut again, &revoke(&david_token)).await),
+        Some(ErrorCode::WrongIdentity)
+    );
+    assert_eq!(request(&mut david, &revoke(&spare_token)).await, ok);
+    assert_eq!(
+        error_code(request(&mut forged, &token_login(&spare_token)).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    // the token logged in with
+    assert_eq!(request(&mut again, r#"{"RevokeToken":null}"#).await, ok);
+    assert_eq!(request(&mut again, r#"{"Leave":null}"#).await, ok);
+    assert_eq!(
+        error_code(request(&mut forged, &token_login(&token)).await),
+        Some(ErrorCode::Unauthorized)
+    );
+}
+
+/// Connects with the handshake asking to resume sessions of dropped connections.
+async fn connect_resumable(addr: std::net::SocketAddr) -> FramedClient {
+    let (reader_half, writer_half) = assert_ok!(TcpStream::connect(addr).await).into_split();
+    let mut reader = FramedRead::new(reader_half, ClientCodec::new(Framing::LengthPrefixed));
+    let mut writer = FramedWrite::new(writer_half, ClientCodec::new(Framing::LengthPrefixed));
+    let hello = ChatCommand::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        software: "test-client/1.0".to_string(),
+        capabilities: vec![Capability::Rooms, Capability::Resume],
+        encoding: Encoding::Json,
+    });
+    assert_ok!(writer.send(&hello).await);
+    let welcome = assert_ok!(reader.next().await.unwrap());
+    assert!(matches!(welcome, ChatResponse::Welcome(_)));
+    (reader, writer)
+}
+
+async fn next_response(client: &mut FramedClient) -> ChatResponse {
+    assert_ok!(client.0.next().await.unwrap())
+}
+
+fn resume_request(ticket: &BearerToken) -> ChatCommand {
+    ChatCommand::Request(Request {
+        id: 1,
+        command: Box::new(ChatCommand::Resume(ticket.clone())),
+    })
+}
+
+#[tokio::test]
+async fn dropped_sessions_are_resumed_with_what_was_missed() {
+    init_tracing_for_tests();
+    let registry =
+        Arc::new(RoomRegistry::new(100, 100).with_resume_grace(Some(Duration::from_millis(500))));
+    let addr = start_server_with_framing(registry.clone(), Framing::LengthPrefixed).await;
+    let send = |content: &str| {
+        ChatCommand::Send(ChatMessage {
+            username: None,
+            room: None,
+            content: content.to_string(),
+        })
+    };
+
+    let mut carl = connect_resumable(addr).await;
+    assert_ok!(carl.1.send(&ChatCommand::Join("carl".to_string())).await);
+    assert!(matches!(
+        next_response(&mut carl).await,
+        ChatResponse::Joined(_)
+    ));
+    let ChatResponse::Resumable(resumable) = next_response(&mut carl).await else {
+        panic!("expected a resume ticket");
+    };
+    assert_eq!(resumable.grace_ms, 500);
+    // david cannot resume, so gets no ticket
+    let mut david = join_with_encoding(addr, Encoding::Json, "david").await;
+    assert!(matches!(
+        next_response(&mut carl).await,
+        ChatResponse::Broadcast(_)
+    ));
+
+    // carl's connection drops, david keeps talking once the server noticed
+    drop(carl);
+    let parked = tokio::time::timeout(Duration::from_secs(5), async {
+        while !registry.is_parked(&resumable.ticket).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    assert_ok!(parked);
+    assert_ok!(david.1.send(&send("while you were away")).await);
+
+    let mut carl = connect_resumable(addr).await;
+    assert_ok!(carl.1.send(&resume_request(&resumable.ticket)).await);
+    let ChatResponse::Resumed(resumed) = next_response(&mut carl).await else {
+        panic!("expected the session resumed");
+    };
+    assert_eq!(resumed.username, "carl");
+    assert_eq!(resumed.rooms, vec![DEFAULT_ROOM.to_string()]);
+    assert_eq!(resumed.held, 1);
+    let ChatResponse::Broadcast(memo) = next_response(&mut carl).await else {
+        panic!("expected the held broadcast");
+    };
+    assert_eq!(memo.content, "while you were away");
+    let ChatResponse::Resumable(renewed) = next_response(&mut carl).await else {
+        panic!("expected a new resume ticket");
+    };
+    assert_ne!(renewed.ticket, resumable.ticket);
+    assert!(matches!(
+        next_response(&mut carl).await,
+        ChatResponse::Ack(_)
+    ));
+
+    // david never saw carl leave or join again
+    assert_ok!(carl.1.send(&send("back")).await);
+    let ChatResponse::Broadcast(memo) = next_response(&mut david).await else {
+        panic!("expected broadcast");
+    };
+    assert_eq!(
+        (memo.username.as_str(), memo.content.as_str()),
+        ("carl", "back")
+    );
+
+    // resumed before the server noticed the connection is gone, which is then closed
+    let mut stale = carl;
+    let mut carl = connect_resumable(addr).await;
+    assert_ok!(carl.1.send(&resume_request(&renewed.ticket)).await);
+    let ChatResponse::Resumed(resumed) = next_response(&mut carl).await else {
+        panic!("expected the session handed over");
+    };
+    assert_eq!(resumed.held, 0);
+    assert!(matches!(
+        next_response(&mut carl).await,
+        ChatResponse::Resumable(_)
+    ));
+    assert!(matches!(
+        next_response(&mut carl).await,
+        ChatResponse::Ack(_)
+    ));
+    assert!(stale.0.next().await.is_none());
+
+    // tickets are good for one resume only
+    let mut other = connect_resumable(addr).await;
+    assert_ok!(other.1.send(&resume_request(&resumable.ticket)).await);
+    let ChatResponse::Error(error) = next_response(&mut other).await else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::UnknownSession);
+
+    // not back within the grace period
+    drop(carl);
+    let ChatResponse::Broadcast(memo) = next_response(&mut david).await else {
+        panic!("expected broadcast");
+    };
+    assert_eq!(
+        (memo.username.as_str(), memo.content.as_str()),
+        ("carl", "Left")
+    );
+    assert!(!lobby_users(&registry).await.contains("carl"));
+}
+
+async fn next_line(client: &mut LineClient) -> String {
+    without_stamps(&client.0.next_line().await.unwrap().unwrap())
+}
+
+#[tokio::test]
+async fn accounts_are_online_from_several_devices() {
+    init_tracing_for_tests();
+    let accounts = Arc::new(MemoryAccountStore::default());
+    assert!(assert_ok!(accounts.create(Account {
+        username: "carl".to_string(),
+        password_hash: assert_ok!(hash_password("long enough")),
+        roles: Vec::new(),
+    })));
+    let registry = Arc::new(RoomRegistry::new(100, 100).with_accounts(accounts));
+    let addr = start_server(registry.clone()).await;
+    let ok = r#"{"Ack":{"id":1}}"#;
+    let login = r#"{"Login":{"username":"carl","password":"long enough"}}"#;
+
+    let mut laptop = connect_lines(addr).await;
+    assert_eq!(request(&mut laptop, login).await, ok);
+    let mut david = connect_lines(addr).await;
+    assert_eq!(request(&mut david, r#"{"Join":"david"}"#).await, ok);
+    assert_eq!(
+        next_line(&mut laptop).await,
+        r#"{"Broadcast":{"room":"lobby","username":"david","content":"Joined"}}"#
+    );
+    // carl is in the lobby already, so no one hears of the phone
+    let mut phone = connect_lines(addr).await;
+    assert_eq!(request(&mut phone, login).await, ok);
+    assert_eq!(registry.user_sessions("carl").await.len(), 2);
+    assert_eq!(
+        lobby_users(&registry).await,
+        ["carl".to_string(), "david".to_string()].into()
+    );
+
+    write_line(&mut laptop.1, r#"{"Send":{"content":"from the laptop"}}"#).await;
+    let from_laptop =
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"from the laptop"}}"#;
+    assert_eq!(next_line(&mut phone).await, from_laptop);
+    assert_eq!(next_line(&mut david).await, from_laptop);
+
+    // direct messages reach every device, and the sender's other devices see them too
+    write_line(&mut david.1, r#"{"Direct":{"to":"carl","content":"psst"}}"#).await;
+    let psst = r#"{"Direct":{"from":"david","to":"carl","content":"psst"}}"#;
+    assert_eq!(next_line(&mut laptop).await, psst);
+    assert_eq!(next_line(&mut phone).await, psst);
+    write_line(&mut phone.1, r#"{"Direct":{"to":"david","content":"hi"}}"#).await;
+    let hi = r#"{"Direct":{"from":"carl","to":"david","content":"hi"}}"#;
+    assert_eq!(next_line(&mut david).await, hi);
+    assert_eq!(next_line(&mut laptop).await, hi);
+
+    // guests still cannot share a username
+    let mut guest = connect_lines(addr).await;
+    assert_eq!(request(&mut guest, r#"{"Join":"erin"}"#).await, ok);
+    assert_eq!(
+        next_line(&mut david).await,
+        r#"{"Broadcast":{"room":"lobby","username":"erin","content":"Joined"}}"#
+    );
+    let mut guest_again = connect_lines(addr).await;
+    let taken = request(&mut guest_again, r#"{"Join":"erin"}"#).await;
+    assert!(taken.contains("UsernameTaken"), "{}", taken);
+
+    // online while any device is
+    drop(laptop);
+    for _ in 0..50 {
+        if registry.user_sessions("carl").await.len() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(registry.user_sessions("carl").await.len(), 1);
+    write_line(&mut phone.1, r#"{"Send":{"content":"still here"}}"#).await;
+    assert_eq!(
+        next_line(&mut david).await,
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"still here"}}"#
+    );
+    drop(phone);
+    assert_eq!(
+        next_line(&mut david).await,
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Left"}}"#
+    );
+    assert!(registry.user_sessions("carl").await.is_empty());
+}
+
+/// Server on a runtime of its own, whose tasks and connections all stop when it is shut down,
+/// as on a restart.
+fn start_server_runtime(registry: Arc<RoomRegistry>, addr: &str) -> tokio::runtime::Runtime {
+    let listener = assert_ok!(std::net::TcpListener::bind(addr));
+    assert_ok!(listener.set_nonblocking(true));
+    let runtime = assert_ok!(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build());
+    runtime.spawn(async move {
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let state = registry.clone();
+            tokio::spawn(async move {
+                let handler = ChatHandler::new(stream).unwrap();
+                let _ = serve(handler, state).await;
+            });
+        }
+    });
+    runtime
+}
+
+/// Reads lines until one has the content, in a broadcast or in the history replayed on joining.
+async fn wait_for_content(client: &mut LineClient, content: &str) {
+    let quoted = format!(r#""content":"{}""#, content);
+    let wait = async { while !next_line(client).await.contains(&quoted) {} };
+    assert_ok!(tokio::time::timeout(Duration::from_secs(5), wait).await);
+}
+
+#[tokio::test]
+async fn client_reconnects_to_a_restarted_server() {
+    init_tracing_for_tests();
+    // a port free for the restarted server to take again
+    let addr = assert_ok!(std::net::TcpListener::bind("127.0.0.1:0"))
+        .local_addr()
+        .unwrap();
+    let server = start_server_runtime(Arc::new(RoomRegistry::new(100, 100)), &addr.to_string());
+    let ok = r#"{"Ack":{"id":1}}"#;
+    let mut david = connect_lines(addr).await;
+    assert_eq!(request(&mut david, r#"{"Join":"david"}"#).await, ok);
+
+    let (input, lines) = tokio::sync::mpsc::unbounded_channel();
+    let prompt = Prompt::new(
+        "carl".to_string(),
+        SignIn::Guest,
+        Encoding::Json,
+        SizeLimits::default(),
+        None,
+    );
+    let status = prompt.tracked().status.clone();
+    let connector = Connector {
+        addr: addr.to_string(),
+        framing: Framing::Lines,
+        tls: None,
+    };
+    let reconnect = ReconnectConfig {
+        max_attempts: 100,
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(100),
+    };
+    let client = tokio::spawn(run(connector, prompt, reconnect, lines));
+    assert_eq!(
+        next_line(&mut david).await,
+        r#"{"Broadcast":{"room":"lobby","username":"carl","content":"Joined"}}"#
+    );
+    assert_ok!(input.send("send before the restart".to_string()));
+    wait_for_content(&mut david, "before the restart").await;
+
+    server.shutdown_background();
+    assert!(!matches!(david.0.next_line().await, Ok(Some(_))));
+    let reconnecting = async {
+        while *status.lock().unwrap() == ConnectionStatus::Connected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    assert_ok!(tokio::time::timeout(Duration::from_secs(5), reconnecting).await);
+    // queued until the server is back
+    assert_ok!(input.send("send typed while down".to_string()));
+    assert_ok!(input.send("join rust".to_string()));
+
+    // restarted with nothing of the session it had, so the client joins again
+    let registry = Arc::new(RoomRegistry::new(100, 100));
+    let server = start_server_runtime(registry.clone(), &addr.to_string());
+    let rejoined = async {
+        while registry.room("rust").await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    assert_ok!(tokio::time::timeout(Duration::from_secs(5), rejoined).await);
+    assert_eq!(registry.user_sessions("carl").await.len(), 1);
+    assert_eq!(*status.lock().unwrap(), ConnectionStatus::Connected);
+
+    let mut erin = connect_lines(addr).await;
+    write_line(&mut erin.1, r#"{"Join":"erin"}"#).await;
+    wait_for_content(&mut erin, "typed while down").await;
+    assert_eq!(request(&mut erin, r#"{"JoinRoom":"rust"}"#).await, ok);
+    assert_ok!(input.send("send after the restart".to_string()));
+    // to the room joined last, as before the restart
+    wait_for_content(&mut erin, "after the restart").await;
+
+    client.abort();
+    server.shutdown_background();
+}
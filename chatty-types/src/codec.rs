@@ -0,0 +1,751 @@
+use crate::command::ChatCommand;
+use crate::response::ChatResponse;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Largest frame accepted or sent unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+
+const LENGTH_PREFIX_BYTES: usize = 4;
+
+/// How frames are delimited on the wire, both sides of a connection have to use the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// One JSON document per line, easy to use from netcat or a terminal.
+    #[default]
+    Lines,
+    /// Each frame starts with its length as a big endian u32.
+    LengthPrefixed,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Self, Self::Err> {
+        match framing {
+            "lines" => Ok(Framing::Lines),
+            "length" => Ok(Framing::LengthPrefixed),
+            other => Err(format!(
+                "Unknown framing {}, expected 'lines' or 'length'",
+                other
+            )),
+        }
+    }
+}
+
+/// How messages are serialized within a frame, chosen per connection in the `Hello` handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Binary encodings may contain newline bytes, so they need length-prefixed framing.
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, Encoding::Json)
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            "cbor" => Ok(Encoding::Cbor),
+            other => Err(format!(
+                "Unknown encoding {}, expected 'json', 'msgpack' or 'cbor'",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("IO error is: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Json parse error is: {0}")]
+    JsonParse(#[from] serde_json::Error),
+
+    #[error("MessagePack encode error is: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("MessagePack decode error is: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("CBOR encode error is: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<io::Error>),
+
+    #[error("CBOR decode error is: {0}")]
+    CborDecode(#[from] ciborium::de::Error<io::Error>),
+
+    #[error("Frame of {len} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
+}
+
+/// Frames and serializes the messages of one side of a connection,
+/// encoding `E` on the way out and decoding `D` on the way in.
+/// The encoding starts as JSON and can be switched once the handshake agreed on another.
+#[derive(Debug)]
+pub struct ChatCodec<E, D> {
+    framing: Framing,
+    encoding: Encoding,
+    max_frame_bytes: usize,
+    /// Bytes of the buffer already searched for a newline in line framing.
+    scanned: usize,
+    messages: PhantomData<fn(&E) -> D>,
+}
+
+/// Message already framed and encoded by `ChatCodec::frame`, written as is.
+/// Cheap to clone, so one encoding can go out on many connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame(pub Bytes);
+
+/// Codec of the server, sending responses and receiving commands.
+pub type ServerCodec = ChatCodec<ChatResponse, ChatCommand>;
+
+/// Codec of the client, sending commands and receiving responses.
+pub type ClientCodec = ChatCodec<ChatCommand, ChatResponse>;
+
+impl<E, D> ChatCodec<E, D> {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            encoding: Encoding::default(),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            scanned: 0,
+            messages: PhantomData,
+        }
+    }
+
+    pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Switches the encoding of all following frames, including those already buffered.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    fn check_len(&self, len: usize) -> Result<(), CodecError> {
+        if len > self.max_frame_bytes {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        match self.framing {
+            Framing::Lines => {
+                let Some(newline) = src[self.scanned..].iter().position(|byte| *byte == b'\n')
+                else {
+                    // a line that can no longer fit is refused before it is fully buffered
+                    self.check_len(src.len())?;
+                    self.scanned = src.len();
+                    return Ok(None);
+                };
+                let len = self.scanned + newline;
+                self.scanned = 0;
+                let mut line = src.split_to(len + 1);
+                line.truncate(len);
+                if line.last() == Some(&b'\r') {
+                    line.truncate(len - 1);
+                }
+                self.check_len(line.len())?;
+                Ok(Some(line))
+            }
+            Framing::LengthPrefixed => {
+                let Some(prefix) = src.get(..LENGTH_PREFIX_BYTES) else {
+                    return Ok(None);
+                };
+                let len = u32::from_be_bytes(prefix.try_into().expect("4 byte length")) as usize;
+                self.check_len(len)?;
+                if src.len() < LENGTH_PREFIX_BYTES + len {
+                    src.reserve(LENGTH_PREFIX_BYTES + len - src.len());
+                    return Ok(None);
+                }
+                src.advance(LENGTH_PREFIX_BYTES);
+                Ok(Some(src.split_to(len)))
+            }
+        }
+    }
+}
+
+impl<E, D> ChatCodec<E, D> {
+    /// Next frame once the connection is closed, which ends whatever is left of it.
+    fn last_frame(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        if let Some(frame) = self.next_frame(src)? {
+            return Ok(Some(frame));
+        }
+        if src.is_empty() {
+            return Ok(None);
+        }
+        match self.framing {
+            // like reading lines, the last line does not need a newline
+            Framing::Lines => {
+                self.scanned = 0;
+                Ok(Some(src.split()))
+            }
+            Framing::LengthPrefixed => Err(CodecError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a frame",
+            ))),
+        }
+    }
+}
+
+impl<E: Serialize, D> ChatCodec<E, D> {
+    fn encode_payload(&self, message: &E) -> Result<Vec<u8>, CodecError> {
+        match self.encoding {
+            Encoding::Json => Ok(serde_json::to_vec(message)?),
+            // with field names, as skipped optional fields would shift positional ones
+            Encoding::MessagePack => Ok(rmp_serde::to_vec_named(message)?),
+            Encoding::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(message, &mut payload)?;
+                Ok(payload)
+            }
+        }
+    }
+}
+
+impl<E, D: DeserializeOwned> ChatCodec<E, D> {
+    fn decode_payload(&self, payload: &[u8]) -> Result<D, CodecError> {
+        match self.encoding {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+            Encoding::Cbor => Ok(ciborium::from_reader(payload)?),
+        }
+    }
+}
+
+impl<E: Serialize, D: DeserializeOwned> Decoder for ChatCodec<E, D> {
+    type Item = D;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, CodecError> {
+        let Some(frame) = self.next_frame(src)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.decode_payload(&frame)?))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<D>, CodecError> {
+        let Some(frame) = self.last_frame(src)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.decode_payload(&frame)?))
+    }
+}
+
+/// Decodes like the codec it wraps, except that a frame whose payload cannot be decoded,
+/// such as a command unknown to this version, is an item of its own and reading goes on
+/// with the next frame. Framing errors still end the stream, as nothing after them can be trusted.
+#[derive(Debug)]
+pub struct Tolerant<E, D>(ChatCodec<E, D>);
+
+impl<E, D> Tolerant<E, D> {
+    pub fn new(codec: ChatCodec<E, D>) -> Self {
+        Self(codec)
+    }
+}
+
+impl<E, D> Deref for Tolerant<E, D> {
+    type Target = ChatCodec<E, D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<E, D> DerefMut for Tolerant<E, D> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<E: Serialize, D: DeserializeOwned> Decoder for Tolerant<E, D> {
+    type Item = Result<D, CodecError>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, CodecError> {
+        let Some(frame) = self.0.next_frame(src)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.0.decode_payload(&frame)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, CodecError> {
+        let Some(frame) = self.0.last_frame(src)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.0.decode_payload(&frame)))
+    }
+}
+
+impl<E: Serialize, D> ChatCodec<E, D> {
+    /// Encodes the message the way `encode` would, for writing it as is with any codec
+    /// of the same framing and encoding.
+    pub fn frame(&mut self, message: &E) -> Result<Frame, CodecError> {
+        let mut frame = BytesMut::new();
+        self.encode(message, &mut frame)?;
+        Ok(Frame(frame.freeze()))
+    }
+}
+
+impl<E, D> Encoder<Frame> for ChatCodec<E, D> {
+    type Error = CodecError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), CodecError> {
+        dst.extend_from_slice(&frame.0);
+        Ok(())
+    }
+}
+
+impl<E: Serialize, D> Encoder<&E> for ChatCodec<E, D> {
+    type Error = CodecError;
+
+    fn encode(&mut self, message: &E, dst: &mut BytesMut) -> Result<(), CodecError> {
+        // JSON escapes newlines in strings, so a document never spans lines
+        let payload = self.encode_payload(message)?;
+        self.check_len(payload.len())?;
+        match self.framing {
+            Framing::Lines => {
+                dst.reserve(payload.len() + 1);
+                dst.put_slice(&payload);
+                dst.put_u8(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                dst.reserve(LENGTH_PREFIX_BYTES + payload.len());
+                dst.put_u32(payload.len() as u32);
+                dst.put_slice(&payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{
+        BearerToken, ChatMessage, Credentials, DirectMessage, Hello, HistoryRequest,
+        PasswordChange, Request,
+    };
+    use crate::protocol::{Capability, Heartbeat};
+    use crate::response::{
+        Ack, ChatError, ChatMemo, DirectMemo, ErrorCode, HistoryPage, Incompatible, Lagged,
+        ResumeTicket, Resumed, RoomSummary, SessionToken, Throttled, Welcome,
+    };
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    fn memo(content: &str) -> ChatMemo {
+        ChatMemo {
+            room: "rust".to_string(),
+            username: "carl".to_string(),
+            content: content.to_string(),
+            stamp: None,
+        }
+    }
+
+    fn direct_memo() -> DirectMemo {
+        DirectMemo {
+            from: "carl".to_string(),
+            to: "david".to_string(),
+            content: "see you at standup".to_string(),
+        }
+    }
+
+    fn all_commands() -> Vec<ChatCommand> {
+        vec![
+            ChatCommand::Hello(Hello {
+                protocol_version: 1,
+                software: "test-client/1.0".to_string(),
+                capabilities: vec![Capability::History, Capability::Unknown],
+                encoding: Encoding::Cbor,
+            }),
+            ChatCommand::Join("carl".to_string()),
+            ChatCommand::Send(ChatMessage {
+                username: Some("carl".to_string()),
+                room: Some("rust".to_string()),
+                content: "hello\nworld".to_string(),
+            }),
+            send("no optional fields"),
+            ChatCommand::Leave(None),
+            ChatCommand::Leave(Some("carl".to_string())),
+            ChatCommand::JoinRoom("rust".to_string()),
+            ChatCommand::PartRoom("rust".to_string()),
+            ChatCommand::ListRooms,
+            ChatCommand::Direct(DirectMessage {
+                to: "david".to_string(),
+                content: "psst".to_string(),
+            }),
+            ChatCommand::History(HistoryRequest {
+                room: "rust".to_string(),
+                before: None,
+            }),
+            ChatCommand::History(HistoryRequest {
+                room: "rust".to_string(),
+                before: Some(42),
+            }),
+            ChatCommand::Request(Request {
+                id: 7,
+                command: Box::new(ChatCommand::ListRooms),
+            }),
+            ChatCommand::Request(Request {
+                id: u64::MAX,
+                command: Box::new(send("with an id")),
+            }),
+            ChatCommand::Ping(Heartbeat { nonce: 1 }),
+            ChatCommand::Pong(Heartbeat { nonce: u64::MAX }),
+            ChatCommand::Register(Credentials {
+                username: "carl".to_string(),
+                password: "correct horse".to_string(),
+            }),
+            ChatCommand::Login(Credentials {
+                username: "carl".to_string(),
+                password: "battery staple".to_string(),
+            }),
+            ChatCommand::ChangePassword(PasswordChange {
+                current: "correct horse".to_string(),
+                new: "battery staple".to_string(),
+            }),
+            ChatCommand::IssueToken,
+            ChatCommand::TokenLogin(BearerToken("payload.signature".to_string())),
+            ChatCommand::RevokeToken(None),
+            ChatCommand::RevokeToken(Some(BearerToken("payload.signature".to_string()))),
+            ChatCommand::Resume(BearerToken("ticket".to_string())),
+        ]
+    }
+
+    fn all_responses() -> Vec<ChatResponse> {
+        vec![
+            ChatResponse::Welcome(Welcome {
+                protocol_version: 1,
+                software: "test-server/1.0".to_string(),
+                capabilities: Capability::ALL.to_vec(),
+                encoding: Encoding::MessagePack,
+            }),
+            ChatResponse::Incompatible(Incompatible {
+                min_version: 1,
+                max_version: 1,
+                reason: "too new".to_string(),
+            }),
+            ChatResponse::Broadcast(memo("hello")),
+            ChatResponse::Joined(memo("Warm Welcome")),
+            ChatResponse::Duplicate(memo("Sorry")),
+            ChatResponse::Rejected(memo("Not in room rust")),
+            ChatResponse::Parted(memo("Goodbye")),
+            ChatResponse::Rooms(vec![
+                RoomSummary {
+                    name: "lobby".to_string(),
+                    members: 2,
+                },
+                RoomSummary {
+                    name: "rust".to_string(),
+                    members: 1,
+                },
+            ]),
+            ChatResponse::Rooms(vec![]),
+            ChatResponse::Direct(direct_memo()),
+            ChatResponse::Undelivered(direct_memo()),
+            ChatResponse::History(HistoryPage {
+                room: "rust".to_string(),
+                memos: vec![memo("one"), memo("two")],
+                before: Some(7),
+            }),
+            ChatResponse::History(HistoryPage {
+                room: "rust".to_string(),
+                memos: vec![],
+                before: None,
+            }),
+            ChatResponse::Ack(Ack { id: 7 }),
+            ChatResponse::Error(ChatError {
+                id: Some(8),
+                code: ErrorCode::NotInRoom,
+                message: "Not in room rust".to_string(),
+            }),
+            ChatResponse::Error(ChatError {
+                id: None,
+                code: ErrorCode::Malformed,
+                message: "Json parse error".to_string(),
+            }),
+            ChatResponse::Lagged(Lagged {
+                room: "rust".to_string(),
+                missed: 12,
+                first_seq: 30,
+                last_seq: 41,
+                recovered: 5,
+            }),
+            ChatResponse::Ping(Heartbeat { nonce: 3 }),
+            ChatResponse::Pong(Heartbeat { nonce: 4 }),
+            ChatResponse::Throttled(Throttled {
+                id: Some(8),
+                retry_after_ms: 250,
+                muted: false,
+            }),
+            ChatResponse::Token(SessionToken {
+                token: BearerToken("payload.signature".to_string()),
+                username: "carl".to_string(),
+                roles: vec!["admin".to_string()],
+                expires_at_ms: 1_700_000_000_000,
+            }),
+            ChatResponse::Resumable(ResumeTicket {
+                ticket: BearerToken("ticket".to_string()),
+                grace_ms: 30_000,
+            }),
+            ChatResponse::Resumed(Resumed {
+                username: "carl".to_string(),
+                rooms: vec!["lobby".to_string(), "rust".to_string()],
+                held: 3,
+            }),
+        ]
+    }
+
+    fn send(content: &str) -> ChatCommand {
+        ChatCommand::Send(ChatMessage {
+            username: None,
+            room: None,
+            content: content.to_string(),
+        })
+    }
+
+    fn content(command: ChatCommand) -> String {
+        let ChatCommand::Send(message) = command else {
+            panic!("expected send, got {:?}", command);
+        };
+        message.content
+    }
+
+    #[test]
+    fn test_round_trip_in_both_framings() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let mut client = ClientCodec::new(framing);
+            let mut server = ServerCodec::new(framing);
+            let mut buf = BytesMut::new();
+            client.encode(&send("one"), &mut buf).unwrap();
+            client.encode(&send("two\nlines"), &mut buf).unwrap();
+
+            assert_eq!(content(server.decode(&mut buf).unwrap().unwrap()), "one");
+            assert_eq!(
+                content(server.decode(&mut buf).unwrap().unwrap()),
+                "two\nlines"
+            );
+            assert!(server.decode(&mut buf).unwrap().is_none());
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_every_variant_round_trips_in_every_encoding() {
+        for encoding in ENCODINGS {
+            let framing = if encoding.is_binary() {
+                Framing::LengthPrefixed
+            } else {
+                Framing::Lines
+            };
+            let mut client = ClientCodec::new(framing).with_encoding(encoding);
+            let mut server = ServerCodec::new(framing).with_encoding(encoding);
+            let mut buf = BytesMut::new();
+
+            for command in all_commands() {
+                client.encode(&command, &mut buf).unwrap();
+                assert_eq!(server.decode(&mut buf).unwrap(), Some(command));
+            }
+            for response in all_responses() {
+                server.encode(&response, &mut buf).unwrap();
+                assert_eq!(client.decode(&mut buf).unwrap(), Some(response));
+            }
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_binary_encodings_are_smaller() {
+        let response = ChatResponse::Broadcast(memo("hello"));
+        let json = ServerCodec::new(Framing::LengthPrefixed)
+            .encode_payload(&response)
+            .unwrap();
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            let binary = ServerCodec::new(Framing::LengthPrefixed)
+                .with_encoding(encoding)
+                .encode_payload(&response)
+                .unwrap();
+            assert!(binary.len() < json.len(), "{:?} is not smaller", encoding);
+        }
+    }
+
+    #[test]
+    fn test_switching_encoding_applies_to_buffered_frames() {
+        let mut client = ClientCodec::new(Framing::LengthPrefixed);
+        let mut buf = BytesMut::new();
+        client.encode(&ChatCommand::ListRooms, &mut buf).unwrap();
+        client.set_encoding(Encoding::MessagePack);
+        client
+            .encode(&ChatCommand::Join("carl".to_string()), &mut buf)
+            .unwrap();
+
+        let mut server = ServerCodec::new(Framing::LengthPrefixed);
+        assert_eq!(
+            server.decode(&mut buf).unwrap(),
+            Some(ChatCommand::ListRooms)
+        );
+        server.set_encoding(Encoding::MessagePack);
+        assert_eq!(
+            server.decode(&mut buf).unwrap(),
+            Some(ChatCommand::Join("carl".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_frames_are_written_as_encoded() {
+        let mut server = ServerCodec::new(Framing::LengthPrefixed).with_encoding(Encoding::Cbor);
+        let response = ChatResponse::Broadcast(memo("shared"));
+        let frame = server.frame(&response).unwrap();
+
+        let mut encoded = BytesMut::new();
+        server.encode(&response, &mut encoded).unwrap();
+        assert_eq!(frame.0, encoded.freeze());
+
+        let mut buf = BytesMut::new();
+        server.encode(frame.clone(), &mut buf).unwrap();
+        server.encode(frame, &mut buf).unwrap();
+        let mut client = ClientCodec::new(Framing::LengthPrefixed).with_encoding(Encoding::Cbor);
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(response.clone()));
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(response));
+    }
+
+    #[test]
+    fn test_partial_frames_wait_for_more_bytes() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let mut encoded = BytesMut::new();
+            ClientCodec::new(framing)
+                .encode(&send("split up"), &mut encoded)
+                .unwrap();
+            let mut server = ServerCodec::new(framing);
+            let mut buf = BytesMut::new();
+            let (first, second) = encoded.split_at(7);
+            buf.extend_from_slice(first);
+            assert!(server.decode(&mut buf).unwrap().is_none());
+            buf.extend_from_slice(second);
+            assert_eq!(
+                content(server.decode(&mut buf).unwrap().unwrap()),
+                "split up"
+            );
+        }
+    }
+
+    #[test]
+    fn test_lines_accept_crlf_and_missing_final_newline() {
+        let mut server = ServerCodec::new(Framing::Lines);
+        let mut buf = BytesMut::from(&b"{\"ListRooms\":null}\r\n\"ListRooms\""[..]);
+        assert!(matches!(
+            server.decode(&mut buf).unwrap(),
+            Some(ChatCommand::ListRooms)
+        ));
+        assert!(server.decode(&mut buf).unwrap().is_none());
+        assert!(matches!(
+            server.decode_eof(&mut buf).unwrap(),
+            Some(ChatCommand::ListRooms)
+        ));
+        assert!(server.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_frames_are_refused() {
+        let mut client = ClientCodec::new(Framing::LengthPrefixed).with_max_frame_bytes(32);
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            client.encode(&send(&"x".repeat(64)), &mut buf),
+            Err(CodecError::FrameTooLarge { max: 32, .. })
+        ));
+
+        // the length prefix alone is enough to refuse the frame
+        let mut server = ServerCodec::new(Framing::LengthPrefixed).with_max_frame_bytes(32);
+        let mut buf = BytesMut::from(&1024u32.to_be_bytes()[..]);
+        assert!(matches!(
+            server.decode(&mut buf),
+            Err(CodecError::FrameTooLarge { len: 1024, .. })
+        ));
+
+        // as is a line that already grew past the maximum without a newline
+        let mut server = ServerCodec::new(Framing::Lines).with_max_frame_bytes(32);
+        let mut buf = BytesMut::from(&[b'x'; 33][..]);
+        assert!(matches!(
+            server.decode(&mut buf),
+            Err(CodecError::FrameTooLarge { len: 33, .. })
+        ));
+    }
+
+    #[test]
+    fn test_tolerant_decoder_goes_on_after_undecodable_payloads() {
+        let mut server = Tolerant::new(ServerCodec::new(Framing::Lines).with_max_frame_bytes(64));
+        let mut buf =
+            BytesMut::from(&b"{\"Shout\":\"hi\"}\nnot json\n\"ListRooms\"\n\"ListRooms\""[..]);
+        assert!(matches!(
+            server.decode(&mut buf),
+            Ok(Some(Err(CodecError::JsonParse(_))))
+        ));
+        assert!(matches!(
+            server.decode(&mut buf),
+            Ok(Some(Err(CodecError::JsonParse(_))))
+        ));
+        assert!(matches!(
+            server.decode(&mut buf),
+            Ok(Some(Ok(ChatCommand::ListRooms)))
+        ));
+        assert!(matches!(
+            server.decode_eof(&mut buf),
+            Ok(Some(Ok(ChatCommand::ListRooms)))
+        ));
+        assert!(matches!(server.decode_eof(&mut buf), Ok(None)));
+
+        // framing errors are still errors of the stream
+        let mut buf = BytesMut::from(&[b'x'; 65][..]);
+        assert!(matches!(
+            server.decode(&mut buf),
+            Err(CodecError::FrameTooLarge { len: 65, .. })
+        ));
+    }
+
+    #[test]
+    fn test_framing_and_encoding_from_str() {
+        assert_eq!("lines".parse(), Ok(Framing::Lines));
+        assert_eq!("length".parse(), Ok(Framing::LengthPrefixed));
+        assert!("json".parse::<Framing>().is_err());
+        assert_eq!("msgpack".parse(), Ok(Encoding::MessagePack));
+        assert_eq!("cbor".parse(), Ok(Encoding::Cbor));
+        assert!("xml".parse::<Encoding>().is_err());
+    }
+}
@@ -1,14 +1,139 @@
+use crate::codec::Encoding;
+use crate::protocol::{Capability, Heartbeat};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Room every user is in after joining, and where messages without a room go.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Chosen by the client to match the `Ack` or `Error` for a command to it.
+pub type RequestId = u64;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum ChatCommand {
+    /// First command on a connection, negotiating protocol version and capabilities.
+    /// Connections starting with `Join` instead get the current version with every capability.
+    Hello(Hello),
+    /// Joins the chat with a username, starting out in the default room.
     Join(String),
     Send(ChatMessage),
-    Leave(String),
+    /// The server leaves on behalf of the username joined on this connection,
+    /// a username is only accepted if it matches that.
+    Leave(Option<String>),
+    /// Joins the named room, creating it if no one is in it yet.
+    JoinRoom(String),
+    /// Leaves only the named room, staying in the chat.
+    PartRoom(String),
+    ListRooms,
+    /// Message delivered only to the connection of one user.
+    Direct(DirectMessage),
+    /// Page of earlier broadcasts in a room the user is in.
+    History(HistoryRequest),
+    /// Any other command with a request id, answered with an `Ack` once carried out
+    /// or an `Error` instead of `Rejected`, `Duplicate` or `Undelivered`.
+    Request(Request),
+    /// Asks the server for a `Pong`, to measure the round trip. Accepted before joining.
+    Ping(Heartbeat),
+    /// Answer to a `Ping` of the server.
+    Pong(Heartbeat),
+    /// Creates an account for a username no one registered yet, then joins the chat with it.
+    Register(Credentials),
+    /// Joins the chat with the username of an account, which guests cannot use.
+    Login(Credentials),
+    /// Changes the password of the account logged in on this connection.
+    ChangePassword(PasswordChange),
+    /// Asks for a session token of the account logged in on this connection, answered with a `Token`.
+    IssueToken,
+    /// Joins the chat with the username of a session token instead of the password.
+    TokenLogin(BearerToken),
+    /// Revokes a session token of the account logged in on this connection, or of anyone
+    /// for users with the admin role. The token logged in with when None.
+    RevokeToken(Option<BearerToken>),
+    /// Takes over the session of a dropped connection with the ticket of its latest `Resumable`,
+    /// instead of joining, answered with `Resumed` and whatever was held for the session.
+    Resume(BearerToken),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ChatMessage {
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Request {
+    pub id: RequestId,
+    pub command: Box<ChatCommand>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Name and version of the client software, for logging.
+    pub software: String,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Encoding for everything after the `Hello`, which itself is always JSON.
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Credentials {
     pub username: String,
+    pub password: String,
+}
+
+// keeps passwords out of logs of commands
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct PasswordChange {
+    pub current: String,
+    pub new: String,
+}
+
+impl fmt::Debug for PasswordChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordChange").finish_non_exhaustive()
+    }
+}
+
+/// Secret handed out by the server, a session token or resume ticket, granting whoever presents it
+/// the identity it was issued for.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct BearerToken(pub String);
+
+// anyone with the token can log in with it, so it stays out of logs too
+impl fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BearerToken(..)")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatMessage {
+    /// Optional as the server knows who joined on the connection,
+    /// if given it must match the joined username.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Room to send to, the default room when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct DirectMessage {
+    pub to: String,
     pub content: String,
 }
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct HistoryRequest {
+    pub room: String,
+    /// Cursor from the previous page to scroll further back, the latest page when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<u64>,
+}
@@ -1,3 +1,6 @@
+pub mod codec;
 pub mod command;
 pub mod config;
+pub mod limits;
+pub mod protocol;
 pub mod response;
@@ -0,0 +1,126 @@
+use crate::codec::DEFAULT_MAX_FRAME_BYTES;
+use crate::command::ChatCommand;
+use thiserror::Error;
+
+/// Longest message content accepted unless configured otherwise.
+pub const DEFAULT_MAX_CONTENT_CHARS: usize = 4096;
+
+/// Longest username accepted unless configured otherwise.
+pub const DEFAULT_MAX_USERNAME_CHARS: usize = 32;
+
+/// Sizes the server accepts, which the client checks as well before sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Largest frame read from a connection, a larger one closes the connection.
+    pub max_frame_bytes: usize,
+    /// Longest content of a broadcast or direct message.
+    pub max_content_chars: usize,
+    /// Longest username, claimed or named as recipient.
+    pub max_username_chars: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_content_chars: DEFAULT_MAX_CONTENT_CHARS,
+            max_username_chars: DEFAULT_MAX_USERNAME_CHARS,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SizeError {
+    #[error("Message of {len} characters exceeds the maximum of {max}")]
+    ContentTooLong { len: usize, max: usize },
+
+    #[error("Username of {len} characters exceeds the maximum of {max}")]
+    UsernameTooLong { len: usize, max: usize },
+}
+
+impl SizeLimits {
+    pub fn check_content(&self, content: &str) -> Result<(), SizeError> {
+        let len = content.chars().count();
+        if len > self.max_content_chars {
+            return Err(SizeError::ContentTooLong {
+                len,
+                max: self.max_content_chars,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_username(&self, username: &str) -> Result<(), SizeError> {
+        let len = username.chars().count();
+        if len > self.max_username_chars {
+            return Err(SizeError::UsernameTooLong {
+                len,
+                max: self.max_username_chars,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the usernames and content in a command, including one wrapped in a request.
+    pub fn check(&self, command: &ChatCommand) -> Result<(), SizeError> {
+        match command {
+            ChatCommand::Join(username) | ChatCommand::Leave(Some(username)) => {
+                self.check_username(username)
+            }
+            ChatCommand::Register(credentials) | ChatCommand::Login(credentials) => {
+                self.check_username(&credentials.username)
+            }
+            ChatCommand::Send(message) => {
+                if let Some(username) = &message.username {
+                    self.check_username(username)?;
+                }
+                self.check_content(&message.content)
+            }
+            ChatCommand::Direct(message) => {
+                self.check_username(&message.to)?;
+                self.check_content(&message.content)
+            }
+            ChatCommand::Request(request) => self.check(&request.command),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{ChatMessage, DirectMessage, Request};
+
+    #[test]
+    fn test_check_sizes_of_commands() {
+        let limits = SizeLimits {
+            max_frame_bytes: 1024,
+            max_content_chars: 5,
+            max_username_chars: 4,
+        };
+        assert_eq!(limits.check(&ChatCommand::Join("carl".to_string())), Ok(()));
+        assert_eq!(
+            limits.check(&ChatCommand::Join("david".to_string())),
+            Err(SizeError::UsernameTooLong { len: 5, max: 4 })
+        );
+        // characters, not bytes
+        let send = ChatCommand::Send(ChatMessage {
+            username: None,
+            room: None,
+            content: "héllo".to_string(),
+        });
+        assert_eq!(limits.check(&send), Ok(()));
+        let direct = ChatCommand::Request(Request {
+            id: 1,
+            command: Box::new(ChatCommand::Direct(DirectMessage {
+                to: "carl".to_string(),
+                content: "hello!".to_string(),
+            })),
+        });
+        assert_eq!(
+            limits.check(&direct),
+            Err(SizeError::ContentTooLong { len: 6, max: 5 })
+        );
+        assert_eq!(limits.check(&ChatCommand::ListRooms), Ok(()));
+    }
+}
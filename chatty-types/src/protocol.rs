@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the wire protocol spoken by this build, sent in `Hello` and `Welcome`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version a server of this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, only used on a connection once both sides listed them.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Room history is replayed on join and can be requested.
+    History,
+    /// Rooms besides the lobby can be joined, parted and listed.
+    Rooms,
+    /// Messages can be sent directly to a user.
+    DirectMessages,
+    /// Own broadcasts are delivered back too, so the sequence of a room has no gaps.
+    Echo,
+    /// The server pings the connection when it has been quiet, the client answers with a pong.
+    Heartbeat,
+    /// The server holds the session of a dropped connection for a while, to be resumed
+    /// with the ticket it handed out.
+    Resume,
+    /// Capability of a newer peer that this build does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::History,
+        Capability::Rooms,
+        Capability::DirectMessages,
+        Capability::Echo,
+        Capability::Heartbeat,
+        Capability::Resume,
+    ];
+
+    /// What connections without a `Hello` get, as clients from before the handshake expect.
+    pub const IMPLICIT: [Capability; 3] = [
+        Capability::History,
+        Capability::Rooms,
+        Capability::DirectMessages,
+    ];
+}
+
+/// Payload of `Ping` and `Pong` in either direction, the pong carries the nonce of its ping.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub nonce: u64,
+}
@@ -1,14 +1,213 @@
+use crate::codec::Encoding;
+use crate::command::{BearerToken, RequestId};
+use crate::protocol::{Capability, Heartbeat};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ChatResponse {
+    /// Accepted `Hello`, with the capabilities both sides have.
+    Welcome(Welcome),
+    /// Refused `Hello`, the server closes the connection after it.
+    Incompatible(Incompatible),
     Broadcast(ChatMemo),
     Joined(ChatMemo),
     Duplicate(ChatMemo),
+    /// Command refused for the connection, content has the reason.
+    Rejected(ChatMemo),
+    Parted(ChatMemo),
+    Rooms(Vec<RoomSummary>),
+    Direct(DirectMemo),
+    /// Direct message handed back to the sender as the recipient is not online.
+    Undelivered(DirectMemo),
+    /// Earlier broadcasts of a room, never live messages.
+    History(HistoryPage),
+    /// The command with the request id was carried out, after any other responses to it.
+    Ack(Ack),
+    /// The command with the request id failed, or without one a frame that could not be decoded
+    /// or a failure of the server itself.
+    Error(ChatError),
+    /// Broadcasts of a room skipped as the connection fell behind, sent right before the
+    /// first broadcast received after them.
+    Lagged(Lagged),
+    /// Sent to connections with the heartbeat capability that have been quiet, to be answered
+    /// with a `Pong` before the idle timeout.
+    Ping(Heartbeat),
+    /// Answer to a `Ping` of the client.
+    Pong(Heartbeat),
+    /// The command was refused for going over the connection's or user's rate limit.
+    Throttled(Throttled),
+    /// Session token issued for the account logged in on the connection.
+    Token(SessionToken),
+    /// Ticket to resume the session with after the connection drops, sent to connections with
+    /// the resume capability after joining and after each resume, replacing the one before.
+    Resumable(ResumeTicket),
+    /// The session of a dropped connection was taken over, followed by what was held for it.
+    Resumed(Resumed),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Ack {
+    pub id: RequestId,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChatError {
+    pub id: Option<RequestId>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The command could not be decoded.
+    Malformed,
+    /// `Hello` after other commands.
+    UnexpectedHello,
+    /// Everything but `Hello` and `Join` needs a joined username.
+    NotJoined,
+    /// The command claimed a username other than the one joined on the connection,
+    /// or joined as another than the one of the client certificate.
+    WrongIdentity,
+    /// Already joined the chat or the room.
+    AlreadyJoined,
+    UsernameTaken,
+    NotInRoom,
+    /// Recipient of a direct message is not online.
+    UserOffline,
+    /// The server failed to carry out the command.
+    Internal,
+    /// Disconnected for going over the rate limits again and again.
+    Flooding,
+    /// A frame, message or username over the server's size limits,
+    /// the connection is closed after a frame too large.
+    TooLarge,
+    /// Wrong username or password, a guest joining with the username of an account,
+    /// or a session token that is invalid, expired or revoked.
+    Unauthorized,
+    /// A username guests may not join with or that cannot be registered.
+    InvalidUsername,
+    /// A new password too short to be accepted.
+    WeakPassword,
+    /// The server does not offer what the command asks for, such as accounts.
+    Unsupported,
+    /// No session is held for the resume ticket, it expired, was used already or never issued.
+    UnknownSession,
+    /// Code of a newer server that this build does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Welcome {
+    pub protocol_version: u32,
+    /// Name and version of the server software.
+    pub software: String,
+    pub capabilities: Vec<Capability>,
+    /// Encoding of everything after the `Welcome`, which itself is always JSON.
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Incompatible {
+    /// Range of protocol versions the server accepts.
+    pub min_version: u32,
+    pub max_version: u32,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ChatMemo {
+    pub room: String,
+    pub username: String,
+    pub content: String,
+    /// Set by the server on every broadcast, never on the other responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<Stamp>,
+}
+
+/// Unique id of a broadcast across rooms and server restarts.
+pub type MessageId = Uuid;
+
+/// Identity and place of a broadcast in its room.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub id: MessageId,
+    /// Milliseconds since the unix epoch when the server broadcast it.
+    pub timestamp_ms: u64,
+    /// One more than the broadcast before it in the same room, so gaps show missed messages.
+    pub seq: u64,
+}
+
+/// Range of sequence numbers a connection missed in a room.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Lagged {
+    pub room: String,
+    pub missed: u64,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    /// Latest of the missed broadcasts still in the room's history, replayed right after
+    /// as broadcasts, own ones only to connections with echo.
+    pub recovered: u64,
+}
+
+/// Command refused by the rate limiter.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Throttled {
+    /// Request id of the refused command, if it had one.
+    pub id: Option<RequestId>,
+    /// Time until the command would be accepted again.
+    pub retry_after_ms: u64,
+    /// Muted for going over the limit repeatedly, no message or join is accepted until then.
+    pub muted: bool,
+}
+
+/// Signed token to log in with on later connections, until it expires or is revoked.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionToken {
+    pub token: BearerToken,
+    pub username: String,
+    pub roles: Vec<String>,
+    /// Milliseconds since the unix epoch after which the token is no longer accepted.
+    pub expires_at_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ResumeTicket {
+    pub ticket: BearerToken,
+    /// How long the session is held after the connection drops.
+    pub grace_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Resumed {
     pub username: String,
+    /// Rooms the session is still in, nothing was left while it was held.
+    pub rooms: Vec<String>,
+    /// Responses held for the session, sent right after this.
+    pub held: u64,
+}
+
+/// Direct message between two users, not part of any room.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DirectMemo {
+    pub from: String,
+    pub to: String,
     pub content: String,
 }
+
+/// Page of a room's history, oldest memo first.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryPage {
+    pub room: String,
+    pub memos: Vec<ChatMemo>,
+    /// Cursor for the next older page, None once the start of the kept history is reached.
+    pub before: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub name: String,
+    pub members: usize,
+}